@@ -0,0 +1,9 @@
+-- Add migration script here
+-- Which nodes have cached each page
+CREATE TABLE
+  PageLocations (
+    page_id INTEGER NOT NULL REFERENCES Pages (id) ON DELETE CASCADE,
+    node TEXT NOT NULL,
+    cached_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (page_id, node)
+  );
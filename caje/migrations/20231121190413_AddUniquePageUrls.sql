@@ -0,0 +1,15 @@
+-- Add migration script here
+-- Nodes recording the same page at the same time could each add a row, keep the oldest
+DELETE FROM Pages
+WHERE
+  id NOT IN (
+    SELECT
+      MIN(id)
+    FROM
+      Pages
+    GROUP BY
+      method,
+      url
+  );
+
+CREATE UNIQUE INDEX idx_pages_method_url ON Pages (method, url);
@@ -0,0 +1,34 @@
+-- Add migration script here
+CREATE TABLE
+  AdminUsers (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    username TEXT NOT NULL,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+  );
+
+CREATE UNIQUE INDEX idx_admin_users_username ON AdminUsers (username);
+
+CREATE TABLE
+  Passkeys (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    admin_user_id INTEGER NOT NULL REFERENCES AdminUsers (id) ON DELETE CASCADE,
+    name TEXT NOT NULL,
+    credential_id TEXT NOT NULL,
+    public_key BLOB NOT NULL,
+    algorithm INTEGER NOT NULL,
+    sign_count INTEGER NOT NULL DEFAULT 0,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    last_used_at DATETIME
+  );
+
+CREATE UNIQUE INDEX idx_passkeys_credential_id ON Passkeys (credential_id);
+
+ALTER TABLE Sessions
+ADD COLUMN admin_user_id INTEGER REFERENCES AdminUsers (id) ON DELETE CASCADE;
+
+-- Outstanding passkey ceremonies, each challenge is deleted as soon as it's answered
+CREATE TABLE
+  PasskeyChallenges (
+    challenge TEXT PRIMARY KEY NOT NULL,
+    created_at DATETIME NOT NULL
+  );
@@ -0,0 +1,7 @@
+-- Add migration script here
+ALTER TABLE AdminUsers ADD COLUMN password_hash TEXT;
+
+ALTER TABLE AdminUsers ADD COLUMN role TEXT NOT NULL DEFAULT 'viewer';
+
+-- Everyone who logged in with the shared password could already do everything
+UPDATE AdminUsers SET role = 'owner';
@@ -0,0 +1,31 @@
+-- Add migration script here
+-- SQLite can't add NOT NULL columns with a non-constant default, so we rebuild the table
+CREATE TABLE
+  NewSessions (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    session_id TEXT NOT NULL,
+    admin_user_id INTEGER NOT NULL REFERENCES AdminUsers (id) ON DELETE CASCADE,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    last_used_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    expires_at DATETIME NOT NULL
+  );
+
+-- Sessions from before admins had users don't belong to anyone, so they don't make the move
+INSERT INTO
+  NewSessions (id, session_id, admin_user_id, expires_at)
+SELECT
+  id,
+  session_id,
+  admin_user_id,
+  datetime ('now', '+7 days')
+FROM
+  Sessions
+WHERE
+  admin_user_id IS NOT NULL;
+
+DROP TABLE Sessions;
+
+ALTER TABLE NewSessions
+RENAME TO Sessions;
+
+CREATE UNIQUE INDEX idx_session_id ON Sessions (session_id);
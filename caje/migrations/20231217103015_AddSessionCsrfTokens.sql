@@ -0,0 +1,8 @@
+-- Add migration script here
+ALTER TABLE Sessions
+ADD COLUMN csrf_token TEXT NOT NULL DEFAULT '';
+
+-- Empty tokens never match, but we don't want to log everyone out either
+UPDATE Sessions
+SET
+  csrf_token = lower(hex (randomblob (32)));
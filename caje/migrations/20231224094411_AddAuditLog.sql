@@ -0,0 +1,15 @@
+-- Add migration script here
+CREATE TABLE
+  AuditLog (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    -- NULL when nobody was logged in, like a failed login, or once the user is deleted
+    admin_user_id INTEGER REFERENCES AdminUsers (id) ON DELETE SET NULL,
+    -- Kept separately so we still know who it was after the user is gone
+    actor TEXT NOT NULL,
+    node TEXT,
+    action TEXT NOT NULL,
+    details TEXT NOT NULL DEFAULT '{}',
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+  );
+
+CREATE INDEX idx_audit_log_created_at ON AuditLog (created_at);
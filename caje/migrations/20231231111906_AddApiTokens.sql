@@ -0,0 +1,17 @@
+-- Add migration script here
+CREATE TABLE
+  ApiTokens (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    admin_user_id INTEGER NOT NULL REFERENCES AdminUsers (id) ON DELETE CASCADE,
+    name TEXT NOT NULL,
+    -- We only keep a hash, the token itself is shown once when it's created
+    token_hash TEXT NOT NULL,
+    -- The start of the token, so you can tell which one is which
+    token_prefix TEXT NOT NULL,
+    -- Space separated
+    scopes TEXT NOT NULL,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    last_used_at DATETIME
+  );
+
+CREATE UNIQUE INDEX idx_api_tokens_token_hash ON ApiTokens (token_hash);
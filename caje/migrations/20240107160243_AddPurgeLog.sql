@@ -0,0 +1,20 @@
+-- Add migration script here
+CREATE TABLE
+  Purges (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    -- url, prefix or glob
+    kind TEXT NOT NULL,
+    pattern TEXT NOT NULL,
+    requested_by TEXT NOT NULL,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+  );
+
+-- Each node adds a row here once it has applied a purge to its own cache
+CREATE TABLE
+  PurgeCompletions (
+    purge_id INTEGER NOT NULL REFERENCES Purges (id) ON DELETE CASCADE,
+    node TEXT NOT NULL,
+    removed INTEGER NOT NULL,
+    completed_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (purge_id, node)
+  );
@@ -0,0 +1,4 @@
+-- Add migration script here
+-- Space separated tags from the Surrogate-Key and Cache-Tag headers of the last response we cached
+ALTER TABLE Pages
+ADD COLUMN tags TEXT NOT NULL DEFAULT '';
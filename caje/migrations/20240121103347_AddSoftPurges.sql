@@ -0,0 +1,4 @@
+-- Add migration script here
+-- Soft purges mark matching entries stale instead of removing them
+ALTER TABLE Purges
+ADD COLUMN soft BOOLEAN NOT NULL DEFAULT FALSE;
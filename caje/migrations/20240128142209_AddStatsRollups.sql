@@ -0,0 +1,26 @@
+-- Add migration script here
+-- Each node rolls up its request counters into a row every minute, so stats are visible from every node
+CREATE TABLE
+  StatsRollups (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    node TEXT NOT NULL,
+    period_start DATETIME NOT NULL,
+    period_end DATETIME NOT NULL,
+    hits INTEGER NOT NULL,
+    misses INTEGER NOT NULL,
+    bytes_from_cache INTEGER NOT NULL,
+    bytes_from_origin INTEGER NOT NULL,
+    -- JSON array of request counts, one per bucket in `stats::LATENCY_BUCKETS_MS` plus one for slower requests
+    origin_latency_histogram TEXT NOT NULL DEFAULT '[]'
+  );
+
+CREATE INDEX idx_stats_rollups_period_start ON StatsRollups (period_start);
+
+CREATE TABLE
+  StatsUrlRollups (
+    rollup_id INTEGER NOT NULL REFERENCES StatsRollups (id) ON DELETE CASCADE,
+    cache_key TEXT NOT NULL,
+    hits INTEGER NOT NULL,
+    misses INTEGER NOT NULL,
+    PRIMARY KEY (rollup_id, cache_key)
+  );
@@ -0,0 +1,16 @@
+-- Add migration script here
+-- Each node upserts its own row every few seconds, so any node's dashboard can show the whole cluster
+CREATE TABLE
+  NodeHeartbeats (
+    node TEXT PRIMARY KEY NOT NULL,
+    region TEXT,
+    version TEXT NOT NULL,
+    is_primary BOOLEAN NOT NULL,
+    cache_size INTEGER NOT NULL,
+    entry_count INTEGER NOT NULL,
+    -- NULL when we aren't running on LiteFS, or couldn't read the lag
+    lag_ms INTEGER,
+    last_populate_at DATETIME,
+    started_at DATETIME NOT NULL,
+    updated_at DATETIME NOT NULL
+  );
@@ -0,0 +1,29 @@
+-- Add migration script here
+-- Admin actions queued for one node, or every node when target_node is NULL
+CREATE TABLE
+  NodeCommands (
+    id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL,
+    -- clear_fs or populate
+    action TEXT NOT NULL,
+    target_node TEXT,
+    -- The node that runs it records the action in the audit log as this user
+    requested_by_id INTEGER REFERENCES AdminUsers (id) ON DELETE SET NULL,
+    requested_by TEXT NOT NULL,
+    created_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+  );
+
+CREATE INDEX idx_node_commands_created_at ON NodeCommands (created_at);
+
+-- Each node adds a row here before it runs a command, and fills in what happened once it's done
+CREATE TABLE
+  NodeCommandResults (
+    command_id INTEGER NOT NULL REFERENCES NodeCommands (id) ON DELETE CASCADE,
+    node TEXT NOT NULL,
+    started_at DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    -- NULL while the command is running
+    succeeded BOOLEAN,
+    -- JSON, the populate report or the error message
+    result TEXT NOT NULL DEFAULT '{}',
+    completed_at DATETIME,
+    PRIMARY KEY (command_id, node)
+  );
@@ -0,0 +1,390 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    net::IpAddr,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use miette::{miette, IntoDiagnostic, Result};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde_json::{Map, Value};
+use tokio::sync::mpsc;
+use tracing::error;
+
+use crate::{cache_status::CacheStatus, node::node_name};
+
+/// How many lines can be waiting for the writer before we start dropping them
+///
+/// Rather than slowing down requests when the disk can't keep up, we lose some log lines
+const ACCESS_LOG_BUFFER: usize = 4096;
+
+/// Log files are rotated once they get this big, unless `ACCESS_LOG_MAX_BYTES` says otherwise
+const DEFAULT_MAX_BYTES: u64 = 100 * 1024 * 1024;
+
+/// How many rotated log files we keep, unless `ACCESS_LOG_KEEP` says otherwise
+const DEFAULT_KEEP: usize = 5;
+
+/// What can go in each access log line, named as they are in the JSON
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Field {
+    Timestamp,
+    ClientIp,
+    Host,
+    Method,
+    Path,
+    Status,
+    Bytes,
+    CacheStatus,
+    OriginMs,
+    TotalMs,
+    Node,
+}
+
+impl Field {
+    pub(crate) const ALL: [Field; 11] = [
+        Field::Timestamp,
+        Field::ClientIp,
+        Field::Host,
+        Field::Method,
+        Field::Path,
+        Field::Status,
+        Field::Bytes,
+        Field::CacheStatus,
+        Field::OriginMs,
+        Field::TotalMs,
+        Field::Node,
+    ];
+
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Field::Timestamp => "timestamp",
+            Field::ClientIp => "client_ip",
+            Field::Host => "host",
+            Field::Method => "method",
+            Field::Path => "path",
+            Field::Status => "status",
+            Field::Bytes => "bytes",
+            Field::CacheStatus => "cache_status",
+            Field::OriginMs => "origin_ms",
+            Field::TotalMs => "total_ms",
+            Field::Node => "node",
+        }
+    }
+}
+
+impl FromStr for Field {
+    type Err = miette::Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Field::ALL
+            .into_iter()
+            .find(|field| field.as_str() == s)
+            .ok_or_else(|| miette!("Unknown access log field: {s}"))
+    }
+}
+
+/// One proxied request
+#[derive(Debug, Clone)]
+pub(crate) struct AccessLogEntry {
+    pub timestamp: DateTime<Utc>,
+    pub client_ip: Option<IpAddr>,
+    pub host: String,
+    pub method: String,
+    pub path: String,
+    pub status: u16,
+    pub bytes: usize,
+    /// `None` when we failed to answer at all
+    pub cache_status: Option<CacheStatus>,
+    pub origin_time: Option<Duration>,
+    pub total_time: Duration,
+}
+
+fn millis(duration: Duration) -> Value {
+    // Microsecond precision, so we don't log float noise like 1.3599599999999998
+    (duration.as_micros() as f64 / 1000.0).into()
+}
+
+impl AccessLogEntry {
+    /// The JSON for this entry, with only `fields` in it
+    fn to_json(&self, fields: &[Field], node: &str) -> Value {
+        let mut json = Map::new();
+
+        for field in fields {
+            let value = match field {
+                Field::Timestamp => self
+                    .timestamp
+                    .to_rfc3339_opts(SecondsFormat::Millis, true)
+                    .into(),
+                Field::ClientIp => self.client_ip.map(|ip| ip.to_string()).into(),
+                Field::Host => self.host.clone().into(),
+                Field::Method => self.method.clone().into(),
+                Field::Path => self.path.clone().into(),
+                Field::Status => self.status.into(),
+                Field::Bytes => self.bytes.into(),
+                Field::CacheStatus => self.cache_status.map(|status| status.label()).into(),
+                Field::OriginMs => self.origin_time.map(millis).into(),
+                Field::TotalMs => millis(self.total_time),
+                Field::Node => node.into(),
+            };
+            json.insert(field.as_str().to_string(), value);
+        }
+
+        Value::Object(json)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Destination {
+    Stdout,
+    File {
+        path: PathBuf,
+        max_bytes: u64,
+        keep: usize,
+    },
+}
+
+/// How the access log is set up, from the environment
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct AccessLogConfig {
+    destination: Destination,
+    fields: Vec<Field>,
+    /// The share of requests we log, from 0 to 1
+    sample_rate: f64,
+}
+
+fn parse_env<T: FromStr>(name: &str, default: T) -> Result<T> {
+    match std::env::var(name) {
+        Ok(value) => value
+            .trim()
+            .parse()
+            .map_err(|_| miette!("{name} isn't valid: {value}")),
+        Err(_) => Ok(default),
+    }
+}
+
+impl AccessLogConfig {
+    /// `None` unless `ACCESS_LOG` is set, to `stdout` or the path of a file
+    pub(crate) fn from_env() -> Result<Option<Self>> {
+        let Ok(destination) = std::env::var("ACCESS_LOG") else {
+            return Ok(None);
+        };
+
+        let destination = match destination.as_str() {
+            "stdout" | "-" => Destination::Stdout,
+            path => Destination::File {
+                path: path.into(),
+                max_bytes: parse_env("ACCESS_LOG_MAX_BYTES", DEFAULT_MAX_BYTES)?,
+                keep: parse_env("ACCESS_LOG_KEEP", DEFAULT_KEEP)?,
+            },
+        };
+
+        let fields = match std::env::var("ACCESS_LOG_FIELDS") {
+            Ok(fields) => parse_fields(&fields)?,
+            Err(_) => Field::ALL.to_vec(),
+        };
+
+        let sample_rate: f64 = parse_env("ACCESS_LOG_SAMPLE_RATE", 1.0)?;
+        if !(0.0..=1.0).contains(&sample_rate) {
+            Err(miette!("ACCESS_LOG_SAMPLE_RATE has to be between 0 and 1"))?
+        }
+
+        Ok(Some(Self {
+            destination,
+            fields,
+            sample_rate,
+        }))
+    }
+}
+
+fn parse_fields(fields: &str) -> Result<Vec<Field>> {
+    fields
+        .split(',')
+        .map(str::trim)
+        .filter(|field| !field.is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+/// Move `path` to `path.1`, `path.1` to `path.2` and so on, forgetting anything past `keep`
+fn rotate(path: &Path, keep: usize) -> io::Result<()> {
+    let rotated = |n: usize| {
+        let mut name = path.as_os_str().to_owned();
+        name.push(format!(".{n}"));
+        PathBuf::from(name)
+    };
+
+    if keep == 0 {
+        return std::fs::remove_file(path);
+    }
+
+    for n in (1..keep).rev() {
+        let from = rotated(n);
+        if from.exists() {
+            std::fs::rename(from, rotated(n + 1))?;
+        }
+    }
+
+    std::fs::rename(path, rotated(1))
+}
+
+fn open_append(path: &Path) -> io::Result<(File, u64)> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let size = file.metadata()?.len();
+
+    Ok((file, size))
+}
+
+/// Write lines until every sender is gone, rotating the file when it gets too big
+fn write_lines(destination: Destination, mut lines: mpsc::Receiver<String>) -> io::Result<()> {
+    match destination {
+        Destination::Stdout => {
+            while let Some(line) = lines.blocking_recv() {
+                writeln!(io::stdout().lock(), "{line}")?;
+            }
+        }
+        Destination::File {
+            path,
+            max_bytes,
+            keep,
+        } => {
+            let (mut file, mut size) = open_append(&path)?;
+
+            while let Some(line) = lines.blocking_recv() {
+                let len = line.len() as u64 + 1;
+                if size > 0 && size + len > max_bytes {
+                    rotate(&path, keep)?;
+                    (file, size) = open_append(&path)?;
+                }
+
+                writeln!(file, "{line}")?;
+                size += len;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes one JSON line per proxied request, in the background
+#[derive(Debug)]
+pub(crate) struct AccessLog {
+    fields: Vec<Field>,
+    sample_rate: f64,
+    node: String,
+    random: SystemRandom,
+    lines: mpsc::Sender<String>,
+}
+
+impl AccessLog {
+    pub(crate) fn spawn(config: AccessLogConfig) -> Result<Self> {
+        // Find out about a bad path now, instead of from the writer in the background
+        if let Destination::File { path, .. } = &config.destination {
+            open_append(path).into_diagnostic()?;
+        }
+
+        let (lines, receiver) = mpsc::channel(ACCESS_LOG_BUFFER);
+
+        let destination = config.destination;
+        tokio::task::spawn_blocking(move || {
+            if let Err(e) = write_lines(destination, receiver) {
+                error!(error = ?e, "Access log writer stopped");
+            }
+        });
+
+        Ok(Self {
+            fields: config.fields,
+            sample_rate: config.sample_rate,
+            node: node_name(),
+            random: SystemRandom::new(),
+            lines,
+        })
+    }
+
+    fn sampled(&self) -> bool {
+        if self.sample_rate >= 1.0 {
+            return true;
+        }
+
+        let mut bytes = [0; 8];
+        if self.random.fill(&mut bytes).is_err() {
+            return false;
+        }
+
+        (u64::from_le_bytes(bytes) as f64 / u64::MAX as f64) < self.sample_rate
+    }
+
+    pub(crate) fn record(&self, entry: &AccessLogEntry) {
+        if !self.sampled() {
+            return;
+        }
+
+        let line = entry.to_json(&self.fields, &self.node).to_string();
+        // Full means the writer is behind, and closed means it died and already logged why
+        let _ = self.lines.try_send(line);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_selected_fields_are_logged() {
+        let entry = AccessLogEntry {
+            timestamp: DateTime::parse_from_rfc3339("2024-02-18T10:00:00Z")
+                .unwrap()
+                .with_timezone(&Utc),
+            client_ip: Some("127.0.0.1".parse().unwrap()),
+            host: "slow.coreyja.com".to_string(),
+            method: "GET".to_string(),
+            path: "/fast".to_string(),
+            status: 200,
+            bytes: 512,
+            cache_status: Some(CacheStatus::Hit),
+            origin_time: None,
+            total_time: Duration::from_micros(1500),
+        };
+
+        let fields = parse_fields("timestamp, path,cache_status,origin_ms,total_ms").unwrap();
+        assert_eq!(
+            entry.to_json(&fields, "abc"),
+            serde_json::json!({
+                "timestamp": "2024-02-18T10:00:00.000Z",
+                "path": "/fast",
+                "cache_status": "hit",
+                "origin_ms": null,
+                "total_ms": 1.5,
+            })
+        );
+
+        assert!(parse_fields("path,referrer").is_err());
+    }
+
+    #[test]
+    fn rotates_log_files() {
+        let dir = std::env::temp_dir().join(format!("caje-access-log-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("access.jsonl");
+
+        for contents in ["first", "second", "third"] {
+            std::fs::write(&path, contents).unwrap();
+            rotate(&path, 2).unwrap();
+        }
+
+        assert!(!path.exists());
+        assert_eq!(
+            std::fs::read_to_string(dir.join("access.jsonl.1")).unwrap(),
+            "third"
+        );
+        assert_eq!(
+            std::fs::read_to_string(dir.join("access.jsonl.2")).unwrap(),
+            "second"
+        );
+        assert!(!dir.join("access.jsonl.3").exists());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,6 +1,22 @@
 pub mod list;
 
+pub mod api;
+pub mod api_tokens;
+pub mod audit_log;
 pub mod auth;
 pub mod clear_db;
 pub mod clear_fs;
+pub mod cluster;
+pub mod commands;
+pub mod csrf;
+pub mod entry;
+pub mod login_limiter;
+pub mod passkeys;
 pub mod populate;
+pub mod purges;
+pub mod roles;
+pub mod scopes;
+pub mod sessions;
+pub mod stats;
+pub mod users;
+pub mod warm;
@@ -0,0 +1,382 @@
+use std::{marker::PhantomData, sync::Arc, time::SystemTime};
+
+use async_trait::async_trait;
+use axum::{
+    extract::{rejection::JsonRejection, FromRequest, FromRequestParts, Query, State},
+    response::{IntoResponse, Response},
+    Json,
+};
+use cacache::Metadata;
+use http::{header::AUTHORIZATION, request::Parts, Method, Request, StatusCode};
+use miette::IntoDiagnostic;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tower_cookies::Cookies;
+use tracing::info;
+
+use crate::{
+    audit::{self, Actor},
+    get_policy_from_cache,
+    manifest::{Manifest, Page},
+    purge::{self, PurgeKind},
+    tags::entry_tags,
+    AppState, CACHE_DIR,
+};
+
+use super::{
+    api_tokens::ApiToken,
+    auth::DBSession,
+    clear_fs::clear_fs,
+    csrf::tokens_match,
+    populate::{populate, PopulateReport},
+    roles::Viewer,
+    scopes::{ClearDb, Populate, Purge, Read, RequiredScope},
+    warm::{self, WarmJob, WarmSource},
+};
+
+/// Browsers calling the API with a session cookie have to send their CSRF token in this header
+const CSRF_HEADER: &str = "X-CSRF-Token";
+
+/// Errors from the API, which are JSON instead of HTML
+#[derive(Debug)]
+pub(crate) struct ApiError {
+    status: StatusCode,
+    message: String,
+}
+
+impl ApiError {
+    fn new(status: StatusCode, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+        }
+    }
+
+    /// The caller sent something we can't act on, like an invalid glob
+    fn bad_request(err: miette::Report) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, err.to_string())
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.status, Json(json!({ "error": self.message }))).into_response()
+    }
+}
+
+impl<E> From<E> for ApiError
+where
+    E: Into<miette::Report>,
+{
+    fn from(err: E) -> Self {
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, err.into().to_string())
+    }
+}
+
+/// Like `Json`, but a body we can't parse, like an unknown purge kind, is a 400 with a JSON error
+pub(crate) struct ApiJson<T>(T);
+
+#[async_trait]
+impl<T, S, B> FromRequest<S, B> for ApiJson<T>
+where
+    Json<T>: FromRequest<S, B, Rejection = JsonRejection>,
+    S: Send + Sync,
+    B: Send + 'static,
+{
+    type Rejection = ApiError;
+
+    async fn from_request(req: Request<B>, state: &S) -> Result<Self, Self::Rejection> {
+        let Json(value) = Json::<T>::from_request(req, state)
+            .await
+            .map_err(|rejection| ApiError::new(StatusCode::BAD_REQUEST, rejection.body_text()))?;
+
+        Ok(Self(value))
+    }
+}
+
+/// Whoever is calling the API, with either a bearer token or a dashboard session
+///
+/// Either way they need the scope `S`, tokens need it granted and sessions need the role for it
+pub(crate) struct ApiCaller<S> {
+    pub actor: Actor,
+    required_scope: PhantomData<S>,
+}
+
+impl<S: RequiredScope> ApiCaller<S> {
+    fn new(actor: Actor) -> Self {
+        Self {
+            actor,
+            required_scope: PhantomData,
+        }
+    }
+
+    async fn from_token(state: &AppState, authorization: &str) -> Result<Self, ApiError> {
+        let token = authorization
+            .strip_prefix("Bearer ")
+            .ok_or_else(|| ApiError::new(StatusCode::UNAUTHORIZED, "Expected a Bearer token"))?;
+
+        let token = ApiToken::authenticate(state, token.trim())
+            .await?
+            .ok_or_else(|| ApiError::new(StatusCode::UNAUTHORIZED, "Invalid API token"))?;
+
+        if !token.scopes.contains(&S::SCOPE) {
+            return Err(ApiError::new(
+                StatusCode::FORBIDDEN,
+                format!("This token doesn't have the {} scope", S::SCOPE),
+            ));
+        }
+        if token.role < S::SCOPE.role() {
+            return Err(ApiError::new(
+                StatusCode::FORBIDDEN,
+                format!(
+                    "{} is no longer an {}, so their tokens can't use the {} scope",
+                    token.actor.name,
+                    S::SCOPE.role(),
+                    S::SCOPE
+                ),
+            ));
+        }
+
+        Ok(Self::new(token.actor))
+    }
+
+    async fn from_session(parts: &mut Parts, state: &AppState) -> Result<Self, ApiError> {
+        let cookies = Cookies::from_request_parts(parts, state)
+            .await
+            .map_err(|(status, message)| ApiError::new(status, message))?;
+
+        let session = DBSession::<Viewer>::from_cookies(state, &cookies)
+            .await?
+            .ok_or_else(|| {
+                ApiError::new(
+                    StatusCode::UNAUTHORIZED,
+                    "Send an API token in the Authorization header",
+                )
+            })?;
+
+        if session.role < S::SCOPE.role() {
+            return Err(ApiError::new(
+                StatusCode::FORBIDDEN,
+                format!("You need to be an {} to do that", S::SCOPE.role()),
+            ));
+        }
+
+        if parts.method != Method::GET {
+            let submitted = parts
+                .headers
+                .get(CSRF_HEADER)
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or_default();
+
+            if !tokens_match(submitted, &session.csrf_token) {
+                return Err(ApiError::new(
+                    StatusCode::FORBIDDEN,
+                    format!("Missing or invalid {CSRF_HEADER} header"),
+                ));
+            }
+        }
+
+        Ok(Self::new(session.actor()))
+    }
+}
+
+#[async_trait]
+impl<S: RequiredScope> FromRequestParts<AppState> for ApiCaller<S> {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let authorization = parts
+            .headers
+            .get(AUTHORIZATION)
+            .map(|value| value.to_str().map(str::to_string));
+
+        match authorization {
+            Some(Ok(authorization)) => Self::from_token(state, &authorization).await,
+            Some(Err(_)) => Err(ApiError::new(
+                StatusCode::BAD_REQUEST,
+                "Authorization header isn't valid",
+            )),
+            None => Self::from_session(parts, state).await,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct CacheEntry {
+    key: String,
+    size: usize,
+    tags: Vec<String>,
+    /// `None` if we couldn't read the entry
+    ttl_seconds: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct ListResponse {
+    /// The manifest, shared by every node
+    pages: Vec<Page>,
+    /// What is cached on the node that answered
+    cache: Vec<CacheEntry>,
+}
+
+pub(crate) async fn list(
+    State(manifest): State<Arc<dyn Manifest>>,
+    _: ApiCaller<Read>,
+) -> Result<Json<ListResponse>, ApiError> {
+    let entries: Result<Vec<Metadata>, _> =
+        tokio::task::spawn_blocking(|| cacache::list_sync(CACHE_DIR).collect())
+            .await
+            .into_diagnostic()?;
+    let entries = entries.unwrap_or_default();
+
+    let now = SystemTime::now();
+    let mut cache = Vec::with_capacity(entries.len());
+    for entry in entries {
+        let ttl_seconds = get_policy_from_cache(&entry.key)
+            .await
+            .ok()
+            .map(|(policy, _)| policy.time_to_live(now).as_secs());
+
+        cache.push(CacheEntry {
+            tags: entry_tags(&entry.metadata).map(str::to_string).collect(),
+            key: entry.key,
+            size: entry.size,
+            ttl_seconds,
+        });
+    }
+
+    Ok(Json(ListResponse {
+        pages: manifest.list_pages().await?,
+        cache,
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct PurgeRequest {
+    kind: PurgeKind,
+    pattern: String,
+    /// Mark entries stale instead of removing them
+    #[serde(default)]
+    soft: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct PurgeResponse {
+    id: i64,
+    /// Entries purged from the node that answered, other nodes catch up in the background
+    removed: usize,
+}
+
+pub(crate) async fn purge_route(
+    State(state): State<AppState>,
+    caller: ApiCaller<Purge>,
+    ApiJson(request): ApiJson<PurgeRequest>,
+) -> Result<Json<PurgeResponse>, ApiError> {
+    purge::validate(request.kind, &request.pattern).map_err(ApiError::bad_request)?;
+
+    let (purge, removed) = purge::request(
+        &state,
+        &caller.actor,
+        request.kind,
+        &request.pattern,
+        request.soft,
+    )
+    .await?;
+
+    Ok(Json(PurgeResponse {
+        id: purge.id,
+        removed,
+    }))
+}
+
+pub(crate) async fn clear_fs_route(
+    State(state): State<AppState>,
+    caller: ApiCaller<Purge>,
+) -> Result<StatusCode, ApiError> {
+    info!(actor = caller.actor.name, "Clearing the cache from the API");
+    clear_fs(&state).await?;
+    audit::record(&state, &caller.actor, "clear_fs", json!({})).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub(crate) async fn clear_db_route(
+    State(state): State<AppState>,
+    caller: ApiCaller<ClearDb>,
+) -> Result<StatusCode, ApiError> {
+    info!(
+        actor = caller.actor.name,
+        "Clearing the manifest from the API"
+    );
+    state.manifest.clear().await?;
+    audit::record(&state, &caller.actor, "clear_db", json!({})).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+pub(crate) async fn populate_route(
+    State(state): State<AppState>,
+    caller: ApiCaller<Populate>,
+) -> Result<Json<PopulateReport>, ApiError> {
+    info!(
+        actor = caller.actor.name,
+        "Populating the cache from the API"
+    );
+
+    let report = populate(&state).await?;
+    audit::record(
+        &state,
+        &caller.actor,
+        "populate",
+        serde_json::to_value(&report).into_diagnostic()?,
+    )
+    .await?;
+
+    Ok(Json(report))
+}
+
+/// Warms run in the background, `GET` the `status_url` to see how it's going
+#[derive(Debug, Serialize)]
+pub(crate) struct WarmStarted {
+    id: u64,
+    status_url: String,
+}
+
+pub(crate) async fn warm_route(
+    State(state): State<AppState>,
+    caller: ApiCaller<Populate>,
+    ApiJson(source): ApiJson<WarmSource>,
+) -> Result<(StatusCode, Json<WarmStarted>), ApiError> {
+    info!(actor = caller.actor.name, "Warming the cache from the API");
+    let id = warm::start(&state, caller.actor, source).map_err(ApiError::bad_request)?;
+
+    Ok((
+        StatusCode::ACCEPTED,
+        Json(WarmStarted {
+            id,
+            status_url: format!("/_caje/api/v1/warm?id={id}"),
+        }),
+    ))
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct WarmJobQuery {
+    id: u64,
+}
+
+pub(crate) async fn warm_job_route(
+    State(state): State<AppState>,
+    _: ApiCaller<Populate>,
+    Query(query): Query<WarmJobQuery>,
+) -> Result<Json<WarmJob>, ApiError> {
+    let job = state.warm_jobs.get(query.id).ok_or_else(|| {
+        ApiError::new(
+            StatusCode::NOT_FOUND,
+            "This node doesn't know about that warm, it may have restarted since",
+        )
+    })?;
+
+    Ok(Json(job))
+}
@@ -0,0 +1,345 @@
+use axum::{
+    extract::State,
+    response::{IntoResponse, Redirect},
+};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use chrono::{NaiveDateTime, Utc};
+use http::StatusCode;
+use maud::html;
+use miette::{miette, IntoDiagnostic, Result};
+use ring::{
+    digest::{digest, SHA256},
+    rand::{SecureRandom, SystemRandom},
+};
+use serde::Deserialize;
+use serde_json::json;
+use sqlx::query_as;
+
+use crate::{
+    audit::{self, Actor},
+    AppState, WrappedError,
+};
+
+use super::{
+    auth::{is_due_for_touch, DBSession},
+    csrf::CsrfForm,
+    roles::Role,
+    scopes::Scope,
+};
+
+/// Every token starts with this, so they're easy to spot if they leak
+const TOKEN_PREFIX: &str = "caje_";
+
+/// How much of the token we keep around in the clear, including `TOKEN_PREFIX`
+const DISPLAYED_PREFIX_LEN: usize = 12;
+
+/// A valid API token, and who it belongs to
+#[derive(Debug, Clone)]
+pub(crate) struct ApiToken {
+    pub scopes: Vec<Scope>,
+    /// The current role of the user who made the token, tokens can't do more than they can
+    pub role: Role,
+    pub actor: Actor,
+}
+
+struct ApiTokenRow {
+    id: i64,
+    admin_user_id: i64,
+    username: String,
+    role: Role,
+    name: String,
+    token_prefix: String,
+    scopes: String,
+    created_at: NaiveDateTime,
+    last_used_at: Option<NaiveDateTime>,
+}
+
+impl ApiTokenRow {
+    fn scopes(&self) -> Result<Vec<Scope>> {
+        self.scopes.split_whitespace().map(str::parse).collect()
+    }
+}
+
+fn hash_token(token: &str) -> String {
+    URL_SAFE_NO_PAD.encode(digest(&SHA256, token.as_bytes()))
+}
+
+fn new_token() -> Result<String> {
+    let mut token = [0u8; 32];
+    SystemRandom::new()
+        .fill(&mut token)
+        .map_err(|_| miette!("Could not generate an API token"))?;
+
+    Ok(format!("{TOKEN_PREFIX}{}", URL_SAFE_NO_PAD.encode(token)))
+}
+
+impl ApiToken {
+    /// Find the token that was presented in a request, if it exists
+    pub(crate) async fn authenticate(state: &AppState, token: &str) -> Result<Option<ApiToken>> {
+        let token_hash = hash_token(token);
+
+        let row = query_as!(
+            ApiTokenRow,
+            r#"SELECT ApiTokens.id, ApiTokens.admin_user_id, AdminUsers.username,
+                AdminUsers.role as "role: Role", ApiTokens.name, ApiTokens.token_prefix,
+                ApiTokens.scopes, ApiTokens.created_at, ApiTokens.last_used_at
+            FROM ApiTokens
+            JOIN AdminUsers ON AdminUsers.id = ApiTokens.admin_user_id
+            WHERE ApiTokens.token_hash = ?"#,
+            token_hash
+        )
+        .fetch_optional(&state.db_pool)
+        .await
+        .into_diagnostic()?;
+
+        let Some(row) = row else {
+            return Ok(None);
+        };
+
+        let now = Utc::now().naive_utc();
+        let due_for_touch = match row.last_used_at {
+            Some(last_used_at) => is_due_for_touch(last_used_at, now),
+            None => true,
+        };
+        if due_for_touch {
+            state
+                .db_writer
+                .write(|| async {
+                    sqlx::query!(
+                        "UPDATE ApiTokens SET last_used_at = ? WHERE id = ?",
+                        now,
+                        row.id
+                    )
+                    .execute(&state.db_pool)
+                    .await
+                    .into_diagnostic()
+                })
+                .await?;
+        }
+
+        Ok(Some(ApiToken {
+            scopes: row.scopes()?,
+            role: row.role,
+            actor: Actor {
+                admin_user_id: Some(row.admin_user_id),
+                name: row.username,
+            },
+        }))
+    }
+}
+
+/// Everyone can manage their own tokens, owners can see and revoke everyone's
+pub(crate) async fn index(
+    State(state): State<AppState>,
+    session: DBSession,
+) -> Result<impl IntoResponse, WrappedError> {
+    let is_owner = session.role >= Role::Owner;
+
+    let tokens = query_as!(
+        ApiTokenRow,
+        r#"SELECT ApiTokens.id, ApiTokens.admin_user_id, AdminUsers.username,
+            AdminUsers.role as "role: Role", ApiTokens.name, ApiTokens.token_prefix,
+            ApiTokens.scopes, ApiTokens.created_at, ApiTokens.last_used_at
+        FROM ApiTokens
+        JOIN AdminUsers ON AdminUsers.id = ApiTokens.admin_user_id
+        WHERE $1 OR ApiTokens.admin_user_id = $2
+        ORDER BY ApiTokens.created_at DESC"#,
+        is_owner,
+        session.admin_user_id
+    )
+    .fetch_all(&state.db_pool)
+    .await
+    .into_diagnostic()?;
+
+    let resp = html! {
+        h2 { "API Tokens" }
+        table {
+            tr {
+                th { "Name" }
+                th { "User" }
+                th { "Token" }
+                th { "Scopes" }
+                th { "Created" }
+                th { "Last Used" }
+                th {}
+            }
+            @for token in tokens {
+                tr {
+                    td { (token.name) }
+                    td { (token.username) }
+                    td { code { (token.token_prefix) "…" } }
+                    td { (token.scopes) }
+                    td { (token.created_at) }
+                    td { (token.last_used_at.map(|t| t.to_string()).unwrap_or_else(|| "Never".to_string())) }
+                    td {
+                        form method="post" action="/_caje/tokens/revoke" {
+                            (session.csrf_input())
+                            input type="hidden" name="id" value=(token.id);
+                            input type="submit" value="Revoke";
+                        }
+                    }
+                }
+            }
+        }
+
+        h2 { "Create a Token" }
+        form method="post" action="/_caje/tokens" {
+            (session.csrf_input())
+            input type="text" name="name" placeholder="Name";
+            @for scope in Scope::ALL {
+                // You can't make a token that can do more than you can
+                @if scope.role() <= session.role {
+                    label {
+                        input type="checkbox" name=(scope.as_str()) value="true";
+                        (scope.as_str())
+                    }
+                }
+            }
+            input type="submit" value="Create Token";
+        }
+    };
+
+    Ok((StatusCode::OK, resp))
+}
+
+#[derive(Deserialize)]
+pub(crate) struct CreateForm {
+    name: String,
+    #[serde(default)]
+    read: bool,
+    #[serde(default)]
+    purge: bool,
+    #[serde(default)]
+    populate: bool,
+    #[serde(default)]
+    clear_db: bool,
+}
+
+pub(crate) async fn create(
+    State(state): State<AppState>,
+    CsrfForm { session, form }: CsrfForm<CreateForm>,
+) -> Result<impl IntoResponse, WrappedError> {
+    let name = form.name.trim();
+    if name.is_empty() {
+        Err(miette!("API tokens need a name"))?
+    }
+
+    let scopes = [
+        (Scope::Read, form.read),
+        (Scope::Purge, form.purge),
+        (Scope::Populate, form.populate),
+        (Scope::ClearDb, form.clear_db),
+    ]
+    .into_iter()
+    .filter_map(|(scope, requested)| requested.then_some(scope))
+    .collect::<Vec<_>>();
+
+    if scopes.is_empty() {
+        Err(miette!("API tokens need at least one scope"))?
+    }
+    if let Some(scope) = scopes.iter().find(|scope| scope.role() > session.role) {
+        Err(miette!(
+            "You need to be an {} to make a token with the {} scope",
+            scope.role(),
+            scope
+        ))?
+    }
+
+    let token = new_token()?;
+    let token_hash = hash_token(&token);
+    let token_prefix = &token[..DISPLAYED_PREFIX_LEN];
+    let scopes = scopes
+        .iter()
+        .map(Scope::as_str)
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    state
+        .db_writer
+        .write(|| async {
+            sqlx::query!(
+                "INSERT INTO ApiTokens (admin_user_id, name, token_hash, token_prefix, scopes)
+                VALUES (?, ?, ?, ?, ?)",
+                session.admin_user_id,
+                name,
+                token_hash,
+                token_prefix,
+                scopes
+            )
+            .execute(&state.db_pool)
+            .await
+            .into_diagnostic()
+        })
+        .await?;
+
+    audit::record(
+        &state,
+        &session.actor(),
+        "token_created",
+        json!({ "name": name, "scopes": scopes }),
+    )
+    .await?;
+
+    let resp = html! {
+        h2 { "Created " (name) }
+        p { "Copy this token now, you won't be able to see it again" }
+        p { code { (token) } }
+        p { a href="/_caje/tokens" { "Back to API Tokens" } }
+    };
+
+    Ok((StatusCode::CREATED, resp))
+}
+
+#[derive(Deserialize)]
+pub(crate) struct RevokeForm {
+    id: i64,
+}
+
+pub(crate) async fn revoke(
+    State(state): State<AppState>,
+    CsrfForm { session, form }: CsrfForm<RevokeForm>,
+) -> Result<impl IntoResponse, WrappedError> {
+    let is_owner = session.role >= Role::Owner;
+
+    state
+        .db_writer
+        .write(|| async {
+            sqlx::query!(
+                "DELETE FROM ApiTokens WHERE id = $1 AND ($2 OR admin_user_id = $3)",
+                form.id,
+                is_owner,
+                session.admin_user_id
+            )
+            .execute(&state.db_pool)
+            .await
+            .into_diagnostic()
+        })
+        .await?;
+
+    audit::record(
+        &state,
+        &session.actor(),
+        "token_revoked",
+        json!({ "id": form.id }),
+    )
+    .await?;
+
+    Ok(Redirect::to("/_caje/tokens"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens_are_prefixed_and_unique() {
+        let first = new_token().unwrap();
+        let second = new_token().unwrap();
+
+        assert!(first.starts_with(TOKEN_PREFIX));
+        assert!(first.len() > DISPLAYED_PREFIX_LEN);
+        assert_ne!(first, second);
+        assert_ne!(hash_token(&first), hash_token(&second));
+        assert_eq!(hash_token(&first), hash_token(&first));
+    }
+}
@@ -0,0 +1,166 @@
+use axum::{
+    extract::{Query, State},
+    response::IntoResponse,
+};
+use chrono::NaiveDateTime;
+use http::StatusCode;
+use maud::html;
+use miette::IntoDiagnostic;
+use serde::Deserialize;
+use sqlx::query_as;
+
+use crate::{AppState, WrappedError};
+
+use super::{auth::DBSession, roles::Owner};
+
+/// How many entries we show per page
+const PAGE_SIZE: i64 = 100;
+
+struct AuditEntry {
+    id: i64,
+    actor: String,
+    node: Option<String>,
+    action: String,
+    details: String,
+    created_at: NaiveDateTime,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct Filter {
+    action: Option<String>,
+    actor: Option<String>,
+    node: Option<String>,
+    /// Only show entries older than this one, for paging back through the log
+    before: Option<i64>,
+}
+
+/// Forms submit empty strings for filters that weren't picked
+fn non_empty(value: &Option<String>) -> Option<&str> {
+    value.as_deref().map(str::trim).filter(|v| !v.is_empty())
+}
+
+impl Filter {
+    /// The query string for the next page of the same filter
+    fn older_than(&self, id: i64) -> String {
+        let params = [
+            ("action", non_empty(&self.action)),
+            ("actor", non_empty(&self.actor)),
+            ("node", non_empty(&self.node)),
+        ];
+
+        let mut query = params
+            .iter()
+            .filter_map(|(name, value)| value.map(|value| (*name, value.to_string())))
+            .collect::<Vec<_>>();
+        query.push(("before", id.to_string()));
+
+        serde_urlencoded::to_string(query).unwrap_or_default()
+    }
+}
+
+pub(crate) async fn index(
+    State(state): State<AppState>,
+    _: DBSession<Owner>,
+    Query(filter): Query<Filter>,
+) -> Result<impl IntoResponse, WrappedError> {
+    let action = non_empty(&filter.action);
+    let actor = non_empty(&filter.actor);
+    let node = non_empty(&filter.node);
+
+    let entries = query_as!(
+        AuditEntry,
+        "SELECT id, actor, node, action, details, created_at
+        FROM AuditLog
+        WHERE ($1 IS NULL OR action = $1)
+            AND ($2 IS NULL OR actor = $2)
+            AND ($3 IS NULL OR node = $3)
+            AND ($4 IS NULL OR id < $4)
+        ORDER BY id DESC
+        LIMIT $5",
+        action,
+        actor,
+        node,
+        filter.before,
+        PAGE_SIZE
+    )
+    .fetch_all(&state.db_pool)
+    .await
+    .into_diagnostic()?;
+
+    let actions = sqlx::query_scalar!("SELECT DISTINCT action FROM AuditLog ORDER BY action")
+        .fetch_all(&state.db_pool)
+        .await
+        .into_diagnostic()?;
+    let nodes = sqlx::query_scalar!(
+        r#"SELECT DISTINCT node as "node!" FROM AuditLog WHERE node IS NOT NULL ORDER BY node"#
+    )
+    .fetch_all(&state.db_pool)
+    .await
+    .into_diagnostic()?;
+
+    let older = (entries.len() as i64 == PAGE_SIZE)
+        .then(|| entries.last().map(|entry| filter.older_than(entry.id)))
+        .flatten();
+
+    let resp = html! {
+        h2 { "Audit Log" }
+        form method="get" action="/_caje/audit" {
+            select name="action" {
+                option value="" { "Any action" }
+                @for a in &actions {
+                    option value=(a) selected[Some(a.as_str()) == action] { (a) }
+                }
+            }
+            input type="text" name="actor" placeholder="Actor" value=(actor.unwrap_or_default());
+            select name="node" {
+                option value="" { "Any node" }
+                @for n in &nodes {
+                    option value=(n) selected[Some(n.as_str()) == node] { (n) }
+                }
+            }
+            input type="submit" value="Filter";
+        }
+
+        table {
+            tr {
+                th { "When" }
+                th { "Actor" }
+                th { "Node" }
+                th { "Action" }
+                th { "Details" }
+            }
+            @for entry in &entries {
+                tr {
+                    td { (entry.created_at) }
+                    td { (entry.actor) }
+                    td { (entry.node.as_deref().unwrap_or_default()) }
+                    td { (entry.action) }
+                    td { code { (entry.details) } }
+                }
+            }
+        }
+
+        @if let Some(older) = older {
+            p { a href={ "/_caje/audit?" (older) } { "Older" } }
+        }
+    };
+
+    Ok((StatusCode::OK, resp))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paging_keeps_the_filter() {
+        let filter = Filter {
+            action: Some("purge".to_string()),
+            actor: Some(" ".to_string()),
+            node: None,
+            before: Some(500),
+        };
+
+        assert_eq!(filter.older_than(42), "action=purge&before=42");
+    }
+}
@@ -1,29 +1,74 @@
+use std::{
+    marker::PhantomData,
+    time::{Duration, Instant},
+};
+
 use async_trait::async_trait;
 use axum::{
     extract::{FromRequestParts, State},
-    response::{IntoResponse, Redirect},
+    response::{IntoResponse, Redirect, Response},
     Form,
 };
-use maud::html;
+use chrono::{NaiveDateTime, Utc};
+use http::{header::RETRY_AFTER, StatusCode};
+use maud::{html, Markup, PreEscaped};
+use miette::IntoDiagnostic;
+use ring::digest::{digest, SHA256};
 use serde::Deserialize;
-use sqlx::{query_as, SqlitePool};
-use tower_cookies::{Cookie, Cookies};
+use serde_json::json;
+use sqlx::query_as;
+use tower_cookies::{
+    cookie::{CookieBuilder, SameSite},
+    Cookie, Cookies,
+};
+use tracing::error;
 
-use crate::AppState;
+use crate::{
+    audit::{self, Actor},
+    client_ip::ClientIp,
+    AppState, WrappedError,
+};
+
+use super::{
+    csrf::{bytes_match, CsrfForm, NoFields},
+    login_limiter::retry_after_secs,
+    passkeys::PASSKEY_JS,
+    roles::{RequiredRole, Role, Viewer},
+    users::{verify_dummy_password, AdminUser},
+};
 
 pub(crate) async fn get(State(app_state): State<AppState>, cookies: Cookies) -> impl IntoResponse {
-    let private = cookies.private(&app_state.cookie_key.0);
+    let db_session = DBSession::<Viewer>::from_cookies(&app_state, &cookies)
+        .await
+        .ok()
+        .flatten();
 
-    let session_cookie = private.get("session_id");
-    let session_id = session_cookie.map(|cookie| cookie.value().to_string());
-    let db_session = DBSession::fetch_optional(&app_state.db_pool, session_id).await;
+    login_page(&app_state, db_session.as_ref(), None)
+}
 
+fn login_page(app_state: &AppState, db_session: Option<&DBSession>, error: Option<&str>) -> Markup {
     html! {
       @if let Some(s) = db_session {
         p { "Session Id: " (s.id) }
+        form method="post" action="/_caje/logout" {
+          (s.csrf_input())
+          input type="submit" value="Logout";
+        }
+      }
+
+      @if app_state.relying_party.is_some() {
+        h2 { "Passkey" }
+        button type="button" onclick="cajePasskeyLogin().catch(cajePasskeyError)" { "Login with a Passkey" }
+        p id="passkey-error" {}
+        script { (PreEscaped(PASSKEY_JS)) }
       }
 
+      h2 { "Password" }
+      @if let Some(error) = error {
+        p { strong { (error) } }
+      }
       form method="post" action="/_caje/auth" {
+        input type="text" name="username" placeholder="Username";
         input type="password" name="password";
 
         input type="submit" value="Login";
@@ -31,97 +76,412 @@ pub(crate) async fn get(State(app_state): State<AppState>, cookies: Cookies) ->
     }
 }
 
-pub(crate) struct DBSession {
+/// Sessions end if they go unused for this long
+const SESSION_IDLE_TIMEOUT: Duration = Duration::from_secs(12 * 60 * 60);
+
+/// Sessions end this long after logging in, no matter how much they are used
+const SESSION_ABSOLUTE_TIMEOUT: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// We only record when a session or API token was last used this often, since on a replica
+/// every write has to HALT the primary
+const LAST_USED_RESOLUTION: Duration = Duration::from_secs(60);
+
+const SESSION_COOKIE: &str = "session_id";
+
+/// A logged in admin user, who has at least the role `R`
+///
+/// Routes pick the role they need with the type parameter, like `DBSession<Operator>`
+pub(crate) struct DBSession<R = Viewer> {
+    pub id: i64,
+    pub admin_user_id: i64,
+    pub username: String,
+    pub role: Role,
+    pub csrf_token: String,
+    required_role: PhantomData<R>,
+}
+
+struct SessionRow {
     id: i64,
-    session_id: String,
+    admin_user_id: i64,
+    username: String,
+    role: Role,
+    csrf_token: String,
+    last_used_at: NaiveDateTime,
+    expires_at: NaiveDateTime,
 }
 
-impl DBSession {
-    async fn fetch(db_pool: &SqlitePool, session_id: String) -> Option<DBSession> {
-        query_as!(
-            DBSession,
-            "SELECT * FROM sessions WHERE session_id = $1",
+impl SessionRow {
+    fn is_expired(&self, now: NaiveDateTime) -> bool {
+        is_expired(self.last_used_at, self.expires_at, now)
+    }
+}
+
+pub(super) fn is_due_for_touch(last_used_at: NaiveDateTime, now: NaiveDateTime) -> bool {
+    now.signed_duration_since(last_used_at)
+        .to_std()
+        .is_ok_and(|since| since >= LAST_USED_RESOLUTION)
+}
+
+pub(super) fn is_expired(
+    last_used_at: NaiveDateTime,
+    expires_at: NaiveDateTime,
+    now: NaiveDateTime,
+) -> bool {
+    let idle = now.signed_duration_since(last_used_at).to_std();
+
+    now >= expires_at || idle.is_ok_and(|idle| idle >= SESSION_IDLE_TIMEOUT)
+}
+
+impl<R> From<SessionRow> for DBSession<R> {
+    fn from(row: SessionRow) -> Self {
+        Self {
+            id: row.id,
+            admin_user_id: row.admin_user_id,
+            username: row.username,
+            role: row.role,
+            csrf_token: row.csrf_token,
+            required_role: PhantomData,
+        }
+    }
+}
+
+impl<R> DBSession<R> {
+    /// Who to record in the audit log for things done in this session
+    pub(crate) fn actor(&self) -> Actor {
+        Actor {
+            admin_user_id: Some(self.admin_user_id),
+            name: self.username.clone(),
+        }
+    }
+
+    /// Look up the session from the cookie, if it is still valid
+    ///
+    /// Cookies for sessions that have expired or been revoked are removed
+    pub(crate) async fn from_cookies(
+        state: &AppState,
+        cookies: &Cookies,
+    ) -> miette::Result<Option<Self>> {
+        let private = cookies.private(&state.cookie_key.0);
+        let Some(session_cookie) = private.get(SESSION_COOKIE) else {
+            return Ok(None);
+        };
+
+        let now = Utc::now().naive_utc();
+        let Some(row) = Self::find(state, session_cookie.value(), now).await? else {
+            private.remove(session_cookie_builder("".to_string()).finish());
+
+            return Ok(None);
+        };
+
+        if is_due_for_touch(row.last_used_at, now) {
+            state
+                .db_writer
+                .write(|| async {
+                    sqlx::query!(
+                        "UPDATE Sessions SET last_used_at = $1 WHERE id = $2",
+                        now,
+                        row.id
+                    )
+                    .execute(&state.db_pool)
+                    .await
+                    .into_diagnostic()
+                })
+                .await?;
+        }
+
+        Ok(Some(row.into()))
+    }
+
+    /// Look up the session from the cookie without counting it as activity or touching the cookie
+    ///
+    /// For checking who is visiting proxied pages, where a write or `Set-Cookie` would cost every
+    /// request
+    pub(crate) async fn peek(state: &AppState, cookies: &Cookies) -> miette::Result<Option<Self>> {
+        // Most visitors have never logged in, and they shouldn't cost a query
+        if cookies.get(SESSION_COOKIE).is_none() {
+            return Ok(None);
+        }
+        let Some(session_cookie) = cookies.private(&state.cookie_key.0).get(SESSION_COOKIE) else {
+            return Ok(None);
+        };
+
+        let now = Utc::now().naive_utc();
+        let row = Self::find(state, session_cookie.value(), now).await?;
+
+        Ok(row.map(Into::into))
+    }
+
+    /// The session with this ID, if it hasn't expired
+    async fn find(
+        state: &AppState,
+        session_id: &str,
+        now: NaiveDateTime,
+    ) -> miette::Result<Option<SessionRow>> {
+        let row = query_as!(
+            SessionRow,
+            r#"SELECT Sessions.id, Sessions.admin_user_id, AdminUsers.username,
+                AdminUsers.role as "role: Role", Sessions.csrf_token, Sessions.last_used_at,
+                Sessions.expires_at
+            FROM Sessions
+            JOIN AdminUsers ON AdminUsers.id = Sessions.admin_user_id
+            WHERE Sessions.session_id = $1"#,
             session_id
         )
-        .fetch_optional(db_pool)
+        .fetch_optional(&state.db_pool)
         .await
-        .unwrap()
+        .into_diagnostic()?;
+
+        Ok(row.filter(|row| !row.is_expired(now)))
     }
+}
 
-    async fn fetch_optional(db_pool: &SqlitePool, session_id: Option<String>) -> Option<DBSession> {
-        Self::fetch(db_pool, session_id?).await
+fn session_cookie_builder(session_id: String) -> CookieBuilder<'static> {
+    Cookie::build(SESSION_COOKIE, session_id)
+        .path("/")
+        .http_only(true)
+        .secure(true)
+        .same_site(SameSite::Strict)
+}
+
+impl DBSession {
+    /// Log `admin_user_id` in, replacing any session cookie they already had
+    pub(crate) async fn start(
+        state: &AppState,
+        cookies: &Cookies,
+        admin_user_id: i64,
+    ) -> miette::Result<()> {
+        let now = Utc::now().naive_utc();
+        let expires_at =
+            now + chrono::Duration::from_std(SESSION_ABSOLUTE_TIMEOUT).into_diagnostic()?;
+        let idle_since =
+            now - chrono::Duration::from_std(SESSION_IDLE_TIMEOUT).into_diagnostic()?;
+
+        let session_id = uuid::Uuid::new_v4().to_string();
+        let csrf_token = uuid::Uuid::new_v4().simple().to_string();
+
+        state
+            .db_writer
+            .write(|| async {
+                // Tidy up while we're here, expired sessions are never let back in anyway
+                sqlx::query!(
+                    "DELETE FROM Sessions WHERE expires_at <= $1 OR last_used_at <= $2",
+                    now,
+                    idle_since
+                )
+                .execute(&state.db_pool)
+                .await
+                .into_diagnostic()?;
+
+                sqlx::query!(
+                    "INSERT INTO Sessions (session_id, admin_user_id, csrf_token, created_at, last_used_at, expires_at)
+                    VALUES ($1, $2, $3, $4, $4, $5)",
+                    session_id,
+                    admin_user_id,
+                    csrf_token,
+                    now,
+                    expires_at
+                )
+                .execute(&state.db_pool)
+                .await
+                .into_diagnostic()
+            })
+            .await?;
+
+        cookies
+            .private(&state.cookie_key.0)
+            .add(session_cookie_builder(session_id).finish());
+
+        Ok(())
     }
 }
 
 #[async_trait]
-impl FromRequestParts<AppState> for DBSession {
-    type Rejection = axum::response::Redirect;
+impl<R: RequiredRole> FromRequestParts<AppState> for DBSession<R> {
+    type Rejection = Response;
 
     async fn from_request_parts(
         parts: &mut http::request::Parts,
         state: &AppState,
     ) -> Result<Self, Self::Rejection> {
-        let cookies = Cookies::from_request_parts(parts, state).await.unwrap();
+        let cookies = Cookies::from_request_parts(parts, state)
+            .await
+            .map_err(IntoResponse::into_response)?;
 
-        let private = cookies.private(&state.cookie_key.0);
+        let session = match DBSession::<R>::from_cookies(state, &cookies).await {
+            Ok(Some(session)) => session,
+            Ok(None) => Err(Redirect::to("/_caje/auth").into_response())?,
+            Err(e) => {
+                error!(error = ?e, "Failed to look up session, sending them to login again");
 
-        let session_cookie = private.get("session_id");
-
-        let Some(session_cookie) = session_cookie else {
-            Err(Redirect::temporary("/_caje/auth"))?
+                Err(Redirect::to("/_caje/auth").into_response())?
+            }
         };
-        let session_id = session_cookie.value().to_string();
 
-        Ok(DBSession::fetch(&state.db_pool, session_id).await.unwrap())
+        if session.role < R::ROLE {
+            let resp = html! {
+                p { "You need to be an " (R::ROLE.as_str()) " to do that, you are a " (session.role.as_str()) }
+            };
+            Err((StatusCode::FORBIDDEN, resp).into_response())?
+        }
+
+        Ok(session)
     }
 }
 
+/// Whether `submitted` is `ADMIN_AUTH_KEY`, hashing both first so comparing them in constant
+/// time doesn't give away its length either
+fn is_shared_password(state: &AppState, submitted: &str) -> bool {
+    let submitted = digest(&SHA256, submitted.as_bytes());
+    let expected = digest(&SHA256, state.admin_password.as_bytes());
+
+    !state.admin_password.is_empty() && bytes_match(submitted.as_ref(), expected.as_ref())
+}
+
 #[derive(Deserialize)]
 pub(crate) struct FormState {
+    username: String,
     password: String,
 }
 
 pub(crate) async fn post(
     State(state): State<AppState>,
+    ClientIp(ip): ClientIp,
     cookies: Cookies,
     Form(form): Form<FormState>,
-) -> impl IntoResponse {
-    let private = cookies.private(&state.cookie_key.0);
+) -> Result<Response, WrappedError> {
+    let now = Instant::now();
+    if let Err(wait) = state.login_limiter.check(ip, now) {
+        let seconds = retry_after_secs(wait);
+        let error = format!("Too many failed logins, try again in {seconds} seconds");
 
-    let mut session_cookie = private.get("session_id");
+        return Ok((
+            StatusCode::TOO_MANY_REQUESTS,
+            [(RETRY_AFTER, seconds.to_string())],
+            login_page(&state, None, Some(&error)),
+        )
+            .into_response());
+    }
+
+    let username = form.username.trim();
+    if username.is_empty() {
+        let error = "A username is required to login";
 
-    if form.password != state.admin_password {
-        return Redirect::to("/_caje/list");
+        return Ok((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            login_page(&state, None, Some(error)),
+        )
+            .into_response());
     }
 
-    if let Some(s) = &session_cookie {
-        let session = DBSession::fetch(&state.db_pool, s.value().to_string()).await;
+    let user = AdminUser::fetch_by_username(&state.db_pool, username).await?;
 
-        // If you have a cookie but the DB doesn't know your session
-        // Treat it like the cookie doesn't exist
-        if session.is_none() {
-            session_cookie = None;
+    let authenticated = match &user {
+        Some(user) if user.has_password() => user.verify_password(&form.password)?,
+        _ => {
+            verify_dummy_password(&form.password)?;
+
+            is_shared_password(&state, &form.password)
+                && AdminUser::can_bootstrap(user.as_ref(), &state.db_pool).await?
         }
-    }
+    };
 
-    if session_cookie.is_none() {
-        let session_id = uuid::Uuid::new_v4().to_string();
-        query_as!(
-            DBSession,
-            "INSERT INTO sessions (session_id) VALUES ($1) returning *",
-            session_id
+    if !authenticated {
+        state.login_limiter.record_failure(ip, now);
+
+        let actor = Actor {
+            admin_user_id: user.map(|user| user.id),
+            name: username.to_string(),
+        };
+        audit::record(
+            &state,
+            &actor,
+            "login_failed",
+            json!({ "ip": ip, "method": "password" }),
         )
-        .fetch_one(&state.db_pool)
-        .await
-        .unwrap();
-
-        let session_cookie = Cookie::build("session_id", session_id)
-            .path("/")
-            .http_only(true)
-            .secure(true)
-            .finish();
-        private.add(session_cookie.clone());
+        .await?;
+
+        let error = "Incorrect username or password";
+        return Ok((
+            StatusCode::UNAUTHORIZED,
+            login_page(&state, None, Some(error)),
+        )
+            .into_response());
+    }
+
+    state.login_limiter.record_success(ip);
+
+    let user = match user {
+        Some(user) => user,
+        None => {
+            state
+                .db_writer
+                .write(|| AdminUser::create(&state.db_pool, username, None, Role::Owner))
+                .await?
+        }
     };
 
-    Redirect::to("/_caje/list")
+    DBSession::start(&state, &cookies, user.id).await?;
+
+    audit::record(
+        &state,
+        &user.actor(),
+        "login",
+        json!({ "ip": ip, "method": "password" }),
+    )
+    .await?;
+
+    Ok(Redirect::to("/_caje/list").into_response())
+}
+
+pub(crate) async fn logout(
+    State(state): State<AppState>,
+    cookies: Cookies,
+    CsrfForm { session, .. }: CsrfForm<NoFields>,
+) -> Result<impl IntoResponse, WrappedError> {
+    state
+        .db_writer
+        .write(|| async {
+            sqlx::query!("DELETE FROM Sessions WHERE id = $1", session.id)
+                .execute(&state.db_pool)
+                .await
+                .into_diagnostic()
+        })
+        .await?;
+
+    cookies
+        .private(&state.cookie_key.0)
+        .remove(session_cookie_builder("".to_string()).finish());
+
+    audit::record(&state, &session.actor(), "logout", json!({})).await?;
+
+    Ok(Redirect::to("/_caje/auth"))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+
+    use super::*;
+
+    #[test]
+    fn sessions_expire_when_idle_or_too_old() {
+        let now = Utc::now().naive_utc();
+        let tomorrow = now + Duration::days(1);
+
+        assert!(!is_expired(now - Duration::hours(1), tomorrow, now));
+        assert!(is_expired(now - Duration::hours(13), tomorrow, now));
+        assert!(is_expired(now, now - Duration::seconds(1), now));
+    }
+
+    #[test]
+    fn sessions_used_in_the_future_are_not_idle() {
+        // Clocks between nodes aren't perfectly in sync
+        let now = Utc::now().naive_utc();
+
+        assert!(!is_expired(
+            now + Duration::minutes(1),
+            now + Duration::days(1),
+            now
+        ));
+    }
 }
@@ -2,20 +2,21 @@ use axum::{
     extract::State,
     response::{IntoResponse, Redirect},
 };
-use miette::IntoDiagnostic;
-use sqlx::SqlitePool;
+use serde_json::json;
 
-use super::auth::DBSession;
+use crate::{audit, AppState, WrappedError};
+
+use super::{
+    csrf::{CsrfForm, NoFields},
+    roles::Owner,
+};
 
 pub(crate) async fn route(
-    State(db_pool): State<SqlitePool>,
-    _: DBSession,
-) -> Result<impl IntoResponse, String> {
-    sqlx::query!("DELETE FROM Pages")
-        .execute(&db_pool)
-        .await
-        .into_diagnostic()
-        .map_err(|e| e.to_string())?;
+    State(state): State<AppState>,
+    CsrfForm { session, .. }: CsrfForm<NoFields, Owner>,
+) -> Result<impl IntoResponse, WrappedError> {
+    state.manifest.clear().await?;
+    audit::record(&state, &session.actor(), "clear_db", json!({})).await?;
 
     Ok(Redirect::to("/_caje/list"))
 }
@@ -1,15 +1,30 @@
-use axum::response::{IntoResponse, Redirect};
+use axum::{
+    extract::State,
+    response::{IntoResponse, Redirect},
+};
 use miette::IntoDiagnostic;
+use serde_json::json;
 
-use crate::CACHE_DIR;
+use crate::{audit, AppState, WrappedError, CACHE_DIR};
 
-use super::auth::DBSession;
+use super::{
+    csrf::{CsrfForm, NoFields},
+    roles::Operator,
+};
 
-pub(crate) async fn route(_: DBSession) -> Result<impl IntoResponse, String> {
-    cacache::clear(CACHE_DIR)
-        .await
-        .into_diagnostic()
-        .map_err(|e| e.to_string())?;
+pub(crate) async fn route(
+    State(state): State<AppState>,
+    CsrfForm { session, .. }: CsrfForm<NoFields, Operator>,
+) -> Result<impl IntoResponse, WrappedError> {
+    clear_fs(&state).await?;
+    audit::record(&state, &session.actor(), "clear_fs", json!({})).await?;
 
     Ok(Redirect::to("/_caje/list"))
 }
+
+/// Remove every response from this node's cache, other nodes keep theirs
+pub(crate) async fn clear_fs(state: &AppState) -> miette::Result<()> {
+    cacache::clear(CACHE_DIR).await.into_diagnostic()?;
+
+    state.manifest.forget_node().await
+}
@@ -0,0 +1,116 @@
+use axum::{extract::State, response::IntoResponse};
+use chrono::{NaiveDateTime, Utc};
+use http::StatusCode;
+use maud::html;
+
+use crate::{
+    cluster::{Heartbeat, HEARTBEAT_STALE_AFTER},
+    node::node_name,
+    AppState, WrappedError,
+};
+
+use super::{auth::DBSession, stats::human_bytes};
+
+/// How long ago `then` was, roughly, for humans
+fn ago(now: NaiveDateTime, then: NaiveDateTime) -> String {
+    let seconds = (now - then).num_seconds().max(0);
+
+    match seconds {
+        0..=59 => format!("{seconds}s ago"),
+        60..=3599 => format!("{}m ago", seconds / 60),
+        3600..=86399 => format!("{}h ago", seconds / 3600),
+        _ => format!("{}d ago", seconds / 86400),
+    }
+}
+
+pub(crate) async fn index(
+    State(state): State<AppState>,
+    _: DBSession,
+) -> Result<impl IntoResponse, WrappedError> {
+    let heartbeats = Heartbeat::list(&state).await?;
+    let now = Utc::now().naive_utc();
+    let this_node = node_name();
+    let stale = heartbeats
+        .iter()
+        .filter(|heartbeat| heartbeat.is_stale(now))
+        .count();
+
+    let resp = html! {
+        h2 { "Cluster" }
+        p {
+            (heartbeats.len())
+            @if heartbeats.len() == 1 { " node, " } @else { " nodes, " }
+            @if stale > 0 {
+                strong { (stale) " haven't sent a heartbeat in over " (HEARTBEAT_STALE_AFTER.as_secs()) "s" }
+            } @else {
+                "all sending heartbeats"
+            }
+        }
+
+        table {
+            tr {
+                th { "Node" }
+                th { "Region" }
+                th { "Version" }
+                th { "Role" }
+                th { "Cache Size" }
+                th { "Entries" }
+                th { "Replication Lag" }
+                th { "Last Populate" }
+                th { "Started" }
+                th { "Last Heartbeat" }
+            }
+            @for heartbeat in &heartbeats {
+                tr {
+                    td {
+                        (heartbeat.node)
+                        @if heartbeat.node == this_node { " (this node)" }
+                    }
+                    td { (heartbeat.region.as_deref().unwrap_or("-")) }
+                    td { (heartbeat.version) }
+                    td { @if heartbeat.is_primary { "Primary" } @else { "Replica" } }
+                    td { (human_bytes(heartbeat.cache_size)) }
+                    td { (heartbeat.entry_count) }
+                    td {
+                        @match heartbeat.lag_ms {
+                            Some(lag_ms) => { (lag_ms) "ms" },
+                            None => "-",
+                        }
+                    }
+                    td {
+                        @match heartbeat.last_populate_at {
+                            Some(at) => { (ago(now, at)) },
+                            None => "Never",
+                        }
+                    }
+                    td { (ago(now, heartbeat.started_at)) }
+                    td {
+                        @if heartbeat.is_stale(now) {
+                            strong { "Stale, " (ago(now, heartbeat.updated_at)) }
+                        } @else {
+                            (ago(now, heartbeat.updated_at))
+                        }
+                    }
+                }
+            }
+        }
+    };
+
+    Ok((StatusCode::OK, resp))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn describes_how_long_ago() {
+        let now = Utc::now().naive_utc();
+
+        assert_eq!(ago(now, now - chrono::Duration::seconds(5)), "5s ago");
+        assert_eq!(ago(now, now - chrono::Duration::seconds(125)), "2m ago");
+        assert_eq!(ago(now, now - chrono::Duration::hours(3)), "3h ago");
+        assert_eq!(ago(now, now - chrono::Duration::days(2)), "2d ago");
+        assert_eq!(ago(now, now + chrono::Duration::seconds(5)), "0s ago");
+    }
+}
@@ -0,0 +1,175 @@
+use std::collections::HashMap;
+
+use axum::{
+    extract::State,
+    response::{IntoResponse, Redirect},
+};
+use chrono::{NaiveDateTime, Utc};
+use http::StatusCode;
+use maud::html;
+use miette::IntoDiagnostic;
+use serde::Deserialize;
+use sqlx::query_as;
+
+use crate::{
+    cluster::Heartbeat,
+    commands::{self, Command, CommandAction},
+    AppState, WrappedError,
+};
+
+use super::{
+    auth::DBSession,
+    csrf::CsrfForm,
+    roles::{Operator, Role},
+};
+
+/// How many of the most recent commands to show
+const RECENT_COMMANDS: i64 = 50;
+
+struct CommandResult {
+    command_id: i64,
+    node: String,
+    started_at: NaiveDateTime,
+    /// `None` while it's still running
+    succeeded: Option<bool>,
+    result: String,
+    completed_at: Option<NaiveDateTime>,
+}
+
+pub(crate) async fn index(
+    State(state): State<AppState>,
+    session: DBSession,
+) -> Result<impl IntoResponse, WrappedError> {
+    let commands = query_as!(
+        Command,
+        r#"SELECT id, action as "action: CommandAction", target_node, requested_by_id,
+            requested_by, created_at
+        FROM NodeCommands
+        ORDER BY id DESC
+        LIMIT ?"#,
+        RECENT_COMMANDS
+    )
+    .fetch_all(&state.db_pool)
+    .await
+    .into_diagnostic()?;
+
+    let results = query_as!(
+        CommandResult,
+        "SELECT command_id, node, started_at, succeeded, result, completed_at
+        FROM NodeCommandResults
+        WHERE command_id IN (SELECT id FROM NodeCommands ORDER BY id DESC LIMIT ?)
+        ORDER BY started_at",
+        RECENT_COMMANDS
+    )
+    .fetch_all(&state.db_pool)
+    .await
+    .into_diagnostic()?;
+
+    // Nodes that have stopped sending heartbeats aren't going to pick anything up
+    let now = Utc::now().naive_utc();
+    let nodes = Heartbeat::list(&state)
+        .await?
+        .into_iter()
+        .filter(|heartbeat| !heartbeat.is_stale(now))
+        .map(|heartbeat| heartbeat.node)
+        .collect::<Vec<_>>();
+
+    let mut results_by_command: HashMap<i64, Vec<CommandResult>> = HashMap::new();
+    for result in results {
+        results_by_command
+            .entry(result.command_id)
+            .or_default()
+            .push(result);
+    }
+
+    let resp = html! {
+        @if session.role >= Role::Operator {
+            h2 { "Run On Nodes" }
+            form method="post" action="/_caje/commands" {
+                (session.csrf_input())
+                select name="action" {
+                    @for action in CommandAction::ALL {
+                        option value=(action.as_str()) { (action.as_str()) }
+                    }
+                }
+                select name="target_node" {
+                    option value="" { "All nodes" }
+                    @for node in &nodes {
+                        option value=(node) { (node) }
+                    }
+                }
+                input type="submit" value="Queue";
+            }
+        }
+
+        h2 { "Recent Commands" }
+        table {
+            tr {
+                th { "Requested" }
+                th { "By" }
+                th { "Action" }
+                th { "Target" }
+                th { "Results" }
+                th { "Pending" }
+            }
+            @for command in commands {
+                @let results = results_by_command.remove(&command.id).unwrap_or_default();
+                tr {
+                    td { (command.created_at) }
+                    td { (command.requested_by) }
+                    td { (command.action.as_str()) }
+                    td { (command.target_node.as_deref().unwrap_or("All nodes")) }
+                    td {
+                        ul {
+                            @for result in &results {
+                                li {
+                                    (result.node)
+                                    @match (result.succeeded, result.completed_at) {
+                                        (Some(succeeded), Some(completed_at)) => {
+                                            @if succeeded { ": done " } @else { ": failed " }
+                                            code { (result.result) }
+                                            " at " (completed_at)
+                                        }
+                                        _ => { ": running since " (result.started_at) }
+                                    }
+                                }
+                            }
+                        }
+                    }
+                    td {
+                        ul {
+                            @for node in nodes.iter().filter(|node| command.targets(node) && results.iter().all(|r| &r.node != *node)) {
+                                li { (node) }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    };
+
+    Ok((StatusCode::OK, resp))
+}
+
+#[derive(Deserialize)]
+pub(crate) struct CommandForm {
+    action: CommandAction,
+    /// Empty for every node
+    #[serde(default)]
+    target_node: String,
+}
+
+pub(crate) async fn create(
+    State(state): State<AppState>,
+    CsrfForm { session, form }: CsrfForm<CommandForm, Operator>,
+) -> Result<impl IntoResponse, WrappedError> {
+    commands::enqueue(
+        &state,
+        &session.actor(),
+        form.action,
+        Some(form.target_node.as_str()),
+    )
+    .await?;
+
+    Ok(Redirect::to("/_caje/commands"))
+}
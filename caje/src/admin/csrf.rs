@@ -0,0 +1,113 @@
+use async_trait::async_trait;
+use axum::{
+    body::{Bytes, HttpBody},
+    extract::{FromRequest, FromRequestParts},
+    response::{IntoResponse, Response},
+    BoxError,
+};
+use http::{Request, StatusCode};
+use maud::{html, Markup};
+use serde::{de::DeserializeOwned, Deserialize};
+
+use crate::AppState;
+
+use super::{
+    auth::DBSession,
+    roles::{RequiredRole, Viewer},
+};
+
+/// The form field the token is submitted in
+const CSRF_FIELD: &str = "csrf_token";
+
+impl<R> DBSession<R> {
+    /// The hidden input every admin form needs to include, for `CsrfForm` to accept it
+    pub(crate) fn csrf_input(&self) -> Markup {
+        html! {
+            input type="hidden" name=(CSRF_FIELD) value=(self.csrf_token);
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct CsrfToken {
+    csrf_token: String,
+}
+
+/// For forms that have nothing to submit but the CSRF token
+#[derive(Deserialize)]
+pub(crate) struct NoFields {}
+
+/// A form POSTed by a logged in admin, that carries the CSRF token from their session
+///
+/// Use this instead of `Form` for anything that changes state, so other sites can't get an
+/// admin's browser to do it for them
+pub(crate) struct CsrfForm<T, R = Viewer> {
+    pub session: DBSession<R>,
+    pub form: T,
+}
+
+pub(super) fn tokens_match(submitted: &str, expected: &str) -> bool {
+    !expected.is_empty() && bytes_match(submitted.as_bytes(), expected.as_bytes())
+}
+
+/// Compare every byte so how long this takes doesn't give the secret away
+pub(super) fn bytes_match(submitted: &[u8], expected: &[u8]) -> bool {
+    if submitted.len() != expected.len() {
+        return false;
+    }
+
+    submitted
+        .iter()
+        .zip(expected)
+        .fold(0, |acc, (a, b)| acc | (a ^ b))
+        == 0
+}
+
+#[async_trait]
+impl<T, R, B> FromRequest<AppState, B> for CsrfForm<T, R>
+where
+    T: DeserializeOwned,
+    R: RequiredRole,
+    B: HttpBody + Send + 'static,
+    B::Data: Send,
+    B::Error: Into<BoxError>,
+{
+    type Rejection = Response;
+
+    async fn from_request(req: Request<B>, state: &AppState) -> Result<Self, Self::Rejection> {
+        let (mut parts, body) = req.into_parts();
+        let session = DBSession::<R>::from_request_parts(&mut parts, state).await?;
+
+        let body = Bytes::from_request(Request::from_parts(parts, body), state)
+            .await
+            .map_err(IntoResponse::into_response)?;
+
+        let submitted = serde_urlencoded::from_bytes::<CsrfToken>(&body)
+            .map(|token| token.csrf_token)
+            .unwrap_or_default();
+        if !tokens_match(&submitted, &session.csrf_token) {
+            let resp = html! {
+                p { "This form has expired, go back, refresh the page and try again" }
+            };
+            Err((StatusCode::FORBIDDEN, resp).into_response())?
+        }
+
+        let form = serde_urlencoded::from_bytes(&body)
+            .map_err(|e| (StatusCode::UNPROCESSABLE_ENTITY, e.to_string()).into_response())?;
+
+        Ok(Self { session, form })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens_must_match_exactly() {
+        assert!(tokens_match("abc123", "abc123"));
+        assert!(!tokens_match("abc124", "abc123"));
+        assert!(!tokens_match("abc12", "abc123"));
+        assert!(!tokens_match("", ""));
+    }
+}
@@ -0,0 +1,481 @@
+use std::time::{Duration, SystemTime};
+
+use axum::{
+    extract::{Query, State},
+    response::{IntoResponse, Redirect},
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{DateTime, Utc};
+use http::{
+    header::{AUTHORIZATION, CACHE_CONTROL},
+    HeaderMap, HeaderValue, Method, StatusCode,
+};
+use http_cache_semantics::CachePolicy;
+use maud::{html, Markup};
+use miette::miette;
+use ring::digest::{digest, SHA256};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::{
+    audit, http_request_from_parts, http_response_from_parts, read_from_cache, stale::stale_window,
+    tags::entry_tags, AppState, CachedResponse, InnerCachedRequest, InnerCachedResponse,
+    WrappedError, CACHE_DIR,
+};
+
+use super::{
+    auth::DBSession,
+    csrf::CsrfForm,
+    populate::fetch_and_cache,
+    roles::{Operator, Role},
+};
+
+/// Something that can stop a shared cache like us storing a response
+struct Obstacle {
+    /// Change the request or response so this isn't what's stopping it
+    remove: fn(&mut InnerCachedRequest, &mut InnerCachedResponse),
+    explain: fn(&InnerCachedRequest, &InnerCachedResponse) -> String,
+}
+
+const OBSTACLES: [Obstacle; 6] = [
+    Obstacle {
+        remove: |request, _| request.method = Method::GET,
+        explain: |request, response| {
+            if is_storable(request, &with_max_age(response)) {
+                format!(
+                    "{} responses are only cached with max-age, s-maxage or Expires",
+                    request.method
+                )
+            } else {
+                format!("{} responses aren't cached", request.method)
+            }
+        },
+    },
+    Obstacle {
+        remove: |request, _| remove_directive(&mut request.headers, "no-store"),
+        explain: |_, _| "The request said no-store".to_string(),
+    },
+    Obstacle {
+        remove: |_, response| remove_directive(&mut response.headers, "no-store"),
+        explain: |_, _| "The response said no-store".to_string(),
+    },
+    Obstacle {
+        remove: |_, response| remove_directive(&mut response.headers, "private"),
+        explain: |_, _| "The response is private, and caje is a shared cache".to_string(),
+    },
+    Obstacle {
+        remove: |request, _| {
+            request.headers.remove(AUTHORIZATION);
+        },
+        explain: |_, _| {
+            "The request was authorized, and the response wasn't public, s-maxage or must-revalidate"
+                .to_string()
+        },
+    },
+    Obstacle {
+        remove: |_, response| response.status_code = StatusCode::OK,
+        explain: |_, response| {
+            let status = response.status_code.as_u16();
+            if is_storable_as_get(response) {
+                format!("Status {status} needs max-age, s-maxage, Expires or public to be cached")
+            } else {
+                format!("Caches don't understand status {status}")
+            }
+        },
+    },
+];
+
+/// What `CachePolicy` says, which is what decides whether we store a response
+fn is_storable(request: &InnerCachedRequest, response: &InnerCachedResponse) -> bool {
+    match (
+        http_request_from_parts(request.clone()),
+        http_response_from_parts(response.clone()),
+    ) {
+        (Ok(request), Ok(response)) => CachePolicy::new(&request, &response).is_storable(),
+        _ => false,
+    }
+}
+
+/// Whether `response` could be stored if it had a max-age and the request was a plain GET
+fn is_storable_as_get(response: &InnerCachedResponse) -> bool {
+    let request = InnerCachedRequest {
+        method: Method::GET,
+        uri: http::Uri::from_static("/"),
+        version: response.version,
+        headers: HeaderMap::new(),
+        body: None,
+    };
+
+    is_storable(&request, &with_max_age(response))
+}
+
+fn with_max_age(response: &InnerCachedResponse) -> InnerCachedResponse {
+    let mut response = response.clone();
+    response
+        .headers
+        .append(CACHE_CONTROL, HeaderValue::from_static("max-age=60"));
+
+    response
+}
+
+fn remove_directive(headers: &mut HeaderMap, directive: &str) {
+    let kept = headers
+        .get_all(CACHE_CONTROL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .filter(|part| {
+            let name = part.split('=').next().unwrap_or_default().trim();
+            !part.is_empty() && !name.eq_ignore_ascii_case(directive)
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    headers.remove(CACHE_CONTROL);
+    if let Ok(kept) = HeaderValue::from_str(&kept) {
+        if !kept.is_empty() {
+            headers.insert(CACHE_CONTROL, kept);
+        }
+    }
+}
+
+/// Why a response can't be stored by a shared cache like us, empty if it can
+///
+/// `CachePolicy::is_storable` only tells us yes or no, so we ask it again with every other
+/// obstacle taken away, and list the ones that still stop it storing the response on their own
+fn storability_problems(
+    request: &InnerCachedRequest,
+    response: &InnerCachedResponse,
+) -> Vec<String> {
+    if is_storable(request, response) {
+        return vec![];
+    }
+
+    let without = |skip: Option<usize>| {
+        let (mut request, mut response) = (request.clone(), response.clone());
+        for (i, obstacle) in OBSTACLES.iter().enumerate() {
+            if Some(i) != skip {
+                (obstacle.remove)(&mut request, &mut response);
+            }
+        }
+
+        (request, response)
+    };
+
+    let (request, response) = without(None);
+    if !is_storable(&request, &response) {
+        return vec!["The cache won't store it, for a reason we can't explain".to_string()];
+    }
+
+    OBSTACLES
+        .iter()
+        .enumerate()
+        .filter_map(|(i, obstacle)| {
+            let (request, response) = without(Some(i));
+
+            (!is_storable(&request, &response)).then(|| (obstacle.explain)(&request, &response))
+        })
+        .collect()
+}
+
+fn body_hash(body: &[u8]) -> String {
+    format!("sha256-{}", STANDARD.encode(digest(&SHA256, body)))
+}
+
+fn entry_href(key: &str) -> String {
+    format!(
+        "/_caje/entry?{}",
+        serde_urlencoded::to_string([("key", key)]).unwrap_or_default()
+    )
+}
+
+/// Link to the inspector for a cache key
+pub(crate) fn entry_link(key: &str) -> Markup {
+    html! { a href=(entry_href(key)) { code { (key) } } }
+}
+
+fn seconds(duration: Duration) -> String {
+    format!("{}s", duration.as_secs())
+}
+
+fn headers_table(headers: &HeaderMap) -> Markup {
+    html! {
+        table {
+            @for (name, value) in headers {
+                tr {
+                    th { (name.as_str()) }
+                    td { code { (String::from_utf8_lossy(value.as_bytes())) } }
+                }
+            }
+        }
+    }
+}
+
+fn cached_details(cached: &CachedResponse, now: SystemTime) -> Result<Markup, WrappedError> {
+    let (policy, _) = cached.policy()?;
+    let problems = storability_problems(&cached.request, &cached.response);
+    let stale_while_revalidate = stale_window(&cached.response.headers, "stale-while-revalidate");
+    let stale_if_error = stale_window(&cached.response.headers, "stale-if-error");
+
+    Ok(html! {
+        table {
+            tr { th { "Cached At" } td { (DateTime::<Utc>::from(cached.cached_at)) } }
+            tr { th { "Age" } td { (seconds(policy.age(now))) } }
+            tr { th { "TTL" } td { (seconds(policy.time_to_live(now))) } }
+            tr {
+                th { "stale-while-revalidate" }
+                td { (stale_while_revalidate.map(seconds).unwrap_or_else(|| "None".to_string())) }
+            }
+            tr {
+                th { "stale-if-error" }
+                td { (stale_if_error.map(seconds).unwrap_or_else(|| "None".to_string())) }
+            }
+            tr { th { "Status" } td { (cached.response.status_code) } }
+            tr { th { "Version" } td { (format!("{:?}", cached.response.version)) } }
+            tr { th { "Body Size" } td { (cached.response.body.len()) } }
+            tr { th { "Body Hash" } td { code { (body_hash(&cached.response.body)) } } }
+            tr {
+                th { "Storable" }
+                td {
+                    @if policy.is_storable() { "Yes" } @else { "No" }
+                    @if !problems.is_empty() {
+                        ul {
+                            @for problem in &problems {
+                                li { (problem) }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        h3 { "Request" }
+        p { code { (cached.request.method) " " (cached.request.uri) " " (format!("{:?}", cached.request.version)) } }
+        (headers_table(&cached.request.headers))
+
+        h3 { "Response Headers" }
+        (headers_table(&cached.response.headers))
+    })
+}
+
+#[derive(Deserialize)]
+pub(crate) struct EntryQuery {
+    key: String,
+}
+
+pub(crate) async fn index(
+    State(state): State<AppState>,
+    session: DBSession,
+    Query(query): Query<EntryQuery>,
+) -> Result<impl IntoResponse, WrappedError> {
+    let key = query.key;
+    let locations = match key.split_once('@') {
+        Some((method, url)) => state.manifest.locations(method, url).await?,
+        None => vec![],
+    };
+    let locations = html! {
+        p {
+            "Cached on: "
+            @if locations.is_empty() { "No nodes in the manifest" } @else { (locations.join(", ")) }
+        }
+    };
+
+    let metadata = cacache::metadata(CACHE_DIR, &key)
+        .await
+        .map_err(|e| miette!("Could not read the cache index: {e}"))?;
+    let Some(metadata) = metadata else {
+        return Ok((
+            StatusCode::NOT_FOUND,
+            html! {
+                h2 { "Not Cached" }
+                p { code { (key) } " isn't cached on this node" }
+                (locations)
+                p { a href="/_caje/list" { "Back to the cache" } }
+            },
+        ));
+    };
+
+    let url = key.split_once('@').map(|(_, url)| url).unwrap_or(&key);
+    let tags = entry_tags(&metadata.metadata).collect::<Vec<_>>();
+    let details = match read_from_cache(&key).await {
+        Ok(cached) => cached_details(&cached, SystemTime::now()),
+        Err(e) => Err(e.into()),
+    };
+
+    let resp = html! {
+        h2 { "Cache Entry " code { (key) } }
+        p { a href="/_caje/list" { "Back to the cache" } }
+
+        @if session.role >= Role::Operator {
+            form method="post" action="/_caje/purges" {
+                (session.csrf_input())
+                input type="hidden" name="kind" value="url";
+                input type="hidden" name="pattern" value=(url);
+                input type="submit" value="Purge";
+            }
+            form method="post" action="/_caje/purges" {
+                (session.csrf_input())
+                input type="hidden" name="kind" value="url";
+                input type="hidden" name="pattern" value=(url);
+                input type="hidden" name="soft" value="true";
+                input type="submit" value="Soft Purge";
+            }
+            form method="post" action="/_caje/entry/refetch" {
+                (session.csrf_input())
+                input type="hidden" name="key" value=(key);
+                input type="submit" value="Refetch";
+            }
+        }
+
+        p {
+            "Stored " (metadata.size) " bytes, tags: "
+            @if tags.is_empty() { "None" } @else { (tags.join(" ")) }
+        }
+        (locations)
+
+        @match details {
+            Ok(details) => { (details) }
+            // Corrupt entries can still be purged or refetched from here
+            Err(e) => { p { strong { "Unreadable: " } (e.0) } }
+        }
+    };
+
+    Ok((StatusCode::OK, resp))
+}
+
+#[derive(Deserialize)]
+pub(crate) struct RefetchForm {
+    key: String,
+}
+
+pub(crate) async fn refetch(
+    State(state): State<AppState>,
+    CsrfForm { session, form }: CsrfForm<RefetchForm, Operator>,
+) -> Result<impl IntoResponse, WrappedError> {
+    let (method, url) = form
+        .key
+        .split_once('@')
+        .ok_or_else(|| miette!("{} isn't a cache key", form.key))?;
+
+    let cached = fetch_and_cache(&state, method, url).await?;
+    audit::record(
+        &state,
+        &session.actor(),
+        "refetch",
+        json!({ "key": form.key, "cached": cached }),
+    )
+    .await?;
+
+    if !cached {
+        Err(miette!("The origin says {url} can't be cached right now"))?
+    }
+
+    Ok(Redirect::to(&entry_href(&form.key)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: Method) -> InnerCachedRequest {
+        InnerCachedRequest {
+            method,
+            uri: "/about".parse().unwrap(),
+            version: http::Version::HTTP_11,
+            headers: HeaderMap::new(),
+            body: None,
+        }
+    }
+
+    fn response(status: u16, cache_control: &'static str) -> InnerCachedResponse {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            http::header::CACHE_CONTROL,
+            HeaderValue::from_static(cache_control),
+        );
+
+        InnerCachedResponse {
+            status_code: StatusCode::from_u16(status).unwrap(),
+            version: http::Version::HTTP_11,
+            headers,
+            body: vec![],
+        }
+    }
+
+    #[test]
+    fn explains_why_responses_cant_be_stored() {
+        assert!(
+            storability_problems(&request(Method::GET), &response(200, "max-age=60")).is_empty()
+        );
+
+        let problems = storability_problems(&request(Method::POST), &response(200, "private"));
+        assert_eq!(
+            problems,
+            vec![
+                "POST responses are only cached with max-age, s-maxage or Expires",
+                "The response is private, and caje is a shared cache"
+            ]
+        );
+
+        let problems = storability_problems(&request(Method::GET), &response(302, "no-store"));
+        assert_eq!(
+            problems,
+            vec![
+                "The response said no-store",
+                "Status 302 needs max-age, s-maxage, Expires or public to be cached"
+            ]
+        );
+
+        let problems = storability_problems(&request(Method::GET), &response(500, "max-age=60"));
+        assert_eq!(problems, vec!["Caches don't understand status 500"]);
+    }
+
+    #[test]
+    fn agrees_with_the_cache_policy() {
+        let mut authorized = request(Method::GET);
+        authorized
+            .headers
+            .insert(AUTHORIZATION, HeaderValue::from_static("Bearer abc"));
+        let mut no_store = request(Method::GET);
+        no_store
+            .headers
+            .insert(CACHE_CONTROL, HeaderValue::from_static("no-store"));
+
+        let requests = [
+            request(Method::GET),
+            request(Method::HEAD),
+            request(Method::POST),
+            request(Method::DELETE),
+            authorized,
+            no_store,
+        ];
+        let responses = [
+            response(200, "max-age=60"),
+            response(200, "no-cache"),
+            response(200, "private, max-age=60"),
+            response(200, "public"),
+            response(200, "s-maxage=60"),
+            response(200, "must-revalidate, max-age=60"),
+            response(204, "no-store"),
+            response(302, "no-cache"),
+            response(404, "max-age=60, private"),
+            response(500, "max-age=60"),
+        ];
+
+        for request in &requests {
+            for response in &responses {
+                let problems = storability_problems(request, response);
+
+                assert_eq!(
+                    problems.is_empty(),
+                    is_storable(request, response),
+                    "{} {:?}: {problems:?}",
+                    request.method,
+                    response.headers
+                );
+                assert!(!problems.iter().any(|p| p.contains("can't explain")));
+            }
+        }
+    }
+}
@@ -1,64 +1,545 @@
-use std::time::SystemTime;
+use std::{
+    cmp::Reverse,
+    collections::HashSet,
+    time::{Duration, SystemTime},
+};
 
-use axum::{extract::State, response::IntoResponse};
+use axum::{
+    extract::{Query, State},
+    response::IntoResponse,
+};
 use cacache::Metadata;
-use http::StatusCode;
-use maud::html;
+use http::{header::HOST, StatusCode};
+use maud::{html, Markup};
 use miette::IntoDiagnostic;
-use sqlx::SqlitePool;
+use serde::{Deserialize, Serialize};
 
-use crate::{get_policy_from_cache, CACHE_DIR};
+use crate::{cache_key, manifest::Page, read_from_cache, AppState, WrappedError, CACHE_DIR};
 
-use super::auth::DBSession;
+use super::{auth::DBSession, entry::entry_link, roles::Role};
+
+/// How many rows we show per page
+const PAGE_SIZE: usize = 50;
+
+/// Which slice of the cache and manifest to browse
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum View {
+    /// Everything cached on this node
+    #[default]
+    Cache,
+    /// Everything in the shared manifest
+    Manifest,
+    /// In the manifest, but not cached on this node
+    Missing,
+    /// Cached on this node, but not in the manifest
+    Unmanifested,
+}
+
+impl View {
+    const ALL: [View; 4] = [
+        View::Cache,
+        View::Manifest,
+        View::Missing,
+        View::Unmanifested,
+    ];
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            View::Cache => "cache",
+            View::Manifest => "manifest",
+            View::Missing => "missing",
+            View::Unmanifested => "unmanifested",
+        }
+    }
+
+    fn label(&self) -> &'static str {
+        match self {
+            View::Cache => "On this node",
+            View::Manifest => "In the manifest",
+            View::Missing => "In the manifest but not on this node",
+            View::Unmanifested => "On this node but not in the manifest",
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Sort {
+    #[default]
+    Key,
+    /// Expiring soonest first
+    Ttl,
+    /// Biggest first
+    Size,
+    /// Most hit first
+    Hits,
+}
+
+impl Sort {
+    const ALL: [Sort; 4] = [Sort::Key, Sort::Ttl, Sort::Size, Sort::Hits];
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Sort::Key => "key",
+            Sort::Ttl => "ttl",
+            Sort::Size => "size",
+            Sort::Hits => "hits",
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Deserialize, Serialize)]
+pub(crate) struct ListQuery {
+    #[serde(default)]
+    view: View,
+    /// Only show entries whose URL or host contain this
+    #[serde(default)]
+    q: String,
+    /// Only show entries with this status code, blank for all of them
+    #[serde(default)]
+    status: String,
+    #[serde(default)]
+    sort: Sort,
+    #[serde(default)]
+    page: usize,
+}
+
+impl ListQuery {
+    fn status(&self) -> Option<u16> {
+        self.status.trim().parse().ok()
+    }
+
+    /// Whether filtering or sorting looks inside the cached entries, rather than just the index
+    fn needs_details(&self) -> bool {
+        self.status().is_some() || self.sort == Sort::Ttl || !self.q.trim().is_empty()
+    }
+
+    fn matches_search(&self, haystacks: &[&str]) -> bool {
+        let q = self.q.trim();
+
+        q.is_empty() || haystacks.iter().any(|haystack| haystack.contains(q))
+    }
+
+    fn href(&self) -> String {
+        format!(
+            "/_caje/list?{}",
+            serde_urlencoded::to_string(self).unwrap_or_default()
+        )
+    }
+
+    fn with_page(&self, page: usize) -> Self {
+        Self {
+            page,
+            ..self.clone()
+        }
+    }
+
+    fn with_view(&self, view: View) -> Self {
+        Self {
+            view,
+            page: 0,
+            ..self.clone()
+        }
+    }
+}
+
+/// What we could read out of a cached entry
+#[derive(Debug, Clone)]
+struct EntryDetails {
+    host: String,
+    status: u16,
+    ttl: Duration,
+}
+
+/// One entry in this node's cache
+#[derive(Debug, Clone)]
+struct CacheRow {
+    key: String,
+    size: usize,
+    hits: u64,
+    /// `None` until we've read the entry, which we only do when we need to
+    ///
+    /// Entries can be corrupt or written by an older version, we still want to list them
+    details: Option<Result<EntryDetails, String>>,
+}
+
+impl CacheRow {
+    fn url(&self) -> &str {
+        self.key
+            .split_once('@')
+            .map(|(_, url)| url)
+            .unwrap_or(&self.key)
+    }
+
+    fn loaded_details(&self) -> Option<&EntryDetails> {
+        self.details
+            .as_ref()
+            .and_then(|details| details.as_ref().ok())
+    }
+
+    fn matches(&self, query: &ListQuery) -> bool {
+        let host = self.loaded_details().map(|d| d.host.as_str()).unwrap_or("");
+        let status_matches = match query.status() {
+            Some(status) => self.loaded_details().is_some_and(|d| d.status == status),
+            None => true,
+        };
+
+        status_matches && query.matches_search(&[self.url(), host])
+    }
+}
+
+async fn read_details(key: &str, now: SystemTime) -> miette::Result<EntryDetails> {
+    let cached = read_from_cache(key).await?;
+    let host = cached
+        .request
+        .headers
+        .get(HOST)
+        .and_then(|host| host.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+    let status = cached.response.status_code.as_u16();
+    let (policy, _) = cached.policy()?;
+
+    Ok(EntryDetails {
+        host,
+        status,
+        ttl: policy.time_to_live(now),
+    })
+}
+
+/// Read the entries we haven't yet, which deserializes each whole cached response
+async fn load_details(rows: &mut [CacheRow], now: SystemTime) {
+    for row in rows.iter_mut().filter(|row| row.details.is_none()) {
+        row.details = Some(read_details(&row.key, now).await.map_err(|e| e.to_string()));
+    }
+}
+
+/// Filter and sort the cache entries for a query, before paginating
+fn filter_and_sort(rows: Vec<CacheRow>, query: &ListQuery) -> Vec<CacheRow> {
+    let mut rows = rows
+        .into_iter()
+        .filter(|row| row.matches(query))
+        .collect::<Vec<_>>();
+
+    match query.sort {
+        Sort::Key => rows.sort_by(|a, b| a.key.cmp(&b.key)),
+        // Unreadable entries go last, there's nothing to sort them by
+        Sort::Ttl => {
+            rows.sort_by_key(|row| row.loaded_details().map(|d| d.ttl).unwrap_or(Duration::MAX))
+        }
+        Sort::Size => rows.sort_by_key(|row| Reverse(row.size)),
+        Sort::Hits => rows.sort_by_key(|row| Reverse(row.hits)),
+    }
+
+    rows
+}
+
+/// `page` comes straight from the query string, so a huge one is just past the end
+fn paginate<T>(rows: Vec<T>, page: usize) -> (Vec<T>, bool) {
+    let has_more = rows.len() > page.saturating_add(1).saturating_mul(PAGE_SIZE);
+    let rows = rows
+        .into_iter()
+        .skip(page.saturating_mul(PAGE_SIZE))
+        .take(PAGE_SIZE)
+        .collect();
+
+    (rows, has_more)
+}
+
+fn cache_table(rows: &[CacheRow]) -> Markup {
+    html! {
+        table {
+            tr {
+                th { "Key" }
+                th { "Host" }
+                th { "Status" }
+                th { "TTL Seconds" }
+                th { "Size" }
+                th { "Hits" }
+            }
+            @for row in rows {
+                tr {
+                    td { (entry_link(&row.key)) }
+                    @match &row.details {
+                        Some(Ok(details)) => {
+                            td { (details.host) }
+                            td { (details.status) }
+                            td { (details.ttl.as_secs()) }
+                        }
+                        Some(Err(e)) => {
+                            td colspan="3" { strong { "Unreadable: " } (e) }
+                        }
+                        None => {
+                            td colspan="3" {}
+                        }
+                    }
+                    td { (row.size) }
+                    td { (row.hits) }
+                }
+            }
+        }
+    }
+}
+
+/// Manifest pages, with the nodes that have each one cached
+fn pages_table(pages: &[(Page, Vec<String>)]) -> Markup {
+    html! {
+        table {
+            tr {
+                th { "Method" }
+                th { "URL" }
+                th { "Tags" }
+                th { "Cached on" }
+            }
+            @for (page, locations) in pages {
+                tr {
+                    td { (page.method) }
+                    td { code { (page.url) } }
+                    td { (page.tags.join(" ")) }
+                    td { (locations.join(", ")) }
+                }
+            }
+        }
+    }
+}
 
 pub(crate) async fn route(
-    State(db_pool): State<SqlitePool>,
-    _: DBSession,
-) -> Result<impl IntoResponse, String> {
+    State(state): State<AppState>,
+    session: DBSession,
+    Query(query): Query<ListQuery>,
+) -> Result<impl IntoResponse, WrappedError> {
     let file_system_entries: Result<Vec<Metadata>, _> =
         tokio::task::spawn_blocking(move || cacache::list_sync(CACHE_DIR).collect())
             .await
-            .into_diagnostic()
-            .map_err(|e| e.to_string())?;
+            .into_diagnostic()?;
     let file_system_entries = file_system_entries.unwrap_or_default();
 
-    let db_pages = sqlx::query!("SELECT * FROM Pages")
-        .fetch_all(&db_pool)
-        .await
-        .into_diagnostic()
-        .map_err(|e| e.to_string())?;
+    let db_pages = state.manifest.list_pages().await?;
 
-    let db_pages = db_pages
-        .into_iter()
-        .map(|page| format!("{} {}", page.method, page.url))
-        .collect::<Vec<_>>();
+    let cached_keys = file_system_entries
+        .iter()
+        .map(|entry| entry.key.clone())
+        .collect::<HashSet<_>>();
+    let manifest_keys = db_pages
+        .iter()
+        .map(|page| cache_key(&page.method, &page.url))
+        .collect::<HashSet<_>>();
+
+    let (cache_rows, pages, has_more) = match query.view {
+        View::Cache | View::Unmanifested => {
+            let now = SystemTime::now();
+            let mut rows = file_system_entries
+                .into_iter()
+                .filter(|entry| query.view == View::Cache || !manifest_keys.contains(&entry.key))
+                .map(|entry| CacheRow {
+                    hits: state.stats.hits(&entry.key),
+                    key: entry.key,
+                    size: entry.size,
+                    details: None,
+                })
+                .collect::<Vec<_>>();
+
+            // Browsing by key, size or hits only needs the index, so we only read the page we show
+            if query.needs_details() {
+                load_details(&mut rows, now).await;
+            }
+            let (mut rows, has_more) = paginate(filter_and_sort(rows, &query), query.page);
+            load_details(&mut rows, now).await;
+
+            (rows, vec![], has_more)
+        }
+        View::Manifest | View::Missing => {
+            let pages = db_pages
+                .into_iter()
+                .filter(|page| {
+                    query.view == View::Manifest
+                        || !cached_keys.contains(&cache_key(&page.method, &page.url))
+                })
+                .filter(|page| query.matches_search(&[page.url.as_str()]))
+                .collect::<Vec<_>>();
+
+            let (pages, has_more) = paginate(pages, query.page);
+            let mut rows = Vec::with_capacity(pages.len());
+            for page in pages {
+                let locations = state.manifest.locations(&page.method, &page.url).await?;
+                rows.push((page, locations));
+            }
+
+            (vec![], rows, has_more)
+        }
+    };
+
+    let missing = manifest_keys.difference(&cached_keys).count();
+    let unmanifested = cached_keys.difference(&manifest_keys).count();
 
     let resp = html! {
-        h2 { "Actions" }
-        form method="post" action="/_caje/clear_db" {
-            input type="submit" value="Clear DB";
+        p {
+            a href="/_caje/stats" { "Stats" }
+            " " a href="/_caje/cluster" { "Cluster" }
+            " " a href="/_caje/passkeys" { "Passkeys" }
+            " " a href="/_caje/purges" { "Purges" }
+            " " a href="/_caje/commands" { "Commands" }
+            " " a href="/_caje/warm" { "Warm" }
+            " " a href="/_caje/sessions" { "Sessions" }
+            " " a href="/_caje/tokens" { "API Tokens" }
+            @if session.role >= Role::Owner {
+                " " a href="/_caje/users" { "Users" }
+                " " a href="/_caje/audit" { "Audit Log" }
+            }
         }
-        form method="post" action="/_caje/clear_fs" {
-            input type="submit" value="Clear FS";
+        form method="post" action="/_caje/logout" {
+            (session.csrf_input())
+            input type="submit" value="Logout";
         }
-        form method="post" action="/_caje/populate" {
-            input type="submit" value="Populate Cache";
+
+        @if session.role >= Role::Operator {
+            h2 { "Actions" }
+            @if session.role >= Role::Owner {
+                form method="post" action="/_caje/clear_db" {
+                    (session.csrf_input())
+                    input type="submit" value="Clear DB";
+                }
+            }
+            form method="post" action="/_caje/clear_fs" {
+                (session.csrf_input())
+                input type="submit" value="Clear FS";
+            }
+            form method="post" action="/_caje/populate" {
+                (session.csrf_input())
+                input type="submit" value="Populate Cache";
+            }
         }
 
-        h2 { "File System" }
-        ul {
-            @for entry in file_system_entries {
-                li { (entry.key) " TTL Seconds: " (get_policy_from_cache(&entry.key).await.unwrap().0.time_to_live(SystemTime::now()).as_secs()) }
+        h2 { "Cache" }
+        p {
+            (cached_keys.len()) " cached on this node, "
+            (manifest_keys.len()) " in the manifest, "
+            (missing) " missing from this node, "
+            (unmanifested) " not in the manifest"
+        }
+        p {
+            @for (i, view) in View::ALL.iter().enumerate() {
+                @if i > 0 { " | " }
+                @if *view == query.view {
+                    strong { (view.label()) }
+                } @else {
+                    a href=(query.with_view(*view).href()) { (view.label()) }
+                }
             }
         }
+        form method="get" action="/_caje/list" {
+            input type="hidden" name="view" value=(query.view.as_str());
+            input type="text" name="q" placeholder="URL or host" value=(query.q);
+            input type="text" name="status" placeholder="Status" value=(query.status);
+            select name="sort" {
+                @for sort in Sort::ALL {
+                    option value=(sort.as_str()) selected[sort == query.sort] { "Sort by " (sort.as_str()) }
+                }
+            }
+            input type="submit" value="Search";
+        }
+
+        @match query.view {
+            View::Cache | View::Unmanifested => { (cache_table(&cache_rows)) }
+            View::Manifest | View::Missing => { (pages_table(&pages)) }
+        }
 
-        h2 { "Database" }
-        ul {
-            @for entry in db_pages {
-                li { (entry) }
+        p {
+            @if query.page > 0 {
+                a href=(query.with_page(query.page - 1).href()) { "Previous" }
+                " "
+            }
+            "Page " (query.page.saturating_add(1))
+            @if has_more {
+                " "
+                a href=(query.with_page(query.page.saturating_add(1)).href()) { "Next" }
             }
         }
     };
 
     Ok((StatusCode::OK, resp))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(key: &str, size: usize, details: Result<EntryDetails, String>) -> CacheRow {
+        CacheRow {
+            key: key.to_string(),
+            size,
+            hits: 0,
+            details: Some(details),
+        }
+    }
+
+    fn details(status: u16, ttl: u64) -> Result<EntryDetails, String> {
+        Ok(EntryDetails {
+            host: "slow.coreyja.com".to_string(),
+            status,
+            ttl: Duration::from_secs(ttl),
+        })
+    }
+
+    #[test]
+    fn unreadable_entries_are_kept_and_sorted_last() {
+        let rows = vec![
+            row("GET@/broken", 10, Err("Could not read".to_string())),
+            row("GET@/slow", 20, details(200, 60)),
+            row("GET@/fast", 30, details(200, 5)),
+        ];
+        let query = ListQuery {
+            sort: Sort::Ttl,
+            ..Default::default()
+        };
+
+        let keys = filter_and_sort(rows, &query)
+            .into_iter()
+            .map(|row| row.key)
+            .collect::<Vec<_>>();
+        assert_eq!(keys, vec!["GET@/fast", "GET@/slow", "GET@/broken"]);
+    }
+
+    #[test]
+    fn searches_by_url_host_and_status() {
+        let rows = vec![
+            row("GET@/products/1", 10, details(200, 60)),
+            row("GET@/products/2", 10, details(404, 60)),
+            row("GET@/about", 10, details(200, 60)),
+        ];
+
+        let query = ListQuery {
+            q: "products".to_string(),
+            status: "200".to_string(),
+            ..Default::default()
+        };
+        let found = filter_and_sort(rows.clone(), &query);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].key, "GET@/products/1");
+
+        let query = ListQuery {
+            q: "coreyja".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(filter_and_sort(rows, &query).len(), 3);
+        assert!(query.needs_details());
+        assert!(!ListQuery::default().needs_details());
+    }
+
+    #[test]
+    fn paginates() {
+        let (page, has_more) = paginate((0..120).collect::<Vec<_>>(), 1);
+        assert_eq!(page.len(), PAGE_SIZE);
+        assert_eq!(page[0], PAGE_SIZE);
+        assert!(has_more);
+
+        let (page, has_more) = paginate((0..120).collect::<Vec<_>>(), 2);
+        assert_eq!(page.len(), 20);
+        assert!(!has_more);
+
+        let (page, has_more) = paginate((0..120).collect::<Vec<_>>(), usize::MAX);
+        assert!(page.is_empty());
+        assert!(!has_more);
+    }
+}
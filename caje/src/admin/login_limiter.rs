@@ -0,0 +1,207 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Failed logins from one IP before it has to start waiting between attempts
+const PER_IP_FREE_ATTEMPTS: u32 = 5;
+
+/// Failed logins across every IP before everyone has to start waiting, so spreading a guessing
+/// attack over lots of addresses doesn't get around the per IP limit
+const GLOBAL_FREE_ATTEMPTS: u32 = 50;
+
+/// How long to wait after the first failure past the free attempts, doubling with each failure
+const BASE_BACKOFF: Duration = Duration::from_secs(1);
+
+const MAX_BACKOFF: Duration = Duration::from_secs(15 * 60);
+
+/// Failures are forgotten once there haven't been any for this long
+const FORGET_AFTER: Duration = Duration::from_secs(60 * 60);
+
+/// Past this many IPs, the one that failed longest ago is forgotten to make room
+///
+/// Spreading guesses over more IPs than this still runs into the global limit
+const MAX_TRACKED_IPS: usize = 10_000;
+
+/// Whole seconds for `Retry-After`, rounded up so nobody comes back before they're allowed
+pub(crate) fn retry_after_secs(wait: Duration) -> u64 {
+    wait.as_secs() + u64::from(wait.subsec_nanos() > 0)
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Failures {
+    count: u32,
+    last: Option<Instant>,
+}
+
+impl Failures {
+    fn is_stale(&self, now: Instant) -> bool {
+        match self.last {
+            Some(last) => now.saturating_duration_since(last) >= FORGET_AFTER,
+            None => true,
+        }
+    }
+
+    fn record(&mut self, now: Instant) {
+        if self.is_stale(now) {
+            self.count = 0;
+        }
+
+        self.count += 1;
+        self.last = Some(now);
+    }
+
+    /// How much longer until another attempt is allowed, if we're backing off
+    fn retry_after(&self, free_attempts: u32, now: Instant) -> Option<Duration> {
+        let last = self.last?;
+        if self.is_stale(now) || self.count < free_attempts {
+            return None;
+        }
+
+        let doublings = (self.count - free_attempts).min(16);
+        let backoff = (BASE_BACKOFF * 2u32.pow(doublings)).min(MAX_BACKOFF);
+
+        (last + backoff)
+            .checked_duration_since(now)
+            .filter(|wait| !wait.is_zero())
+    }
+}
+
+#[derive(Debug, Default)]
+struct Attempts {
+    per_ip: HashMap<Option<IpAddr>, Failures>,
+    global: Failures,
+}
+
+/// Tracks failed admin logins on this node, and makes guessers wait longer and longer
+#[derive(Debug, Default)]
+pub(crate) struct LoginLimiter {
+    attempts: Mutex<Attempts>,
+}
+
+impl LoginLimiter {
+    /// `Err` with how long they need to wait if `ip` isn't allowed to try logging in yet
+    pub(crate) fn check(&self, ip: Option<IpAddr>, now: Instant) -> Result<(), Duration> {
+        let attempts = self.attempts.lock().unwrap();
+
+        let per_ip = attempts
+            .per_ip
+            .get(&ip)
+            .and_then(|failures| failures.retry_after(PER_IP_FREE_ATTEMPTS, now));
+        let global = attempts.global.retry_after(GLOBAL_FREE_ATTEMPTS, now);
+
+        match per_ip.max(global) {
+            Some(wait) => Err(wait),
+            None => Ok(()),
+        }
+    }
+
+    pub(crate) fn record_failure(&self, ip: Option<IpAddr>, now: Instant) {
+        let mut attempts = self.attempts.lock().unwrap();
+
+        attempts
+            .per_ip
+            .retain(|_, failures| !failures.is_stale(now));
+        if attempts.per_ip.len() >= MAX_TRACKED_IPS && !attempts.per_ip.contains_key(&ip) {
+            let oldest = attempts
+                .per_ip
+                .iter()
+                .min_by_key(|(_, failures)| failures.last)
+                .map(|(ip, _)| *ip);
+            if let Some(oldest) = oldest {
+                attempts.per_ip.remove(&oldest);
+            }
+        }
+        attempts.per_ip.entry(ip).or_default().record(now);
+        attempts.global.record(now);
+    }
+
+    /// A successful login clears the failures for that IP, but not the global ones
+    pub(crate) fn record_success(&self, ip: Option<IpAddr>) {
+        self.attempts.lock().unwrap().per_ip.remove(&ip);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IP: Option<IpAddr> = Some(IpAddr::V4(std::net::Ipv4Addr::new(10, 0, 0, 1)));
+    const OTHER_IP: Option<IpAddr> = Some(IpAddr::V4(std::net::Ipv4Addr::new(10, 0, 0, 2)));
+
+    #[test]
+    fn backs_off_exponentially_after_the_free_attempts() {
+        let limiter = LoginLimiter::default();
+        let now = Instant::now();
+
+        for _ in 0..PER_IP_FREE_ATTEMPTS - 1 {
+            limiter.record_failure(IP, now);
+            assert_eq!(limiter.check(IP, now), Ok(()));
+        }
+
+        limiter.record_failure(IP, now);
+        assert_eq!(limiter.check(IP, now), Err(BASE_BACKOFF));
+        assert_eq!(limiter.check(OTHER_IP, now), Ok(()));
+
+        limiter.record_failure(IP, now);
+        assert_eq!(limiter.check(IP, now), Err(BASE_BACKOFF * 2));
+        assert_eq!(limiter.check(IP, now + BASE_BACKOFF * 2), Ok(()));
+    }
+
+    #[test]
+    fn success_clears_the_ip() {
+        let limiter = LoginLimiter::default();
+        let now = Instant::now();
+
+        for _ in 0..PER_IP_FREE_ATTEMPTS {
+            limiter.record_failure(IP, now);
+        }
+        assert!(limiter.check(IP, now).is_err());
+
+        limiter.record_success(IP);
+        assert_eq!(limiter.check(IP, now), Ok(()));
+    }
+
+    #[test]
+    fn failures_from_everywhere_add_up() {
+        let limiter = LoginLimiter::default();
+        let now = Instant::now();
+
+        for i in 0..GLOBAL_FREE_ATTEMPTS {
+            let ip = IpAddr::V4(std::net::Ipv4Addr::from(i));
+            limiter.record_failure(Some(ip), now);
+        }
+
+        assert_eq!(limiter.check(OTHER_IP, now), Err(BASE_BACKOFF));
+    }
+
+    #[test]
+    fn failures_are_forgotten() {
+        let limiter = LoginLimiter::default();
+        let now = Instant::now();
+
+        for _ in 0..PER_IP_FREE_ATTEMPTS * 2 {
+            limiter.record_failure(IP, now);
+        }
+
+        assert_eq!(limiter.check(IP, now + FORGET_AFTER), Ok(()));
+    }
+
+    #[test]
+    fn tracks_a_limited_number_of_ips() {
+        let limiter = LoginLimiter::default();
+        let now = Instant::now();
+
+        limiter.record_failure(IP, now);
+        for i in 0..MAX_TRACKED_IPS as u32 {
+            let ip = IpAddr::V4(std::net::Ipv4Addr::from(i));
+            limiter.record_failure(Some(ip), now + Duration::from_millis(1));
+        }
+
+        let attempts = limiter.attempts.lock().unwrap();
+        assert_eq!(attempts.per_ip.len(), MAX_TRACKED_IPS);
+        assert!(!attempts.per_ip.contains_key(&IP));
+    }
+}
@@ -0,0 +1,480 @@
+use std::time::{Duration, Instant};
+
+use axum::{
+    extract::State,
+    response::{IntoResponse, Redirect, Response},
+    Json,
+};
+use chrono::{NaiveDateTime, Utc};
+use http::{header::RETRY_AFTER, StatusCode};
+use maud::{html, PreEscaped};
+use miette::{miette, IntoDiagnostic};
+use serde::Deserialize;
+use serde_json::json;
+use sqlx::query_as;
+use tower_cookies::{Cookie, Cookies};
+use tracing::warn;
+
+use crate::{
+    audit::{self, Actor},
+    client_ip::ClientIp,
+    webauthn::{
+        new_challenge, AuthenticationResponse, RegistrationResponse, RelyingParty, StoredCredential,
+    },
+    AppState, WrappedError,
+};
+
+use super::{auth::DBSession, csrf::CsrfForm, login_limiter::retry_after_secs, users::AdminUser};
+
+const CHALLENGE_COOKIE: &str = "webauthn_challenge";
+
+/// Browser side of the ceremonies, shared by the login and passkey management pages
+pub(crate) const PASSKEY_JS: &str = r#"
+const cajeFromB64 = (s) =>
+  Uint8Array.from(atob(s.replace(/-/g, "+").replace(/_/g, "/")), (c) => c.charCodeAt(0));
+const cajeToB64 = (buf) =>
+  btoa(String.fromCharCode(...new Uint8Array(buf)))
+    .replace(/\+/g, "-")
+    .replace(/\//g, "_")
+    .replace(/=+$/, "");
+
+async function cajePostJson(url, body) {
+  const res = await fetch(url, {
+    method: "POST",
+    headers: { "Content-Type": "application/json" },
+    body: JSON.stringify(body ?? {}),
+  });
+  if (!res.ok) {
+    throw new Error(`${url} failed with ${res.status}`);
+  }
+  return res;
+}
+
+function cajePasskeyError(e) {
+  document.getElementById("passkey-error").textContent = e.message;
+}
+
+async function cajePasskeyLogin() {
+  const options = await (await cajePostJson("/_caje/auth/passkey/start")).json();
+  options.challenge = cajeFromB64(options.challenge);
+
+  const credential = await navigator.credentials.get({ publicKey: options });
+  await cajePostJson("/_caje/auth/passkey/finish", {
+    id: credential.id,
+    client_data_json: cajeToB64(credential.response.clientDataJSON),
+    authenticator_data: cajeToB64(credential.response.authenticatorData),
+    signature: cajeToB64(credential.response.signature),
+  });
+
+  window.location = "/_caje/list";
+}
+
+async function cajePasskeyRegister() {
+  const name = document.getElementById("passkey-name").value;
+  const options = await (await cajePostJson("/_caje/passkeys/register/start")).json();
+  options.challenge = cajeFromB64(options.challenge);
+  options.user.id = cajeFromB64(options.user.id);
+  options.excludeCredentials.forEach((c) => (c.id = cajeFromB64(c.id)));
+
+  const credential = await navigator.credentials.create({ publicKey: options });
+  await cajePostJson("/_caje/passkeys/register/finish", {
+    name,
+    credential: {
+      id: credential.id,
+      client_data_json: cajeToB64(credential.response.clientDataJSON),
+      attestation_object: cajeToB64(credential.response.attestationObject),
+    },
+  });
+
+  window.location.reload();
+}
+"#;
+
+#[derive(Debug)]
+struct Passkey {
+    id: i64,
+    admin_user_id: i64,
+    name: String,
+    public_key: Vec<u8>,
+    algorithm: i64,
+    sign_count: i64,
+    created_at: NaiveDateTime,
+    last_used_at: Option<NaiveDateTime>,
+}
+
+fn relying_party(state: &AppState) -> miette::Result<&RelyingParty> {
+    state
+        .relying_party
+        .as_ref()
+        .ok_or_else(|| miette!("Passkeys are not configured, set WEBAUTHN_RP_ID to enable them"))
+}
+
+/// How long someone has to finish a passkey ceremony once they've started it
+const CHALLENGE_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// Challenges are kept until they're answered or expire, so each one can only be used once, and
+/// a private cookie ties the ceremony to the browser that started it
+async fn store_challenge(
+    state: &AppState,
+    cookies: &Cookies,
+    challenge: &str,
+) -> miette::Result<()> {
+    let now = Utc::now().naive_utc();
+    let expired = now - chrono::Duration::from_std(CHALLENGE_TTL).into_diagnostic()?;
+
+    state
+        .db_writer
+        .write(|| async {
+            // Tidy up ceremonies nobody finished while we're here
+            sqlx::query!(
+                "DELETE FROM PasskeyChallenges WHERE created_at <= ?",
+                expired
+            )
+            .execute(&state.db_pool)
+            .await
+            .into_diagnostic()?;
+
+            sqlx::query!(
+                "INSERT INTO PasskeyChallenges (challenge, created_at) VALUES (?, ?)",
+                challenge,
+                now
+            )
+            .execute(&state.db_pool)
+            .await
+            .into_diagnostic()
+        })
+        .await?;
+
+    let cookie = Cookie::build(CHALLENGE_COOKIE, challenge.to_string())
+        .path("/_caje")
+        .http_only(true)
+        .secure(true)
+        .finish();
+
+    cookies.private(&state.cookie_key.0).add(cookie);
+
+    Ok(())
+}
+
+async fn take_challenge(state: &AppState, cookies: &Cookies) -> miette::Result<String> {
+    let private = cookies.private(&state.cookie_key.0);
+    let cookie = private
+        .get(CHALLENGE_COOKIE)
+        .ok_or_else(|| miette!("No passkey ceremony in progress"))?;
+    let challenge = cookie.value().to_string();
+
+    private.remove(Cookie::build(CHALLENGE_COOKIE, "").path("/_caje").finish());
+
+    let expired =
+        Utc::now().naive_utc() - chrono::Duration::from_std(CHALLENGE_TTL).into_diagnostic()?;
+    let created_at = state
+        .db_writer
+        .write(|| async {
+            sqlx::query_scalar!(
+                r#"DELETE FROM PasskeyChallenges WHERE challenge = ?
+                RETURNING created_at as "created_at!: NaiveDateTime""#,
+                challenge
+            )
+            .fetch_optional(&state.db_pool)
+            .await
+            .into_diagnostic()
+        })
+        .await?;
+
+    match created_at {
+        Some(created_at) if created_at > expired => Ok(challenge),
+        Some(_) => Err(miette!("Passkey ceremony took too long, start again")),
+        None => Err(miette!("Passkey challenge was already used")),
+    }
+}
+
+pub(crate) async fn index(
+    State(state): State<AppState>,
+    session: DBSession,
+) -> Result<impl IntoResponse, WrappedError> {
+    let user = AdminUser::fetch(&state.db_pool, session.admin_user_id)
+        .await?
+        .ok_or_else(|| miette!("Logged in user no longer exists"))?;
+
+    let passkeys = query_as!(
+        Passkey,
+        "SELECT id, admin_user_id, name, public_key, algorithm, sign_count, created_at, last_used_at
+        FROM Passkeys
+        WHERE admin_user_id = ?",
+        user.id
+    )
+    .fetch_all(&state.db_pool)
+    .await
+    .into_diagnostic()?;
+
+    let resp = html! {
+        h2 { "Passkeys for " (user.username) }
+        ul {
+            @for passkey in passkeys {
+                li {
+                    (passkey.name) " Added: " (passkey.created_at)
+                    " Last Used: " (passkey.last_used_at.map(|t| t.to_string()).unwrap_or_else(|| "Never".to_string()))
+
+                    form method="post" action="/_caje/passkeys/delete" {
+                        (session.csrf_input())
+                        input type="hidden" name="id" value=(passkey.id);
+                        input type="submit" value="Remove";
+                    }
+                }
+            }
+        }
+
+        @if state.relying_party.is_some() {
+            h2 { "Add a Passkey" }
+            input type="text" id="passkey-name" placeholder="Name";
+            button type="button" onclick="cajePasskeyRegister().catch(cajePasskeyError)" { "Register Passkey" }
+            p id="passkey-error" {}
+            script { (PreEscaped(PASSKEY_JS)) }
+        } @else {
+            p { "Set WEBAUTHN_RP_ID to enable passkeys" }
+        }
+    };
+
+    Ok((StatusCode::OK, resp))
+}
+
+pub(crate) async fn register_start(
+    State(state): State<AppState>,
+    cookies: Cookies,
+    session: DBSession,
+) -> Result<impl IntoResponse, WrappedError> {
+    let relying_party = relying_party(&state)?;
+    let user = AdminUser::fetch(&state.db_pool, session.admin_user_id)
+        .await?
+        .ok_or_else(|| miette!("Logged in user no longer exists"))?;
+
+    let existing_credential_ids = sqlx::query_scalar!(
+        "SELECT credential_id FROM Passkeys WHERE admin_user_id = ?",
+        user.id
+    )
+    .fetch_all(&state.db_pool)
+    .await
+    .into_diagnostic()?;
+
+    let challenge = new_challenge()?;
+    store_challenge(&state, &cookies, &challenge).await?;
+
+    Ok(Json(relying_party.creation_options(
+        &challenge,
+        user.id,
+        &user.username,
+        &existing_credential_ids,
+    )))
+}
+
+#[derive(Deserialize)]
+pub(crate) struct RegisterFinish {
+    name: String,
+    credential: RegistrationResponse,
+}
+
+pub(crate) async fn register_finish(
+    State(state): State<AppState>,
+    cookies: Cookies,
+    session: DBSession,
+    Json(body): Json<RegisterFinish>,
+) -> Result<impl IntoResponse, WrappedError> {
+    let relying_party = relying_party(&state)?;
+    let challenge = take_challenge(&state, &cookies).await?;
+
+    let verified = relying_party.verify_registration(&challenge, &body.credential)?;
+
+    let name = match body.name.trim() {
+        "" => "Passkey",
+        name => name,
+    };
+    let sign_count = i64::from(verified.sign_count);
+    state
+        .db_writer
+        .write(|| async {
+            sqlx::query!(
+                "INSERT INTO Passkeys (admin_user_id, name, credential_id, public_key, algorithm, sign_count)
+                VALUES (?, ?, ?, ?, ?, ?)",
+                session.admin_user_id,
+                name,
+                verified.credential_id,
+                verified.public_key,
+                verified.algorithm,
+                sign_count,
+            )
+            .execute(&state.db_pool)
+            .await
+            .into_diagnostic()
+        })
+        .await?;
+
+    audit::record(
+        &state,
+        &session.actor(),
+        "passkey_added",
+        json!({ "name": name }),
+    )
+    .await?;
+
+    Ok(StatusCode::CREATED)
+}
+
+#[derive(Deserialize)]
+pub(crate) struct DeleteForm {
+    id: i64,
+}
+
+pub(crate) async fn delete(
+    State(state): State<AppState>,
+    CsrfForm { session, form }: CsrfForm<DeleteForm>,
+) -> Result<impl IntoResponse, WrappedError> {
+    state
+        .db_writer
+        .write(|| async {
+            sqlx::query!(
+                "DELETE FROM Passkeys WHERE id = ? AND admin_user_id = ?",
+                form.id,
+                session.admin_user_id
+            )
+            .execute(&state.db_pool)
+            .await
+            .into_diagnostic()
+        })
+        .await?;
+
+    audit::record(
+        &state,
+        &session.actor(),
+        "passkey_removed",
+        json!({ "id": form.id }),
+    )
+    .await?;
+
+    Ok(Redirect::to("/_caje/passkeys"))
+}
+
+pub(crate) async fn login_start(
+    State(state): State<AppState>,
+    cookies: Cookies,
+) -> Result<impl IntoResponse, WrappedError> {
+    let relying_party = relying_party(&state)?;
+
+    let challenge = new_challenge()?;
+    store_challenge(&state, &cookies, &challenge).await?;
+
+    Ok(Json(relying_party.request_options(&challenge)))
+}
+
+pub(crate) async fn login_finish(
+    State(state): State<AppState>,
+    ClientIp(ip): ClientIp,
+    cookies: Cookies,
+    Json(response): Json<AuthenticationResponse>,
+) -> Result<Response, WrappedError> {
+    let now = Instant::now();
+    if let Err(wait) = state.login_limiter.check(ip, now) {
+        let seconds = retry_after_secs(wait);
+
+        return Ok((
+            StatusCode::TOO_MANY_REQUESTS,
+            [(RETRY_AFTER, seconds.to_string())],
+            format!("Too many failed logins, try again in {seconds} seconds"),
+        )
+            .into_response());
+    }
+
+    let relying_party = relying_party(&state)?;
+    let challenge = take_challenge(&state, &cookies).await?;
+
+    let passkey = query_as!(
+        Passkey,
+        "SELECT id, admin_user_id, name, public_key, algorithm, sign_count, created_at, last_used_at
+        FROM Passkeys
+        WHERE credential_id = ?",
+        response.id
+    )
+    .fetch_optional(&state.db_pool)
+    .await
+    .into_diagnostic()?;
+
+    let verified = passkey.as_ref().and_then(|passkey| {
+        let verified = u32::try_from(passkey.sign_count)
+            .into_diagnostic()
+            .and_then(|sign_count| {
+                relying_party.verify_authentication(
+                    &challenge,
+                    &response,
+                    &StoredCredential {
+                        public_key: &passkey.public_key,
+                        algorithm: passkey.algorithm,
+                        sign_count,
+                    },
+                )
+            });
+
+        match verified {
+            Ok(sign_count) => Some(sign_count),
+            Err(e) => {
+                warn!(passkey_id = passkey.id, error = ?e, "Passkey failed verification");
+                None
+            }
+        }
+    });
+
+    let (Some(passkey), Some(sign_count)) = (passkey.as_ref(), verified) else {
+        state.login_limiter.record_failure(ip, now);
+
+        let user = match &passkey {
+            Some(passkey) => AdminUser::fetch(&state.db_pool, passkey.admin_user_id).await?,
+            None => None,
+        };
+        let actor = match user {
+            Some(user) => user.actor(),
+            None => Actor {
+                admin_user_id: None,
+                name: "unknown passkey".to_string(),
+            },
+        };
+        audit::record(
+            &state,
+            &actor,
+            "login_failed",
+            json!({ "ip": ip, "method": "passkey" }),
+        )
+        .await?;
+
+        return Ok((StatusCode::UNAUTHORIZED, "Passkey login failed").into_response());
+    };
+
+    state.login_limiter.record_success(ip);
+
+    let sign_count = i64::from(sign_count);
+    state
+        .db_writer
+        .write(|| async {
+            sqlx::query!(
+                "UPDATE Passkeys SET sign_count = ?, last_used_at = CURRENT_TIMESTAMP WHERE id = ?",
+                sign_count,
+                passkey.id
+            )
+            .execute(&state.db_pool)
+            .await
+            .into_diagnostic()
+        })
+        .await?;
+
+    DBSession::start(&state, &cookies, passkey.admin_user_id).await?;
+
+    let user = AdminUser::fetch(&state.db_pool, passkey.admin_user_id)
+        .await?
+        .ok_or_else(|| miette!("Passkey belongs to a user that no longer exists"))?;
+    audit::record(
+        &state,
+        &user.actor(),
+        "login",
+        json!({ "ip": ip, "method": "passkey", "passkey": passkey.name }),
+    )
+    .await?;
+
+    Ok(StatusCode::NO_CONTENT.into_response())
+}
@@ -4,102 +4,178 @@ use axum::{
     extract::State,
     response::{IntoResponse, Redirect},
 };
-use http::{header::HOST, uri::PathAndQuery, Method, Request, Uri};
+use http::{header::HOST, uri::PathAndQuery, HeaderMap, Method, Request, Uri};
 use http_cache_semantics::CachePolicy;
-use hyper::body::Bytes;
-use miette::{Context, IntoDiagnostic};
-use sqlx::SqlitePool;
+use miette::IntoDiagnostic;
+use serde::Serialize;
 
 use crate::{
-    cache_key, get_policy_from_cache, http_response_from_parts, CachedResponse,
-    InnerCachedResponse, IntoInnerCachedRequest, IntoInnerCachedResponse, WrappedError, CACHE_DIR,
-    PROXY_FROM_DOMAIN, PROXY_ORIGIN_DOMAIN,
+    audit, cache_key, get_policy_from_cache, http_response_from_parts, origin_url,
+    tags::tags_from_headers, telemetry, write_to_cache, AppState, CachedResponse,
+    InnerCachedResponse, IntoInnerCachedRequest, IntoInnerCachedResponse, WrappedError,
+    MAX_POPULATE_LAG, PROXY_FROM_DOMAIN,
 };
 
-use super::auth::DBSession;
+use super::{
+    csrf::{CsrfForm, NoFields},
+    roles::Operator,
+};
 
 pub(crate) async fn route(
-    State(db_pool): State<SqlitePool>,
-    _: DBSession,
+    State(app_state): State<AppState>,
+    CsrfForm { session, .. }: CsrfForm<NoFields, Operator>,
 ) -> Result<impl IntoResponse, WrappedError> {
-    let db_pages = sqlx::query!("SELECT * FROM Pages")
-        .fetch_all(&db_pool)
-        .await
-        .into_diagnostic()?;
+    let report = populate(&app_state).await?;
+    audit::record(
+        &app_state,
+        &session.actor(),
+        "populate",
+        serde_json::to_value(&report).into_diagnostic()?,
+    )
+    .await?;
+
+    Ok(Redirect::to("/_caje/list"))
+}
+
+/// What a populate run did with each page in the manifest
+#[derive(Debug, Default, Serialize)]
+pub(crate) struct PopulateReport {
+    /// Pages fetched from the origin and written to the cache
+    pub populated: usize,
+    /// Pages that were already fresh in the cache
+    pub fresh: usize,
+    /// Pages the origin says we aren't allowed to cache right now
+    pub uncacheable: usize,
+}
+
+/// Fetch every page in the manifest that isn't already fresh in our local cache
+pub(crate) async fn populate(app_state: &AppState) -> miette::Result<PopulateReport> {
+    if let Some(lag_watcher) = &app_state.lag_watcher {
+        match lag_watcher.current() {
+            Some(lag) if lag <= MAX_POPULATE_LAG => {}
+            Some(lag) => Err(miette::miette!(
+                "Refusing to populate, this replica is {lag:?} behind the primary"
+            ))?,
+            None => Err(miette::miette!(
+                "Refusing to populate, could not determine replication lag"
+            ))?,
+        }
+    }
+
+    let db_pages = app_state.manifest.list_pages().await?;
     let now = SystemTime::now();
+    let mut report = PopulateReport::default();
 
     for page in db_pages {
         let cache_key = cache_key(&page.method, &page.url);
         let policy = get_policy_from_cache(&cache_key).await;
 
         if policy.is_ok_and(|(p, _)| !p.time_to_live(now).is_zero()) {
+            report.fresh += 1;
             continue;
         }
 
-        let path = page
-            .url
-            .parse::<Uri>()
-            .into_diagnostic()?
-            .path_and_query()
-            .cloned()
-            .unwrap_or_else(|| PathAndQuery::from_static("/"));
-
-        let proxy_url = http::Uri::builder()
-            .scheme("https")
-            .authority(PROXY_ORIGIN_DOMAIN)
-            .path_and_query(path.clone())
-            .build()
-            .map_err(|_| miette::miette!("Could not build url"))?;
-
-        let client = reqwest::Client::new();
-        let method: Method = page.method.clone().parse().into_diagnostic()?;
-        let origin_response = client
-            .request(method.clone(), proxy_url.to_string())
-            .send()
-            .await
-            .map_err(|_| miette::miette!("Request failed"))?;
-
-        let origin_status = origin_response.status();
-        let origin_headers = origin_response.headers().clone();
-        let origin_version = origin_response.version();
-        let origin_bytes = origin_response
-            .bytes()
-            .await
-            .map_err(|_| miette::miette!("Could not get bytes from body"))?;
-
-        let parts = InnerCachedResponse {
-            status_code: origin_status,
-            headers: origin_headers.clone(),
-            body: origin_bytes.into(),
-            version: origin_version,
-        };
-        let response_to_cache = http_response_from_parts(parts.clone())
-            .map_err(|_| miette::miette!("Could not build response"))?;
-        let request_to_cache: Request<()> = Request::builder()
-            .method(method)
-            .uri(path)
-            .header(HOST, PROXY_FROM_DOMAIN)
-            .body(())
-            .into_diagnostic()?;
-
-        let policy = CachePolicy::new(&request_to_cache, &response_to_cache);
-
-        if policy.is_storable() && !policy.time_to_live(SystemTime::now()).is_zero() {
-            let response_to_cache = CachedResponse {
-                request: request_to_cache.into_inner_cached_request()?,
-                response: response_to_cache.into_inner_cached_response()?,
-                cached_at: SystemTime::now(),
-            };
-
-            cacache::write(
-                CACHE_DIR,
-                cache_key,
-                postcard::to_allocvec(&response_to_cache).into_diagnostic()?,
-            )
-            .await
-            .context("Could not write to cache")?;
+        if fetch_and_cache(app_state, &page.method, &page.url).await? {
+            report.populated += 1;
+        } else {
+            report.uncacheable += 1;
         }
     }
 
-    Ok(Redirect::to("/_caje/list"))
+    Ok(report)
+}
+
+/// Fetch one page from the origin and cache it on this node, whether or not it is already cached
+///
+/// Returns `false` if the origin says we aren't allowed to cache it right now
+pub(crate) async fn fetch_and_cache(
+    app_state: &AppState,
+    method: &str,
+    url: &str,
+) -> miette::Result<bool> {
+    let response = fetch_from_origin(method, url).await?;
+
+    cache_response(app_state, method, url, response).await
+}
+
+fn path_and_query(url: &str) -> miette::Result<PathAndQuery> {
+    Ok(url
+        .parse::<Uri>()
+        .into_diagnostic()?
+        .path_and_query()
+        .cloned()
+        .unwrap_or_else(|| PathAndQuery::from_static("/")))
+}
+
+/// Fetch one page from the origin, without caching it
+#[tracing::instrument(name = "origin_fetch", skip_all, fields(url = %url))]
+pub(crate) async fn fetch_from_origin(
+    method: &str,
+    url: &str,
+) -> miette::Result<InnerCachedResponse> {
+    let proxy_url = origin_url(path_and_query(url)?)?;
+
+    let client = reqwest::Client::new();
+    let parsed_method: Method = method.parse().into_diagnostic()?;
+    let mut headers = HeaderMap::new();
+    telemetry::propagate_trace(&tracing::Span::current(), &mut headers);
+    let origin_response = client
+        .request(parsed_method, proxy_url.to_string())
+        .headers(headers)
+        .send()
+        .await
+        .map_err(|_| miette::miette!("Request failed"))?;
+
+    let origin_status = origin_response.status();
+    let origin_headers = origin_response.headers().clone();
+    let origin_version = origin_response.version();
+    let origin_bytes = origin_response
+        .bytes()
+        .await
+        .map_err(|_| miette::miette!("Could not get bytes from body"))?;
+
+    Ok(InnerCachedResponse {
+        status_code: origin_status,
+        headers: origin_headers,
+        body: origin_bytes.into(),
+        version: origin_version,
+    })
+}
+
+/// Cache a response we fetched from the origin on this node, and add it to the manifest
+///
+/// Returns `false` if the origin says we aren't allowed to cache it right now
+pub(crate) async fn cache_response(
+    app_state: &AppState,
+    method: &str,
+    url: &str,
+    parts: InnerCachedResponse,
+) -> miette::Result<bool> {
+    let origin_headers = parts.headers.clone();
+    let response_to_cache =
+        http_response_from_parts(parts).map_err(|_| miette::miette!("Could not build response"))?;
+    let request_to_cache: Request<()> = Request::builder()
+        .method(method.parse::<Method>().into_diagnostic()?)
+        .uri(path_and_query(url)?)
+        .header(HOST, PROXY_FROM_DOMAIN)
+        .body(())
+        .into_diagnostic()?;
+
+    let policy = CachePolicy::new(&request_to_cache, &response_to_cache);
+
+    if !policy.is_storable() || policy.time_to_live(SystemTime::now()).is_zero() {
+        return Ok(false);
+    }
+
+    let response_to_cache = CachedResponse {
+        request: request_to_cache.into_inner_cached_request()?,
+        response: response_to_cache.into_inner_cached_response()?,
+        cached_at: SystemTime::now(),
+    };
+
+    let tags = tags_from_headers(&origin_headers);
+    write_to_cache(cache_key(method, url), &response_to_cache, &tags).await?;
+    app_state.manifest.record_page(method, url, &tags).await?;
+
+    Ok(true)
 }
@@ -0,0 +1,165 @@
+use std::collections::HashMap;
+
+use axum::{
+    extract::State,
+    response::{IntoResponse, Redirect},
+};
+use chrono::NaiveDateTime;
+use http::StatusCode;
+use maud::html;
+use miette::IntoDiagnostic;
+use serde::Deserialize;
+use sqlx::query_as;
+
+use crate::{
+    purge::{self, Purge, PurgeKind},
+    AppState, WrappedError,
+};
+
+use super::{
+    auth::DBSession,
+    csrf::CsrfForm,
+    roles::{Operator, Role},
+};
+
+/// How many of the most recent purges to show
+const RECENT_PURGES: i64 = 50;
+
+struct Completion {
+    purge_id: i64,
+    node: String,
+    removed: i64,
+    completed_at: NaiveDateTime,
+}
+
+pub(crate) async fn index(
+    State(state): State<AppState>,
+    session: DBSession,
+) -> Result<impl IntoResponse, WrappedError> {
+    let purges = query_as!(
+        Purge,
+        r#"SELECT id, kind as "kind: PurgeKind", pattern, requested_by, created_at, soft
+        FROM Purges
+        ORDER BY id DESC
+        LIMIT ?"#,
+        RECENT_PURGES
+    )
+    .fetch_all(&state.db_pool)
+    .await
+    .into_diagnostic()?;
+
+    let completions = query_as!(
+        Completion,
+        "SELECT purge_id, node, removed, completed_at
+        FROM PurgeCompletions
+        WHERE purge_id IN (SELECT id FROM Purges ORDER BY id DESC LIMIT ?)
+        ORDER BY completed_at",
+        RECENT_PURGES
+    )
+    .fetch_all(&state.db_pool)
+    .await
+    .into_diagnostic()?;
+
+    // We only know about nodes that have completed a purge before
+    let nodes = sqlx::query_scalar!("SELECT DISTINCT node FROM PurgeCompletions ORDER BY node")
+        .fetch_all(&state.db_pool)
+        .await
+        .into_diagnostic()?;
+
+    let mut completions_by_purge: HashMap<i64, Vec<Completion>> = HashMap::new();
+    for completion in completions {
+        completions_by_purge
+            .entry(completion.purge_id)
+            .or_default()
+            .push(completion);
+    }
+
+    let resp = html! {
+        @if session.role >= Role::Operator {
+            h2 { "Purge" }
+            form method="post" action="/_caje/purges" {
+                (session.csrf_input())
+                select name="kind" {
+                    @for kind in PurgeKind::ALL {
+                        option value=(kind.as_str()) { (kind.as_str()) }
+                    }
+                }
+                input type="text" name="pattern" placeholder="/products/*";
+                label {
+                    input type="checkbox" name="soft" value="true";
+                    "Soft (mark stale instead of removing)"
+                }
+                input type="submit" value="Purge";
+            }
+        }
+
+        h2 { "Recent Purges" }
+        table {
+            tr {
+                th { "Requested" }
+                th { "By" }
+                th { "Kind" }
+                th { "Pattern" }
+                th { "Completed" }
+                th { "Pending" }
+            }
+            @for purge in purges {
+                @let completed = completions_by_purge.remove(&purge.id).unwrap_or_default();
+                tr {
+                    td { (purge.created_at) }
+                    td { (purge.requested_by) }
+                    td {
+                        (purge.kind.as_str())
+                        @if purge.soft { " (soft)" }
+                    }
+                    td { code { (purge.pattern) } }
+                    td {
+                        ul {
+                            @for completion in &completed {
+                                li {
+                                    (completion.node)
+                                    @if purge.soft { ": marked stale " } @else { ": removed " }
+                                    (completion.removed)
+                                    " at " (completion.completed_at)
+                                }
+                            }
+                        }
+                    }
+                    td {
+                        ul {
+                            @for node in nodes.iter().filter(|node| completed.iter().all(|c| &c.node != *node)) {
+                                li { (node) }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    };
+
+    Ok((StatusCode::OK, resp))
+}
+
+#[derive(Deserialize)]
+pub(crate) struct PurgeForm {
+    kind: PurgeKind,
+    pattern: String,
+    #[serde(default)]
+    soft: bool,
+}
+
+pub(crate) async fn create(
+    State(state): State<AppState>,
+    CsrfForm { session, form }: CsrfForm<PurgeForm, Operator>,
+) -> Result<impl IntoResponse, WrappedError> {
+    purge::request(
+        &state,
+        &session.actor(),
+        form.kind,
+        &form.pattern,
+        form.soft,
+    )
+    .await?;
+
+    Ok(Redirect::to("/_caje/purges"))
+}
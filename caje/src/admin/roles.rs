@@ -0,0 +1,60 @@
+use std::fmt::Display;
+
+use serde::Deserialize;
+
+/// What an admin user is allowed to do, each role can do everything the ones before it can
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(rename_all = "lowercase")]
+pub(crate) enum Role {
+    /// Can look at what is cached
+    Viewer,
+    /// Can change what is cached, by populating or clearing it
+    Operator,
+    /// Can clear the database and manage other admin users
+    Owner,
+}
+
+impl Role {
+    pub(crate) const ALL: [Role; 3] = [Role::Viewer, Role::Operator, Role::Owner];
+
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Role::Viewer => "viewer",
+            Role::Operator => "operator",
+            Role::Owner => "owner",
+        }
+    }
+}
+
+impl Display for Role {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// The minimum role a route needs, used as the type parameter of `DBSession`
+pub(crate) trait RequiredRole: Send + Sync + 'static {
+    const ROLE: Role;
+}
+
+#[derive(Debug)]
+pub(crate) struct Viewer;
+
+impl RequiredRole for Viewer {
+    const ROLE: Role = Role::Viewer;
+}
+
+#[derive(Debug)]
+pub(crate) struct Operator;
+
+impl RequiredRole for Operator {
+    const ROLE: Role = Role::Operator;
+}
+
+#[derive(Debug)]
+pub(crate) struct Owner;
+
+impl RequiredRole for Owner {
+    const ROLE: Role = Role::Owner;
+}
@@ -0,0 +1,92 @@
+use std::{fmt::Display, str::FromStr};
+
+use miette::miette;
+use serde::Serialize;
+
+use super::roles::Role;
+
+/// What an API token is allowed to do
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Scope {
+    /// List the manifest and what is cached
+    Read,
+    /// Remove responses from the cache
+    Purge,
+    /// Fetch the manifest into the cache
+    Populate,
+    /// Forget every page in the shared manifest
+    ClearDb,
+}
+
+impl Scope {
+    pub(crate) const ALL: [Scope; 4] = [Scope::Read, Scope::Purge, Scope::Populate, Scope::ClearDb];
+
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Scope::Read => "read",
+            Scope::Purge => "purge",
+            Scope::Populate => "populate",
+            Scope::ClearDb => "clear_db",
+        }
+    }
+
+    /// The role you need to use this scope, whether through a token or a session
+    pub(crate) fn role(&self) -> Role {
+        match self {
+            Scope::Read => Role::Viewer,
+            Scope::Purge | Scope::Populate => Role::Operator,
+            Scope::ClearDb => Role::Owner,
+        }
+    }
+}
+
+impl Display for Scope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for Scope {
+    type Err = miette::Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Scope::ALL
+            .into_iter()
+            .find(|scope| scope.as_str() == s)
+            .ok_or_else(|| miette!("Unknown API token scope: {s}"))
+    }
+}
+
+/// The scope an API route needs, used as the type parameter of `ApiCaller`
+pub(crate) trait RequiredScope: Send + Sync + 'static {
+    const SCOPE: Scope;
+}
+
+#[derive(Debug)]
+pub(crate) struct Read;
+
+impl RequiredScope for Read {
+    const SCOPE: Scope = Scope::Read;
+}
+
+#[derive(Debug)]
+pub(crate) struct Purge;
+
+impl RequiredScope for Purge {
+    const SCOPE: Scope = Scope::Purge;
+}
+
+#[derive(Debug)]
+pub(crate) struct Populate;
+
+impl RequiredScope for Populate {
+    const SCOPE: Scope = Scope::Populate;
+}
+
+#[derive(Debug)]
+pub(crate) struct ClearDb;
+
+impl RequiredScope for ClearDb {
+    const SCOPE: Scope = Scope::ClearDb;
+}
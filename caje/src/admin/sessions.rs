@@ -0,0 +1,125 @@
+use axum::{
+    extract::State,
+    response::{IntoResponse, Redirect},
+};
+use chrono::{NaiveDateTime, Utc};
+use http::StatusCode;
+use maud::html;
+use miette::IntoDiagnostic;
+use serde::Deserialize;
+use serde_json::json;
+use sqlx::query_as;
+
+use crate::{audit, AppState, WrappedError};
+
+use super::{
+    auth::{is_expired, DBSession},
+    csrf::CsrfForm,
+    roles::Role,
+};
+
+struct SessionListing {
+    id: i64,
+    username: String,
+    created_at: NaiveDateTime,
+    last_used_at: NaiveDateTime,
+    expires_at: NaiveDateTime,
+}
+
+/// Everyone can see and revoke their own sessions, owners can see and revoke everyone's
+pub(crate) async fn index(
+    State(state): State<AppState>,
+    session: DBSession,
+) -> Result<impl IntoResponse, WrappedError> {
+    let is_owner = session.role >= Role::Owner;
+
+    let sessions = query_as!(
+        SessionListing,
+        "SELECT Sessions.id, AdminUsers.username, Sessions.created_at, Sessions.last_used_at, Sessions.expires_at
+        FROM Sessions
+        JOIN AdminUsers ON AdminUsers.id = Sessions.admin_user_id
+        WHERE $1 OR Sessions.admin_user_id = $2
+        ORDER BY Sessions.last_used_at DESC",
+        is_owner,
+        session.admin_user_id
+    )
+    .fetch_all(&state.db_pool)
+    .await
+    .into_diagnostic()?;
+
+    let now = Utc::now().naive_utc();
+    let sessions = sessions
+        .into_iter()
+        .filter(|s| !is_expired(s.last_used_at, s.expires_at, now));
+
+    let resp = html! {
+        h2 { "Sessions" }
+        table {
+            tr {
+                th { "User" }
+                th { "Logged In" }
+                th { "Last Used" }
+                th { "Expires" }
+                th {}
+            }
+            @for s in sessions {
+                tr {
+                    td { (s.username) }
+                    td { (s.created_at) }
+                    td { (s.last_used_at) }
+                    td { (s.expires_at) }
+                    td {
+                        @if s.id == session.id {
+                            "This session"
+                        } @else {
+                            form method="post" action="/_caje/sessions/revoke" {
+                                (session.csrf_input())
+                                input type="hidden" name="id" value=(s.id);
+                                input type="submit" value="Revoke";
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    };
+
+    Ok((StatusCode::OK, resp))
+}
+
+#[derive(Deserialize)]
+pub(crate) struct RevokeForm {
+    id: i64,
+}
+
+pub(crate) async fn revoke(
+    State(state): State<AppState>,
+    CsrfForm { session, form }: CsrfForm<RevokeForm>,
+) -> Result<impl IntoResponse, WrappedError> {
+    let is_owner = session.role >= Role::Owner;
+
+    state
+        .db_writer
+        .write(|| async {
+            sqlx::query!(
+                "DELETE FROM Sessions WHERE id = $1 AND ($2 OR admin_user_id = $3)",
+                form.id,
+                is_owner,
+                session.admin_user_id
+            )
+            .execute(&state.db_pool)
+            .await
+            .into_diagnostic()
+        })
+        .await?;
+
+    audit::record(
+        &state,
+        &session.actor(),
+        "session_revoked",
+        json!({ "id": form.id }),
+    )
+    .await?;
+
+    Ok(Redirect::to("/_caje/sessions"))
+}
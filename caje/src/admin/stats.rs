@@ -0,0 +1,293 @@
+use std::collections::BTreeMap;
+
+use axum::{
+    extract::{Query, State},
+    response::IntoResponse,
+};
+use chrono::Utc;
+use http::StatusCode;
+use maud::html;
+use miette::IntoDiagnostic;
+use serde::Deserialize;
+
+use crate::{
+    stats::{latency_percentile, merge_histograms},
+    AppState, WrappedError,
+};
+
+use super::{auth::DBSession, entry::entry_link};
+
+/// The time ranges you can pick between, in hours
+const RANGES: [(i64, &str); 4] = [
+    (1, "Last hour"),
+    (6, "Last 6 hours"),
+    (24, "Last day"),
+    (168, "Last week"),
+];
+
+/// How many URLs we show in the top lists
+const TOP_URLS: i64 = 20;
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct StatsQuery {
+    hours: Option<i64>,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct Totals {
+    hits: i64,
+    misses: i64,
+    bytes_from_cache: i64,
+    bytes_from_origin: i64,
+}
+
+impl Totals {
+    fn add(&mut self, other: Totals) {
+        self.hits += other.hits;
+        self.misses += other.misses;
+        self.bytes_from_cache += other.bytes_from_cache;
+        self.bytes_from_origin += other.bytes_from_origin;
+    }
+}
+
+fn hit_ratio(hits: i64, misses: i64) -> Option<f64> {
+    let total = hits + misses;
+
+    (total > 0).then(|| hits as f64 / total as f64)
+}
+
+fn percent(ratio: Option<f64>) -> String {
+    match ratio {
+        Some(ratio) => format!("{:.1}%", ratio * 100.0),
+        None => "-".to_string(),
+    }
+}
+
+pub(crate) fn human_bytes(bytes: i64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{bytes} B")
+    } else {
+        format!("{size:.1} {}", UNITS[unit])
+    }
+}
+
+pub(crate) async fn index(
+    State(state): State<AppState>,
+    _: DBSession,
+    Query(query): Query<StatsQuery>,
+) -> Result<impl IntoResponse, WrappedError> {
+    let hours = query.hours.unwrap_or(24).clamp(1, 168);
+    let since = Utc::now().naive_utc() - chrono::Duration::hours(hours);
+
+    let nodes = sqlx::query!(
+        r#"SELECT node, SUM(hits) as "hits!: i64", SUM(misses) as "misses!: i64",
+            SUM(bytes_from_cache) as "bytes_from_cache!: i64",
+            SUM(bytes_from_origin) as "bytes_from_origin!: i64"
+        FROM StatsRollups
+        WHERE period_start >= ?
+        GROUP BY node
+        ORDER BY node"#,
+        since
+    )
+    .fetch_all(&state.db_pool)
+    .await
+    .into_diagnostic()?;
+
+    let hourly = sqlx::query!(
+        r#"SELECT strftime('%Y-%m-%d %H:00', period_start) as "hour!: String",
+            SUM(hits) as "hits!: i64", SUM(misses) as "misses!: i64"
+        FROM StatsRollups
+        WHERE period_start >= ?
+        GROUP BY 1
+        ORDER BY 1"#,
+        since
+    )
+    .fetch_all(&state.db_pool)
+    .await
+    .into_diagnostic()?;
+
+    let histograms = sqlx::query!(
+        "SELECT node, origin_latency_histogram FROM StatsRollups WHERE period_start >= ?",
+        since
+    )
+    .fetch_all(&state.db_pool)
+    .await
+    .into_diagnostic()?;
+
+    let top_urls = sqlx::query!(
+        r#"SELECT cache_key, SUM(StatsUrlRollups.hits) as "hits!: i64",
+            SUM(StatsUrlRollups.misses) as "misses!: i64"
+        FROM StatsUrlRollups
+        JOIN StatsRollups ON StatsRollups.id = StatsUrlRollups.rollup_id
+        WHERE StatsRollups.period_start >= ?
+        GROUP BY cache_key
+        ORDER BY 2 DESC
+        LIMIT ?"#,
+        since,
+        TOP_URLS
+    )
+    .fetch_all(&state.db_pool)
+    .await
+    .into_diagnostic()?;
+
+    let top_misses = sqlx::query!(
+        r#"SELECT cache_key, SUM(StatsUrlRollups.hits) as "hits!: i64",
+            SUM(StatsUrlRollups.misses) as "misses!: i64"
+        FROM StatsUrlRollups
+        JOIN StatsRollups ON StatsRollups.id = StatsUrlRollups.rollup_id
+        WHERE StatsRollups.period_start >= ?
+        GROUP BY cache_key
+        HAVING SUM(StatsUrlRollups.misses) > 0
+        ORDER BY 3 DESC
+        LIMIT ?"#,
+        since,
+        TOP_URLS
+    )
+    .fetch_all(&state.db_pool)
+    .await
+    .into_diagnostic()?;
+
+    let mut totals = Totals::default();
+    for node in &nodes {
+        totals.add(Totals {
+            hits: node.hits,
+            misses: node.misses,
+            bytes_from_cache: node.bytes_from_cache,
+            bytes_from_origin: node.bytes_from_origin,
+        });
+    }
+
+    let latency = merge_histograms(
+        histograms
+            .iter()
+            .map(|row| row.origin_latency_histogram.as_str()),
+    );
+    let mut latency_by_node = BTreeMap::new();
+    for row in &histograms {
+        latency_by_node
+            .entry(row.node.as_str())
+            .or_insert_with(Vec::new)
+            .push(row.origin_latency_histogram.as_str());
+    }
+    let latency_by_node = latency_by_node
+        .into_iter()
+        .map(|(node, histograms)| (node, merge_histograms(histograms)))
+        .collect::<BTreeMap<_, _>>();
+
+    let resp = html! {
+        h2 { "Stats" }
+        p {
+            @for (i, (range, label)) in RANGES.iter().enumerate() {
+                @if i > 0 { " | " }
+                @if *range == hours {
+                    strong { (label) }
+                } @else {
+                    a href={ "/_caje/stats?hours=" (range) } { (label) }
+                }
+            }
+        }
+        p { "Every node rolls its counters up once a minute, so the last minute isn't shown yet" }
+
+        table {
+            tr { th { "Hit Ratio" } td { (percent(hit_ratio(totals.hits, totals.misses))) } }
+            tr { th { "Served From Cache" } td { (totals.hits) " requests, " (human_bytes(totals.bytes_from_cache)) } }
+            tr { th { "Served From Origin" } td { (totals.misses) " requests, " (human_bytes(totals.bytes_from_origin)) } }
+            tr { th { "Bandwidth Saved" } td { (human_bytes(totals.bytes_from_cache)) } }
+            tr { th { "Origin Latency p50" } td { (latency_percentile(&latency, 0.5)) } }
+            tr { th { "Origin Latency p95" } td { (latency_percentile(&latency, 0.95)) } }
+        }
+
+        h3 { "Hit Ratio Over Time" }
+        table {
+            tr {
+                th { "Hour (UTC)" }
+                th { "Hits" }
+                th { "Misses" }
+                th { "Hit Ratio" }
+                th {}
+            }
+            @for hour in &hourly {
+                @let ratio = hit_ratio(hour.hits, hour.misses);
+                tr {
+                    td { (hour.hour) }
+                    td { (hour.hits) }
+                    td { (hour.misses) }
+                    td { (percent(ratio)) }
+                    td { meter value=(ratio.unwrap_or_default()) {} }
+                }
+            }
+        }
+
+        h3 { "Per Node" }
+        table {
+            tr {
+                th { "Node" }
+                th { "Hits" }
+                th { "Misses" }
+                th { "Hit Ratio" }
+                th { "From Cache" }
+                th { "From Origin" }
+                th { "Origin p50" }
+                th { "Origin p95" }
+            }
+            @for node in &nodes {
+                tr {
+                    td { (node.node) }
+                    td { (node.hits) }
+                    td { (node.misses) }
+                    td { (percent(hit_ratio(node.hits, node.misses))) }
+                    td { (human_bytes(node.bytes_from_cache)) }
+                    td { (human_bytes(node.bytes_from_origin)) }
+                    @if let Some(histogram) = latency_by_node.get(node.node.as_str()) {
+                        td { (latency_percentile(histogram, 0.5)) }
+                        td { (latency_percentile(histogram, 0.95)) }
+                    } @else {
+                        td {}
+                        td {}
+                    }
+                }
+            }
+        }
+
+        h3 { "Top URLs" }
+        table {
+            tr { th { "Key" } th { "Hits" } th { "Misses" } }
+            @for url in &top_urls {
+                tr { td { (entry_link(&url.cache_key)) } td { (url.hits) } td { (url.misses) } }
+            }
+        }
+
+        h3 { "Top Misses" }
+        table {
+            tr { th { "Key" } th { "Misses" } th { "Hits" } }
+            @for url in &top_misses {
+                tr { td { (entry_link(&url.cache_key)) } td { (url.misses) } td { (url.hits) } }
+            }
+        }
+    };
+
+    Ok((StatusCode::OK, resp))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_ratios_and_sizes() {
+        assert_eq!(percent(hit_ratio(3, 1)), "75.0%");
+        assert_eq!(percent(hit_ratio(0, 0)), "-");
+        assert_eq!(human_bytes(512), "512 B");
+        assert_eq!(human_bytes(1536), "1.5 KiB");
+        assert_eq!(human_bytes(3 * 1024 * 1024), "3.0 MiB");
+    }
+}
@@ -0,0 +1,480 @@
+use std::sync::OnceLock;
+
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
+use axum::{
+    extract::State,
+    response::{IntoResponse, Redirect},
+};
+use http::StatusCode;
+use maud::html;
+use miette::{miette, IntoDiagnostic, Result};
+use serde::Deserialize;
+use serde_json::json;
+use sqlx::{query_as, SqlitePool};
+
+use crate::{
+    audit::{self, Actor},
+    AppState, WrappedError,
+};
+
+use super::{
+    auth::DBSession,
+    csrf::CsrfForm,
+    roles::{Owner, Role},
+};
+
+#[derive(Debug, Clone)]
+pub(crate) struct AdminUser {
+    pub id: i64,
+    pub username: String,
+    pub role: Role,
+    password_hash: Option<String>,
+}
+
+fn hash_password(password: &str) -> Result<String> {
+    if password.is_empty() {
+        Err(miette!("Passwords can't be empty"))?
+    }
+
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|e| miette!("Failed to hash password: {e}"))?;
+
+    Ok(hash.to_string())
+}
+
+/// Spend as long as checking a real password, so a failed login can't tell anyone whether the
+/// username exists or has a password
+pub(crate) fn verify_dummy_password(password: &str) -> Result<()> {
+    static DUMMY_HASH: OnceLock<String> = OnceLock::new();
+
+    let dummy_hash = match DUMMY_HASH.get() {
+        Some(hash) => hash,
+        None => {
+            let hash = hash_password("not anyone's password")?;
+            DUMMY_HASH.get_or_init(|| hash)
+        }
+    };
+    let dummy_hash =
+        PasswordHash::new(dummy_hash).map_err(|e| miette!("Invalid password hash: {e}"))?;
+
+    let _ = Argon2::default().verify_password(password.as_bytes(), &dummy_hash);
+
+    Ok(())
+}
+
+impl AdminUser {
+    pub(crate) async fn fetch(db_pool: &SqlitePool, id: i64) -> Result<Option<AdminUser>> {
+        query_as!(
+            AdminUser,
+            r#"SELECT id, username, role as "role: Role", password_hash
+            FROM AdminUsers
+            WHERE id = ?"#,
+            id
+        )
+        .fetch_optional(db_pool)
+        .await
+        .into_diagnostic()
+    }
+
+    pub(crate) async fn fetch_by_username(
+        db_pool: &SqlitePool,
+        username: &str,
+    ) -> Result<Option<AdminUser>> {
+        query_as!(
+            AdminUser,
+            r#"SELECT id, username, role as "role: Role", password_hash
+            FROM AdminUsers
+            WHERE username = ?"#,
+            username
+        )
+        .fetch_optional(db_pool)
+        .await
+        .into_diagnostic()
+    }
+
+    async fn list(db_pool: &SqlitePool) -> Result<Vec<AdminUser>> {
+        query_as!(
+            AdminUser,
+            r#"SELECT id, username, role as "role: Role", password_hash
+            FROM AdminUsers
+            ORDER BY username"#
+        )
+        .fetch_all(db_pool)
+        .await
+        .into_diagnostic()
+    }
+
+    pub(crate) async fn any_exist(db_pool: &SqlitePool) -> Result<bool> {
+        let count = sqlx::query_scalar!("SELECT COUNT(*) FROM AdminUsers")
+            .fetch_one(db_pool)
+            .await
+            .into_diagnostic()?;
+
+        Ok(count > 0)
+    }
+
+    /// Create a user, without a password they can only log in with the shared bootstrap password
+    pub(crate) async fn create(
+        db_pool: &SqlitePool,
+        username: &str,
+        password: Option<&str>,
+        role: Role,
+    ) -> Result<AdminUser> {
+        let username = username.trim();
+        if username.is_empty() {
+            Err(miette!("Usernames can't be empty"))?
+        }
+
+        let password_hash = password.map(hash_password).transpose()?;
+
+        query_as!(
+            AdminUser,
+            r#"INSERT INTO AdminUsers (username, password_hash, role) VALUES (?, ?, ?)
+            RETURNING id, username, role as "role: Role", password_hash"#,
+            username,
+            password_hash,
+            role
+        )
+        .fetch_one(db_pool)
+        .await
+        .into_diagnostic()
+    }
+
+    pub(crate) fn actor(&self) -> Actor {
+        Actor {
+            admin_user_id: Some(self.id),
+            name: self.username.clone(),
+        }
+    }
+
+    pub(crate) fn has_password(&self) -> bool {
+        self.password_hash.is_some()
+    }
+
+    pub(crate) fn verify_password(&self, password: &str) -> Result<bool> {
+        let Some(password_hash) = &self.password_hash else {
+            return Ok(false);
+        };
+
+        let password_hash =
+            PasswordHash::new(password_hash).map_err(|e| miette!("Invalid password hash: {e}"))?;
+
+        Ok(Argon2::default()
+            .verify_password(password.as_bytes(), &password_hash)
+            .is_ok())
+    }
+
+    pub(crate) async fn has_passkeys(&self, db_pool: &SqlitePool) -> Result<bool> {
+        let count = sqlx::query_scalar!(
+            "SELECT COUNT(*) FROM Passkeys WHERE admin_user_id = ?",
+            self.id
+        )
+        .fetch_one(db_pool)
+        .await
+        .into_diagnostic()?;
+
+        Ok(count > 0)
+    }
+
+    /// Whether the shared `ADMIN_AUTH_KEY` can log in as `user`, or create them if they don't exist
+    ///
+    /// It is only for bootstrapping, so it works for users with no other way to log in yet, and
+    /// for creating the first owner
+    pub(crate) async fn can_bootstrap(
+        user: Option<&AdminUser>,
+        db_pool: &SqlitePool,
+    ) -> Result<bool> {
+        match user {
+            Some(user) => Ok(!user.has_password() && !user.has_passkeys(db_pool).await?),
+            None => Ok(!AdminUser::any_exist(db_pool).await?),
+        }
+    }
+}
+
+pub(crate) async fn index(
+    State(state): State<AppState>,
+    session: DBSession<Owner>,
+) -> Result<impl IntoResponse, WrappedError> {
+    let users = AdminUser::list(&state.db_pool).await?;
+
+    let resp = html! {
+        h2 { "Admin Users" }
+        table {
+            tr {
+                th { "Username" }
+                th { "Role" }
+                th { "Password" }
+                th {}
+            }
+            @for user in users {
+                tr {
+                    td { (user.username) }
+                    // You can't lock yourself out, so there is always at least one owner
+                    @if user.id == session.admin_user_id {
+                        td { (user.role.as_str()) }
+                        td { @if user.has_password() { "Set" } @else { "Not Set" } }
+                        td {}
+                    } @else {
+                        td colspan="2" {
+                            form method="post" action="/_caje/users/update" {
+                                (session.csrf_input())
+                                input type="hidden" name="id" value=(user.id);
+                                (role_select(user.role))
+                                input type="password" name="password" placeholder="New Password";
+                                input type="submit" value="Update";
+                            }
+                        }
+                        td {
+                            form method="post" action="/_caje/users/delete" {
+                                (session.csrf_input())
+                                input type="hidden" name="id" value=(user.id);
+                                input type="submit" value="Delete";
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        h2 { "Add a User" }
+        form method="post" action="/_caje/users" {
+            (session.csrf_input())
+            input type="text" name="username" placeholder="Username";
+            input type="password" name="password" placeholder="Password";
+            (role_select(Role::Viewer))
+            input type="submit" value="Add User";
+        }
+    };
+
+    Ok((StatusCode::OK, resp))
+}
+
+fn role_select(selected: Role) -> maud::Markup {
+    html! {
+        select name="role" {
+            @for role in Role::ALL {
+                option value=(role.as_str()) selected[role == selected] { (role.as_str()) }
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub(crate) struct CreateForm {
+    username: String,
+    password: String,
+    role: Role,
+}
+
+pub(crate) async fn create(
+    State(state): State<AppState>,
+    CsrfForm { session, form }: CsrfForm<CreateForm, Owner>,
+) -> Result<impl IntoResponse, WrappedError> {
+    let user = state
+        .db_writer
+        .write(|| {
+            AdminUser::create(
+                &state.db_pool,
+                &form.username,
+                Some(&form.password),
+                form.role,
+            )
+        })
+        .await?;
+
+    audit::record(
+        &state,
+        &session.actor(),
+        "user_created",
+        json!({ "id": user.id, "username": user.username, "role": user.role.as_str() }),
+    )
+    .await?;
+
+    Ok(Redirect::to("/_caje/users"))
+}
+
+#[derive(Deserialize)]
+pub(crate) struct UpdateForm {
+    id: i64,
+    role: Role,
+    /// Left blank to keep the current password
+    password: String,
+}
+
+pub(crate) async fn update(
+    State(state): State<AppState>,
+    CsrfForm { session, form }: CsrfForm<UpdateForm, Owner>,
+) -> Result<impl IntoResponse, WrappedError> {
+    if form.id == session.admin_user_id {
+        Err(miette!("You can't change your own role"))?
+    }
+
+    state
+        .db_writer
+        .write(|| async {
+            sqlx::query!(
+                "UPDATE AdminUsers SET role = ? WHERE id = ?",
+                form.role,
+                form.id
+            )
+            .execute(&state.db_pool)
+            .await
+            .into_diagnostic()
+        })
+        .await?;
+
+    if !form.password.is_empty() {
+        let password_hash = hash_password(&form.password)?;
+        state
+            .db_writer
+            .write(|| async {
+                sqlx::query!(
+                    "UPDATE AdminUsers SET password_hash = ? WHERE id = ?",
+                    password_hash,
+                    form.id
+                )
+                .execute(&state.db_pool)
+                .await
+                .into_diagnostic()
+            })
+            .await?;
+    }
+
+    audit::record(
+        &state,
+        &session.actor(),
+        "user_updated",
+        json!({
+            "id": form.id,
+            "role": form.role.as_str(),
+            "password_changed": !form.password.is_empty(),
+        }),
+    )
+    .await?;
+
+    Ok(Redirect::to("/_caje/users"))
+}
+
+#[derive(Deserialize)]
+pub(crate) struct DeleteForm {
+    id: i64,
+}
+
+pub(crate) async fn delete(
+    State(state): State<AppState>,
+    CsrfForm { session, form }: CsrfForm<DeleteForm, Owner>,
+) -> Result<impl IntoResponse, WrappedError> {
+    if form.id == session.admin_user_id {
+        Err(miette!("You can't delete yourself"))?
+    }
+
+    let user = AdminUser::fetch(&state.db_pool, form.id).await?;
+
+    // Their sessions and passkeys go with them
+    state
+        .db_writer
+        .write(|| async {
+            sqlx::query!("DELETE FROM AdminUsers WHERE id = ?", form.id)
+                .execute(&state.db_pool)
+                .await
+                .into_diagnostic()
+        })
+        .await?;
+
+    audit::record(
+        &state,
+        &session.actor(),
+        "user_deleted",
+        json!({ "id": form.id, "username": user.map(|user| user.username) }),
+    )
+    .await?;
+
+    Ok(Redirect::to("/_caje/users"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn db_pool() -> SqlitePool {
+        let db_pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        sqlx::migrate!().run(&db_pool).await.unwrap();
+
+        db_pool
+    }
+
+    #[tokio::test]
+    async fn passwords_are_hashed_and_verified() {
+        let db_pool = db_pool().await;
+
+        let user = AdminUser::create(&db_pool, "alice", Some("hunter2"), Role::Operator)
+            .await
+            .unwrap();
+        assert_ne!(user.password_hash.as_deref(), Some("hunter2"));
+
+        let user = AdminUser::fetch_by_username(&db_pool, "alice")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(user.role, Role::Operator);
+        assert!(user.verify_password("hunter2").unwrap());
+        assert!(!user.verify_password("hunter3").unwrap());
+    }
+
+    #[tokio::test]
+    async fn shared_password_only_bootstraps() {
+        let db_pool = db_pool().await;
+        assert!(AdminUser::can_bootstrap(None, &db_pool).await.unwrap());
+
+        let alice = AdminUser::create(&db_pool, "alice", None, Role::Owner)
+            .await
+            .unwrap();
+        assert!(AdminUser::can_bootstrap(Some(&alice), &db_pool)
+            .await
+            .unwrap());
+        // Nobody new gets in with it once there's an owner
+        assert!(!AdminUser::can_bootstrap(None, &db_pool).await.unwrap());
+
+        sqlx::query!(
+            "INSERT INTO Passkeys (admin_user_id, name, credential_id, public_key, algorithm, sign_count)
+            VALUES (?, 'Laptop', 'credential', x'00', -7, 0)",
+            alice.id
+        )
+        .execute(&db_pool)
+        .await
+        .unwrap();
+        assert!(!AdminUser::can_bootstrap(Some(&alice), &db_pool)
+            .await
+            .unwrap());
+
+        let bob = AdminUser::create(&db_pool, "bob", Some("hunter2"), Role::Viewer)
+            .await
+            .unwrap();
+        assert!(!AdminUser::can_bootstrap(Some(&bob), &db_pool)
+            .await
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn users_without_a_password_never_verify() {
+        let db_pool = db_pool().await;
+
+        let user = AdminUser::create(&db_pool, "bob", None, Role::Owner)
+            .await
+            .unwrap();
+
+        assert!(!user.has_password());
+        assert!(!user.verify_password("").unwrap());
+    }
+
+    #[test]
+    fn roles_are_ordered_by_what_they_can_do() {
+        assert!(Role::Viewer < Role::Operator);
+        assert!(Role::Operator < Role::Owner);
+    }
+}
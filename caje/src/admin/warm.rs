@@ -0,0 +1,832 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    extract::{Query, State},
+    response::{IntoResponse, Redirect},
+};
+use chrono::{NaiveDateTime, Utc};
+use html5ever::{
+    tendril::StrTendril,
+    tokenizer::{
+        BufferQueue, Tag, TagKind, Token, TokenSink, TokenSinkResult, Tokenizer, TokenizerOpts,
+    },
+};
+use http::{header::CONTENT_TYPE, StatusCode};
+use maud::html;
+use miette::{miette, IntoDiagnostic};
+use quick_xml::{events::Event, Reader};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::{sync::Semaphore, task::JoinSet};
+
+use crate::{audit, AppState, WrappedError, PROXY_FROM_DOMAIN, PROXY_ORIGIN_DOMAIN};
+
+use super::{
+    auth::DBSession,
+    csrf::CsrfForm,
+    populate::{cache_response, fetch_from_origin},
+    roles::Operator,
+};
+
+/// How many requests we make to the origin at once while warming
+const WARM_CONCURRENCY: usize = 8;
+
+/// We stop discovering URLs after this many, so one warm can't fetch the whole internet
+const MAX_WARM_URLS: usize = 1000;
+
+/// Crawls can't go deeper than this many links from the start page
+const MAX_CRAWL_DEPTH: usize = 5;
+
+/// How many finished warms we remember, so their reports can still be looked at
+const KEEP_FINISHED_JOBS: usize = 20;
+
+/// Where to find the URLs to warm
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum WarmSource {
+    /// Every `<loc>` in a sitemap.xml, following sitemap indexes one level down
+    Sitemap(String),
+    /// A list of URLs or paths
+    Urls(Vec<String>),
+    /// Pages linked from `start` on the same host, up to `depth` links away
+    Crawl { start: String, depth: usize },
+}
+
+impl WarmSource {
+    fn kind(&self) -> &'static str {
+        match self {
+            WarmSource::Sitemap(_) => "sitemap",
+            WarmSource::Urls(_) => "urls",
+            WarmSource::Crawl { .. } => "crawl",
+        }
+    }
+
+    /// Check the sitemap or crawl start is on our host, before fetching anything
+    pub(crate) fn validate(&self) -> miette::Result<()> {
+        let base = origin_base();
+
+        match self {
+            WarmSource::Sitemap(sitemap) if to_path(&base, sitemap).is_none() => {
+                Err(miette!("The sitemap has to be on our host"))
+            }
+            WarmSource::Crawl { start, .. } if to_path(&base, start).is_none() => {
+                Err(miette!("The crawl has to start on our host"))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// What a warm did with each URL it found
+#[derive(Debug, Default, Clone, Serialize)]
+pub(crate) struct WarmReport {
+    /// URLs on our host that we are going to warm, plus any sitemaps we failed to read. The warm
+    /// is done when the rest add up to this
+    pub discovered: usize,
+    /// Responses we stored in the cache
+    pub cached: usize,
+    /// Responses the origin says we aren't allowed to cache
+    pub uncacheable: usize,
+    /// URLs we couldn't fetch or store, and sitemaps in a sitemap index we couldn't read
+    pub failed: usize,
+    /// URLs on other hosts, which we don't proxy
+    pub skipped: usize,
+}
+
+impl WarmReport {
+    /// URLs we have finished with, one way or another
+    pub(crate) fn done(&self) -> usize {
+        self.cached + self.uncacheable + self.failed
+    }
+}
+
+/// The path and query we would cache `link` under, if it is on our host
+///
+/// Relative links are resolved against `base`, and fragments are dropped
+fn to_path(base: &Url, link: &str) -> Option<String> {
+    let url = base.join(link.trim()).ok()?;
+
+    if !matches!(url.scheme(), "http" | "https") {
+        return None;
+    }
+    if !matches!(
+        url.host_str(),
+        Some(PROXY_FROM_DOMAIN | PROXY_ORIGIN_DOMAIN)
+    ) {
+        return None;
+    }
+
+    Some(match url.query() {
+        Some(query) => format!("{}?{}", url.path(), query),
+        None => url.path().to_string(),
+    })
+}
+
+fn origin_base() -> Url {
+    Url::parse(&format!("https://{PROXY_FROM_DOMAIN}/")).expect("Our domain is a valid URL")
+}
+
+/// The URLs in a sitemap, and whether it was a sitemap index pointing at more sitemaps
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Sitemap {
+    index: bool,
+    locs: Vec<String>,
+}
+
+/// Read every `<loc>` in a sitemap or sitemap index, whatever namespace prefix it uses
+fn parse_sitemap(xml: &str) -> miette::Result<Sitemap> {
+    let mut reader = Reader::from_str(xml);
+    reader.trim_text(true);
+
+    let mut sitemap = Sitemap::default();
+    let mut loc: Option<String> = None;
+    loop {
+        match reader
+            .read_event()
+            .map_err(|e| miette!("Invalid sitemap at byte {}: {e}", reader.buffer_position()))?
+        {
+            Event::Start(tag) if tag.local_name().as_ref() == b"sitemapindex" => {
+                sitemap.index = true;
+            }
+            Event::Start(tag) if tag.local_name().as_ref() == b"loc" => {
+                loc = Some(String::new());
+            }
+            Event::Text(text) => {
+                if let Some(loc) = &mut loc {
+                    loc.push_str(&text.unescape().into_diagnostic()?);
+                }
+            }
+            Event::CData(cdata) => {
+                if let Some(loc) = &mut loc {
+                    loc.push_str(&String::from_utf8_lossy(&cdata.into_inner()));
+                }
+            }
+            Event::End(tag) if tag.local_name().as_ref() == b"loc" => {
+                if let Some(loc) = loc.take() {
+                    sitemap.locs.push(loc.trim().to_string());
+                }
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+
+    Ok(sitemap)
+}
+
+/// Collects the `href` of every tag the tokenizer sees
+#[derive(Debug, Default)]
+struct LinkSink {
+    links: Vec<String>,
+}
+
+impl TokenSink for LinkSink {
+    type Handle = ();
+
+    fn process_token(&mut self, token: Token, _line_number: u64) -> TokenSinkResult<()> {
+        if let Token::TagToken(Tag {
+            kind: TagKind::StartTag,
+            attrs,
+            ..
+        }) = token
+        {
+            self.links.extend(
+                attrs
+                    .into_iter()
+                    .filter(|attr| &*attr.name.local == "href")
+                    .map(|attr| attr.value.to_string()),
+            );
+        }
+
+        TokenSinkResult::Continue
+    }
+}
+
+/// The target of every `href` attribute in an HTML page
+fn html_links(html: &str) -> Vec<String> {
+    let mut input = BufferQueue::new();
+    input.push_back(StrTendril::from_slice(html));
+
+    let mut tokenizer = Tokenizer::new(LinkSink::default(), TokenizerOpts::default());
+    let _ = tokenizer.feed(&mut input);
+    tokenizer.end();
+
+    tokenizer.sink.links
+}
+
+/// One URL per line, ignoring blank lines and `#` comments
+fn url_list(text: &str) -> Vec<String> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect()
+}
+
+/// Keeps track of which URLs we have already seen, and counts the ones we skip
+#[derive(Debug, Default)]
+struct Discovered {
+    seen: HashSet<String>,
+    skipped: usize,
+}
+
+impl Discovered {
+    /// Returns the path to warm for `link`, unless it's elsewhere, a repeat or over the limit
+    fn add(&mut self, base: &Url, link: &str) -> Option<String> {
+        let Some(path) = to_path(base, link) else {
+            self.skipped += 1;
+            return None;
+        };
+
+        if self.is_full() || self.seen.contains(&path) {
+            return None;
+        }
+        self.seen.insert(path.clone());
+
+        Some(path)
+    }
+
+    fn is_full(&self) -> bool {
+        self.seen.len() >= MAX_WARM_URLS
+    }
+}
+
+/// Fetch a page from the origin only to read it, like a sitemap
+async fn fetch_text(path: &str) -> miette::Result<String> {
+    let response = fetch_from_origin("GET", path).await?;
+    if !response.status_code.is_success() {
+        Err(miette!(
+            "Origin responded to {path} with {}",
+            response.status_code
+        ))?
+    }
+
+    String::from_utf8(response.body).into_diagnostic()
+}
+
+/// The paths to warm from a sitemap, or from every sitemap in a sitemap index
+///
+/// A child sitemap we can't fetch or read is counted as failed instead of failing the warm
+async fn sitemap_paths(
+    sitemap: &str,
+    discovered: &mut Discovered,
+    report: &mut WarmReport,
+) -> miette::Result<Vec<String>> {
+    let base = origin_base();
+    let sitemap =
+        to_path(&base, sitemap).ok_or_else(|| miette!("The sitemap has to be on our host"))?;
+    let sitemap = parse_sitemap(&fetch_text(&sitemap).await?)?;
+
+    if !sitemap.index {
+        return Ok(sitemap
+            .locs
+            .iter()
+            .filter_map(|loc| discovered.add(&base, loc))
+            .collect());
+    }
+
+    let mut paths = vec![];
+    for child in sitemap.locs {
+        if discovered.is_full() {
+            break;
+        }
+        let Some(child) = to_path(&base, &child) else {
+            continue;
+        };
+
+        match fetch_text(&child).await.and_then(|xml| parse_sitemap(&xml)) {
+            Ok(child) => paths.extend(
+                child
+                    .locs
+                    .iter()
+                    .filter_map(|loc| discovered.add(&base, loc)),
+            ),
+            Err(e) => {
+                tracing::warn!(error = ?e, sitemap = child, "Failed to read sitemap");
+                report.discovered += 1;
+                report.failed += 1;
+            }
+        }
+    }
+
+    Ok(paths)
+}
+
+/// Fetch and cache `path`, returning the links in it if it's a page we should crawl
+async fn warm_one(
+    state: AppState,
+    path: String,
+    crawl: bool,
+) -> (miette::Result<bool>, String, Vec<String>) {
+    let response = match fetch_from_origin("GET", &path).await {
+        Ok(response) => response,
+        Err(e) => return (Err(e), path, vec![]),
+    };
+
+    let is_html = response
+        .headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("text/html"));
+    let links = if crawl && is_html && response.status_code.is_success() {
+        html_links(&String::from_utf8_lossy(&response.body))
+    } else {
+        vec![]
+    };
+
+    let cached = cache_response(&state, "GET", &path, response).await;
+
+    (cached, path, links)
+}
+
+/// Called with the report so far each time a URL is done
+type Progress<'a> = &'a (dyn Fn(&WarmReport) + Send + Sync);
+
+/// Warm every path in one go, a few at a time, and return the links found on crawled pages
+async fn warm_batch(
+    state: &AppState,
+    paths: Vec<String>,
+    crawl: bool,
+    report: &mut WarmReport,
+    progress: Progress<'_>,
+) -> Vec<(String, Vec<String>)> {
+    report.discovered += paths.len();
+    progress(report);
+
+    let permits = Arc::new(Semaphore::new(WARM_CONCURRENCY));
+    let mut tasks = JoinSet::new();
+
+    for path in paths {
+        let state = state.clone();
+        let permits = permits.clone();
+        tasks.spawn(async move {
+            let _permit = permits.acquire_owned().await;
+
+            warm_one(state, path, crawl).await
+        });
+    }
+
+    let mut pages = vec![];
+    while let Some(task) = tasks.join_next().await {
+        match task {
+            Ok((Ok(true), path, links)) => {
+                report.cached += 1;
+                pages.push((path, links));
+            }
+            Ok((Ok(false), path, links)) => {
+                report.uncacheable += 1;
+                pages.push((path, links));
+            }
+            Ok((Err(e), path, _)) => {
+                tracing::warn!(error = ?e, path, "Failed to warm");
+                report.failed += 1;
+            }
+            Err(e) => {
+                tracing::warn!(error = ?e, "Warm task panicked");
+                report.failed += 1;
+            }
+        }
+        progress(report);
+    }
+
+    pages
+}
+
+/// Fetch every URL from `source` into this node's cache and the shared manifest
+async fn warm(
+    state: &AppState,
+    source: &WarmSource,
+    progress: Progress<'_>,
+) -> miette::Result<WarmReport> {
+    let base = origin_base();
+    let mut discovered = Discovered::default();
+    let mut report = WarmReport::default();
+
+    match source {
+        WarmSource::Sitemap(sitemap) => {
+            let paths = sitemap_paths(sitemap, &mut discovered, &mut report).await?;
+            report.skipped = discovered.skipped;
+            warm_batch(state, paths, false, &mut report, progress).await;
+        }
+        WarmSource::Urls(urls) => {
+            let paths = urls
+                .iter()
+                .filter_map(|url| discovered.add(&base, url))
+                .collect();
+            report.skipped = discovered.skipped;
+            warm_batch(state, paths, false, &mut report, progress).await;
+        }
+        WarmSource::Crawl { start, depth } => {
+            let start = discovered
+                .add(&base, start)
+                .ok_or_else(|| miette!("The crawl has to start on our host"))?;
+
+            let mut level = vec![start];
+            for depth in (0..=(*depth).min(MAX_CRAWL_DEPTH)).rev() {
+                report.skipped = discovered.skipped;
+                let pages = warm_batch(state, level, depth > 0, &mut report, progress).await;
+
+                level = vec![];
+                for (path, links) in pages {
+                    let page_url = base.join(&path).into_diagnostic()?;
+                    level.extend(
+                        links
+                            .iter()
+                            .filter_map(|link| discovered.add(&page_url, link)),
+                    );
+                }
+                if level.is_empty() {
+                    break;
+                }
+            }
+        }
+    }
+
+    report.skipped = discovered.skipped;
+
+    Ok(report)
+}
+
+/// Warm the cache, and record who did it and how it went
+async fn warm_and_audit(
+    state: &AppState,
+    actor: &audit::Actor,
+    source: &WarmSource,
+    progress: Progress<'_>,
+) -> miette::Result<WarmReport> {
+    let report = warm(state, source, progress).await?;
+
+    audit::record(
+        state,
+        actor,
+        "warm",
+        json!({
+            "source": source.kind(),
+            "report": report,
+        }),
+    )
+    .await?;
+
+    Ok(report)
+}
+
+/// A warm running in the background on this node, or one that finished recently
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct WarmJob {
+    pub id: u64,
+    pub source: &'static str,
+    pub started_by: String,
+    #[serde(skip)]
+    pub started_at: NaiveDateTime,
+    pub finished: bool,
+    /// How far it has got, or how it went once it's finished
+    pub report: WarmReport,
+    pub error: Option<String>,
+}
+
+/// The warms this node is running, and the last few it finished
+///
+/// Warming fills this node's cache, so each node only knows about its own warms
+#[derive(Debug, Default)]
+pub(crate) struct WarmJobs {
+    jobs: Mutex<BTreeMap<u64, WarmJob>>,
+}
+
+impl WarmJobs {
+    fn insert(&self, source: &WarmSource, actor: &audit::Actor) -> u64 {
+        let mut jobs = self.jobs.lock().unwrap();
+        let id = jobs.last_key_value().map_or(1, |(id, _)| id + 1);
+
+        jobs.insert(
+            id,
+            WarmJob {
+                id,
+                source: source.kind(),
+                started_by: actor.name.clone(),
+                started_at: Utc::now().naive_utc(),
+                finished: false,
+                report: WarmReport::default(),
+                error: None,
+            },
+        );
+
+        id
+    }
+
+    fn update(&self, id: u64, f: impl FnOnce(&mut WarmJob)) {
+        let mut jobs = self.jobs.lock().unwrap();
+        if let Some(job) = jobs.get_mut(&id) {
+            f(job);
+        }
+
+        let finished = jobs.values().filter(|job| job.finished).count();
+        let forget = finished.saturating_sub(KEEP_FINISHED_JOBS);
+        let old = jobs
+            .values()
+            .filter(|job| job.finished)
+            .take(forget)
+            .map(|job| job.id)
+            .collect::<Vec<_>>();
+        for id in old {
+            jobs.remove(&id);
+        }
+    }
+
+    pub(crate) fn get(&self, id: u64) -> Option<WarmJob> {
+        self.jobs.lock().unwrap().get(&id).cloned()
+    }
+
+    /// Newest first
+    pub(crate) fn recent(&self) -> Vec<WarmJob> {
+        self.jobs.lock().unwrap().values().rev().cloned().collect()
+    }
+}
+
+/// Start warming the cache in the background, returning the id of the job to follow it with
+///
+/// Big sitemaps and crawls take far longer than anyone should wait on one request
+pub(crate) fn start(
+    state: &AppState,
+    actor: audit::Actor,
+    source: WarmSource,
+) -> miette::Result<u64> {
+    source.validate()?;
+
+    let id = state.warm_jobs.insert(&source, &actor);
+    let state = state.clone();
+    tokio::spawn(async move {
+        let progress = |report: &WarmReport| {
+            state
+                .warm_jobs
+                .update(id, |job| job.report = report.clone());
+        };
+        let result = warm_and_audit(&state, &actor, &source, &progress).await;
+
+        if let Err(e) = &result {
+            tracing::warn!(error = ?e, id, "Warm failed");
+        }
+        state.warm_jobs.update(id, |job| {
+            job.finished = true;
+            match result {
+                Ok(report) => job.report = report,
+                Err(e) => job.error = Some(e.to_string()),
+            }
+        });
+    });
+
+    Ok(id)
+}
+
+fn job_status(job: &WarmJob) -> &'static str {
+    match (job.finished, &job.error) {
+        (false, _) => "Running",
+        (true, None) => "Done",
+        (true, Some(_)) => "Failed",
+    }
+}
+
+pub(crate) async fn index(
+    State(state): State<AppState>,
+    session: DBSession<Operator>,
+) -> impl IntoResponse {
+    let jobs = state.warm_jobs.recent();
+
+    html! {
+        @if !jobs.is_empty() {
+            h2 { "Recent Warms On This Node" }
+            table {
+                tr {
+                    th { "Started" }
+                    th { "By" }
+                    th { "Source" }
+                    th { "Status" }
+                    th { "Done" }
+                }
+                @for job in &jobs {
+                    tr {
+                        td { a href={ "/_caje/warm/job?id=" (job.id) } { (job.started_at) } }
+                        td { (job.started_by) }
+                        td { (job.source) }
+                        td { (job_status(job)) }
+                        td { (job.report.done()) " of " (job.report.discovered) }
+                    }
+                }
+            }
+        }
+
+        h2 { "Warm From A Sitemap" }
+        form method="post" action="/_caje/warm" {
+            (session.csrf_input())
+            input type="hidden" name="source" value="sitemap";
+            input type="text" name="sitemap" value="/sitemap.xml";
+            input type="submit" value="Warm";
+        }
+
+        h2 { "Warm A List Of URLs" }
+        form method="post" action="/_caje/warm" {
+            (session.csrf_input())
+            input type="hidden" name="source" value="urls";
+            textarea name="urls" rows="10" cols="60" placeholder="One URL or path per line" {}
+            input type="submit" value="Warm";
+        }
+
+        h2 { "Warm By Crawling" }
+        form method="post" action="/_caje/warm" {
+            (session.csrf_input())
+            input type="hidden" name="source" value="crawl";
+            input type="text" name="start" value="/";
+            label {
+                "Depth "
+                input type="number" name="depth" value="2" min="0" max=(MAX_CRAWL_DEPTH);
+            }
+            input type="submit" value="Warm";
+        }
+
+        p { "Only URLs on " (PROXY_FROM_DOMAIN) " are warmed, and at most " (MAX_WARM_URLS) " of them" }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum SourceKind {
+    Sitemap,
+    Urls,
+    Crawl,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct WarmForm {
+    source: SourceKind,
+    #[serde(default)]
+    sitemap: String,
+    #[serde(default)]
+    urls: String,
+    #[serde(default)]
+    start: String,
+    depth: Option<usize>,
+}
+
+impl WarmForm {
+    fn source(self) -> WarmSource {
+        match self.source {
+            SourceKind::Sitemap => WarmSource::Sitemap(self.sitemap),
+            SourceKind::Urls => WarmSource::Urls(url_list(&self.urls)),
+            SourceKind::Crawl => WarmSource::Crawl {
+                start: self.start,
+                depth: self.depth.unwrap_or_default(),
+            },
+        }
+    }
+}
+
+pub(crate) async fn create(
+    State(state): State<AppState>,
+    CsrfForm { session, form }: CsrfForm<WarmForm, Operator>,
+) -> Result<impl IntoResponse, WrappedError> {
+    let id = start(&state, session.actor(), form.source())?;
+
+    Ok(Redirect::to(&format!("/_caje/warm/job?id={id}")))
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct JobQuery {
+    id: u64,
+}
+
+pub(crate) async fn job(
+    State(state): State<AppState>,
+    _session: DBSession<Operator>,
+    Query(query): Query<JobQuery>,
+) -> impl IntoResponse {
+    let Some(job) = state.warm_jobs.get(query.id) else {
+        return (
+            StatusCode::NOT_FOUND,
+            html! {
+                p { "This node doesn't know about that warm, it may have restarted since" }
+                a href="/_caje/warm" { "Warm more" }
+            },
+        );
+    };
+    let report = &job.report;
+
+    let resp = html! {
+        @if !job.finished {
+            meta http-equiv="refresh" content="2";
+        }
+        h2 { "Warm " (job.id) ": " (job_status(&job)) }
+        p { "Warming from " (job.source) ", started by " (job.started_by) " at " (job.started_at) }
+        @if let Some(error) = &job.error {
+            p { "Error: " (error) }
+        }
+        table {
+            tr { th { "Discovered" } td { (report.discovered) } }
+            tr { th { "Cached" } td { (report.cached) } }
+            tr { th { "Uncacheable" } td { (report.uncacheable) } }
+            tr { th { "Failed" } td { (report.failed) } }
+            tr { th { "Skipped (other hosts)" } td { (report.skipped) } }
+        }
+        a href="/_caje/warm" { "Warm more" }
+    };
+
+    (StatusCode::OK, resp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_sitemaps_and_links() {
+        let sitemap = r#"<?xml version="1.0" encoding="UTF-8"?>
+            <sm:urlset xmlns:sm="http://www.sitemaps.org/schemas/sitemap/0.9">
+                <sm:url><sm:loc> https://slow.coreyja.com/a?x=1&amp;y=2 </sm:loc></sm:url>
+                <sm:url><sm:loc><![CDATA[https://slow.coreyja.com/b?x=1&y=2]]></sm:loc></sm:url>
+            </sm:urlset>"#;
+        assert_eq!(
+            parse_sitemap(sitemap).unwrap(),
+            Sitemap {
+                index: false,
+                locs: vec![
+                    "https://slow.coreyja.com/a?x=1&y=2".to_string(),
+                    "https://slow.coreyja.com/b?x=1&y=2".to_string(),
+                ]
+            }
+        );
+
+        let index = r#"<sitemapindex xmlns="http://www.sitemaps.org/schemas/sitemap/0.9">
+            <sitemap><loc>/sitemap-1.xml</loc></sitemap></sitemapindex>"#;
+        assert!(parse_sitemap(index).unwrap().index);
+        assert!(parse_sitemap("<urlset><loc>/a</urlset>").is_err());
+
+        let html = r#"<a href="/about?x=1&amp;y=2">About</a> <a href='team#top'>Team</a>
+            <A HREF=/unquoted>Loud</A> <!-- <a href="/commented"> -->"#;
+        assert_eq!(
+            html_links(html),
+            vec!["/about?x=1&y=2", "team#top", "/unquoted"]
+        );
+
+        assert_eq!(url_list("/a\n\n# comment\n  /b  \n"), vec!["/a", "/b"]);
+    }
+
+    #[test]
+    fn only_warms_our_host_once() {
+        let base = Url::parse("https://slow.coreyja.com/products/").unwrap();
+        let mut discovered = Discovered::default();
+
+        assert_eq!(
+            discovered.add(&base, "1#reviews"),
+            Some("/products/1".to_string())
+        );
+        assert_eq!(discovered.add(&base, "/products/1"), None);
+        assert_eq!(
+            discovered.add(&base, "https://slow-server.fly.dev/about?x=1"),
+            Some("/about?x=1".to_string())
+        );
+        assert_eq!(discovered.add(&base, "https://example.com/"), None);
+        assert_eq!(discovered.add(&base, "mailto:hi@example.com"), None);
+        assert_eq!(discovered.skipped, 2);
+    }
+
+    #[test]
+    fn sources_have_to_be_on_our_host() {
+        assert!(WarmSource::Sitemap("/sitemap.xml".to_string())
+            .validate()
+            .is_ok());
+        assert!(
+            WarmSource::Sitemap("https://example.com/sitemap.xml".to_string())
+                .validate()
+                .is_err()
+        );
+        assert!(WarmSource::Crawl {
+            start: "mailto:hi@example.com".to_string(),
+            depth: 1
+        }
+        .validate()
+        .is_err());
+    }
+
+    #[test]
+    fn forgets_old_finished_jobs() {
+        let jobs = WarmJobs::default();
+        let actor = audit::Actor {
+            admin_user_id: None,
+            name: "alice".to_string(),
+        };
+        let source = WarmSource::Urls(vec![]);
+
+        let running = jobs.insert(&source, &actor);
+        for _ in 0..KEEP_FINISHED_JOBS + 1 {
+            let id = jobs.insert(&source, &actor);
+            jobs.update(id, |job| job.finished = true);
+        }
+
+        assert!(jobs.get(running).is_some());
+        assert!(jobs.get(running + 1).is_none());
+        assert_eq!(jobs.recent().len(), KEEP_FINISHED_JOBS + 1);
+        assert_eq!(jobs.recent()[0].id, running + KEEP_FINISHED_JOBS as u64 + 1);
+    }
+}
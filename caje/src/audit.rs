@@ -0,0 +1,42 @@
+use miette::{IntoDiagnostic, Result};
+use serde_json::Value;
+
+use crate::{node::node_name, AppState};
+
+/// Who did something worth auditing
+#[derive(Debug, Clone)]
+pub(crate) struct Actor {
+    pub admin_user_id: Option<i64>,
+    pub name: String,
+}
+
+/// Record `action` in the audit log, which is shared by every node
+pub(crate) async fn record(
+    state: &AppState,
+    actor: &Actor,
+    action: &str,
+    details: Value,
+) -> Result<()> {
+    let node = node_name();
+    let details = details.to_string();
+
+    state
+        .db_writer
+        .write(|| async {
+            sqlx::query!(
+                "INSERT INTO AuditLog (admin_user_id, actor, node, action, details)
+                VALUES (?, ?, ?, ?, ?)",
+                actor.admin_user_id,
+                actor.name,
+                node,
+                action,
+                details
+            )
+            .execute(&state.db_pool)
+            .await
+            .into_diagnostic()?;
+
+            Ok(())
+        })
+        .await
+}
@@ -0,0 +1,91 @@
+use http::HeaderValue;
+
+/// The header we say how we answered each proxied request in, from RFC 9211
+pub(crate) const CACHE_STATUS: &str = "cache-status";
+
+/// How we answered a proxied request
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum CacheStatus {
+    /// Fresh from our cache, without asking the origin
+    Hit,
+    /// From our cache after it expired, because the origin failed and allows `stale-if-error`
+    Stale,
+    /// From our cache after the origin said it hadn't changed
+    Revalidated,
+    /// From the origin, and we cached it for next time
+    Stored,
+    /// From the origin, and we weren't allowed to cache it
+    Miss,
+}
+
+impl CacheStatus {
+    pub(crate) const ALL: [CacheStatus; 5] = [
+        CacheStatus::Hit,
+        CacheStatus::Stale,
+        CacheStatus::Revalidated,
+        CacheStatus::Stored,
+        CacheStatus::Miss,
+    ];
+
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            CacheStatus::Hit => "caje; hit",
+            CacheStatus::Stale => "caje; hit; detail=stale-if-error",
+            CacheStatus::Revalidated => "caje; fwd=stale; fwd-status=304",
+            CacheStatus::Stored => "caje; fwd=miss; stored",
+            CacheStatus::Miss => "caje; fwd=miss",
+        }
+    }
+
+    pub(crate) fn label(&self) -> &'static str {
+        match self {
+            CacheStatus::Hit => "hit",
+            CacheStatus::Stale => "stale",
+            CacheStatus::Revalidated => "revalidated",
+            CacheStatus::Stored => "stored",
+            CacheStatus::Miss => "miss",
+        }
+    }
+
+    /// Whether the body came out of our cache
+    pub(crate) fn is_hit(&self) -> bool {
+        matches!(
+            self,
+            CacheStatus::Hit | CacheStatus::Stale | CacheStatus::Revalidated
+        )
+    }
+
+    pub(crate) fn header_value(&self) -> HeaderValue {
+        HeaderValue::from_static(self.as_str())
+    }
+
+    /// Read our entry back out of a `Cache-Status` header, ignoring any other caches in it
+    pub(crate) fn parse(header: &str) -> Option<CacheStatus> {
+        let ours = header
+            .split(',')
+            .map(str::trim)
+            .find(|entry| entry.starts_with("caje;") || *entry == "caje")?;
+
+        CacheStatus::ALL
+            .into_iter()
+            .find(|status| status.as_str() == ours)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cache_statuses_round_trip() {
+        for status in CacheStatus::ALL {
+            assert_eq!(CacheStatus::parse(status.as_str()), Some(status));
+        }
+
+        assert_eq!(
+            CacheStatus::parse("other; fwd=miss, caje; hit"),
+            Some(CacheStatus::Hit)
+        );
+        assert_eq!(CacheStatus::parse("other; hit"), None);
+    }
+}
@@ -0,0 +1,58 @@
+use std::{convert::Infallible, net::IpAddr, net::SocketAddr};
+
+use async_trait::async_trait;
+use axum::extract::{ConnectInfo, FromRequestParts};
+use http::request::Parts;
+use miette::miette;
+
+use crate::AppState;
+
+/// The header Fly's proxy puts the real client IP in
+const FLY_CLIENT_IP: &str = "Fly-Client-IP";
+
+/// Whether to believe the `Fly-Client-IP` header
+///
+/// Set `TRUST_FLY_CLIENT_IP` to `true` or `false`, it defaults to whether we're running on Fly.
+/// Anywhere else nothing strips the header, so anyone could send it to pretend to be someone else
+pub(crate) fn trust_fly_client_ip_from_env() -> miette::Result<bool> {
+    match std::env::var("TRUST_FLY_CLIENT_IP") {
+        Ok(value) => value
+            .trim()
+            .parse()
+            .map_err(|_| miette!("TRUST_FLY_CLIENT_IP should be true or false, not {value}")),
+        Err(_) => Ok(std::env::var("FLY_APP_NAME").is_ok()),
+    }
+}
+
+/// The IP address of whoever made the request, if we can tell
+///
+/// On Fly every connection comes from their proxy, so we trust the `Fly-Client-IP` header it
+/// adds and only fall back to the address of the connection itself
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ClientIp(pub Option<IpAddr>);
+
+#[async_trait]
+impl FromRequestParts<AppState> for ClientIp {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        state: &AppState,
+    ) -> Result<Self, Self::Rejection> {
+        let from_header = parts
+            .headers
+            .get(FLY_CLIENT_IP)
+            .filter(|_| state.trust_fly_client_ip)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.trim().parse().ok());
+
+        let from_connection = || {
+            parts
+                .extensions
+                .get::<ConnectInfo<SocketAddr>>()
+                .map(|ConnectInfo(addr)| addr.ip())
+        };
+
+        Ok(ClientIp(from_header.or_else(from_connection)))
+    }
+}
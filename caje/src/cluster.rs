@@ -0,0 +1,186 @@
+use std::time::Duration;
+
+use cacache::Metadata;
+use chrono::{NaiveDateTime, Utc};
+use miette::{IntoDiagnostic, Result};
+use tokio::task::JoinHandle;
+use tracing::error;
+
+use crate::{
+    node::{node_name, node_region},
+    AppState, CACHE_DIR,
+};
+
+/// How often each node writes its heartbeat
+///
+/// On a replica every write HALTs the primary, so this shouldn't be too often
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// A heartbeat this old means the node has missed a few beats, and is probably gone
+pub(crate) const HEARTBEAT_STALE_AFTER: Duration = Duration::from_secs(3 * 30);
+
+/// Nodes that haven't sent a heartbeat for this long are forgotten
+const HEARTBEAT_RETENTION: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// The last status a node published for the rest of the cluster
+#[derive(Debug, Clone)]
+pub(crate) struct Heartbeat {
+    pub node: String,
+    pub region: Option<String>,
+    pub version: String,
+    pub is_primary: bool,
+    pub cache_size: i64,
+    pub entry_count: i64,
+    pub lag_ms: Option<i64>,
+    pub last_populate_at: Option<NaiveDateTime>,
+    pub started_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+impl Heartbeat {
+    /// Whether the node has stopped sending heartbeats
+    pub(crate) fn is_stale(&self, now: NaiveDateTime) -> bool {
+        chrono::Duration::from_std(HEARTBEAT_STALE_AFTER)
+            .is_ok_and(|stale_after| now - self.updated_at > stale_after)
+    }
+
+    /// Every node we've heard from recently, sorted by name
+    pub(crate) async fn list(state: &AppState) -> Result<Vec<Heartbeat>> {
+        sqlx::query_as!(
+            Heartbeat,
+            r#"SELECT node, region, version, is_primary, cache_size, entry_count, lag_ms,
+                last_populate_at, started_at, updated_at
+            FROM NodeHeartbeats
+            ORDER BY node"#
+        )
+        .fetch_all(&state.db_pool)
+        .await
+        .into_diagnostic()
+    }
+}
+
+/// How big this node's cache is, in bytes and entries
+async fn cache_usage() -> Result<(i64, i64)> {
+    let entries: Result<Vec<Metadata>, _> =
+        tokio::task::spawn_blocking(|| cacache::list_sync(CACHE_DIR).collect())
+            .await
+            .into_diagnostic()?;
+    let entries = entries.unwrap_or_default();
+
+    let size = entries.iter().map(|entry| entry.size as i64).sum();
+
+    Ok((size, entries.len() as i64))
+}
+
+/// Publish this node's status to the shared database
+async fn beat(state: &AppState, started_at: NaiveDateTime) -> Result<()> {
+    let now = Utc::now().naive_utc();
+    let node = node_name();
+    let region = node_region();
+    let version = env!("CARGO_PKG_VERSION");
+    let is_primary = state.db_writer.is_primary()?;
+    let (cache_size, entry_count) = cache_usage().await?;
+    let lag_ms = state
+        .lag_watcher
+        .as_ref()
+        .and_then(|lag_watcher| lag_watcher.current())
+        .map(|lag| i64::try_from(lag.as_millis()).unwrap_or(i64::MAX));
+    let last_populate_at = sqlx::query_scalar!(
+        r#"SELECT MAX(created_at) as "created_at: NaiveDateTime"
+        FROM AuditLog
+        WHERE action = 'populate' AND node = ?"#,
+        node
+    )
+    .fetch_one(&state.db_pool)
+    .await
+    .into_diagnostic()?;
+    let forget_before = now - chrono::Duration::from_std(HEARTBEAT_RETENTION).into_diagnostic()?;
+
+    state
+        .db_writer
+        .write(|| async {
+            let mut tx = state.db_pool.begin().await.into_diagnostic()?;
+
+            sqlx::query!(
+                "INSERT INTO NodeHeartbeats (node, region, version, is_primary, cache_size,
+                    entry_count, lag_ms, last_populate_at, started_at, updated_at)
+                VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+                ON CONFLICT (node) DO UPDATE SET
+                    region = excluded.region,
+                    version = excluded.version,
+                    is_primary = excluded.is_primary,
+                    cache_size = excluded.cache_size,
+                    entry_count = excluded.entry_count,
+                    lag_ms = excluded.lag_ms,
+                    last_populate_at = excluded.last_populate_at,
+                    started_at = excluded.started_at,
+                    updated_at = excluded.updated_at",
+                node,
+                region,
+                version,
+                is_primary,
+                cache_size,
+                entry_count,
+                lag_ms,
+                last_populate_at,
+                started_at,
+                now
+            )
+            .execute(&mut *tx)
+            .await
+            .into_diagnostic()?;
+
+            sqlx::query!(
+                "DELETE FROM NodeHeartbeats WHERE updated_at < ?",
+                forget_before
+            )
+            .execute(&mut *tx)
+            .await
+            .into_diagnostic()?;
+
+            tx.commit().await.into_diagnostic()
+        })
+        .await
+}
+
+/// Periodically publish this node's heartbeat
+pub(crate) fn spawn_worker(state: AppState) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let started_at = Utc::now().naive_utc();
+        let mut interval = tokio::time::interval(HEARTBEAT_INTERVAL);
+
+        loop {
+            interval.tick().await;
+
+            if let Err(e) = beat(&state, started_at).await {
+                error!(error = ?e, "Failed to send heartbeat");
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn heartbeats_go_stale() {
+        let now = Utc::now().naive_utc();
+        let mut heartbeat = Heartbeat {
+            node: "abc".to_string(),
+            region: Some("lhr".to_string()),
+            version: "0.1.0".to_string(),
+            is_primary: false,
+            cache_size: 0,
+            entry_count: 0,
+            lag_ms: None,
+            last_populate_at: None,
+            started_at: now,
+            updated_at: now - chrono::Duration::seconds(30),
+        };
+        assert!(!heartbeat.is_stale(now));
+
+        heartbeat.updated_at = now - chrono::Duration::seconds(91);
+        assert!(heartbeat.is_stale(now));
+    }
+}
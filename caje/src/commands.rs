@@ -0,0 +1,295 @@
+use std::time::Duration;
+
+use chrono::{NaiveDateTime, Utc};
+use miette::{IntoDiagnostic, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sqlx::query_as;
+use tokio::task::JoinHandle;
+use tracing::{error, info};
+
+use crate::{
+    admin::{clear_fs::clear_fs, populate::populate},
+    audit::{self, Actor},
+    node::node_name,
+    AppState,
+};
+
+/// How often each node checks the queue for commands it hasn't run yet
+const COMMAND_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Commands older than this are never run by nodes that missed them
+///
+/// A node that was down for longer than this comes back with a different cache anyway
+const COMMAND_WINDOW: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, sqlx::Type)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(rename_all = "snake_case")]
+pub(crate) enum CommandAction {
+    /// Remove every response from the node's cache
+    ClearFs,
+    /// Fetch the manifest into the node's cache
+    Populate,
+}
+
+impl CommandAction {
+    pub(crate) const ALL: [CommandAction; 2] = [CommandAction::ClearFs, CommandAction::Populate];
+
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            CommandAction::ClearFs => "clear_fs",
+            CommandAction::Populate => "populate",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Command {
+    pub id: i64,
+    pub action: CommandAction,
+    /// `None` runs on every node
+    pub target_node: Option<String>,
+    pub requested_by_id: Option<i64>,
+    pub requested_by: String,
+    pub created_at: NaiveDateTime,
+}
+
+impl Command {
+    /// Whether `node` should run this command
+    pub(crate) fn targets(&self, node: &str) -> bool {
+        match &self.target_node {
+            Some(target) => target == node,
+            None => true,
+        }
+    }
+
+    /// Do what the command asks to this node, and let everyone know how it went
+    async fn run(&self, state: &AppState) -> Result<()> {
+        let actor = Actor {
+            admin_user_id: self.requested_by_id,
+            name: self.requested_by.clone(),
+        };
+
+        // Claim it first, so a command is never run twice even if recording how it went fails.
+        // A node that dies partway through leaves it claimed, and the dashboard shows it running
+        let node = node_name();
+        let claimed = state
+            .db_writer
+            .write(|| async {
+                sqlx::query!(
+                    "INSERT INTO NodeCommandResults (command_id, node)
+                    VALUES (?, ?)
+                    ON CONFLICT (command_id, node) DO NOTHING",
+                    self.id,
+                    node
+                )
+                .execute(&state.db_pool)
+                .await
+                .into_diagnostic()
+            })
+            .await?;
+        if claimed.rows_affected() == 0 {
+            return Ok(());
+        }
+
+        let outcome = match self.action {
+            CommandAction::ClearFs => clear_fs(state).await.map(|_| json!({})),
+            CommandAction::Populate => populate(state)
+                .await
+                .and_then(|report| serde_json::to_value(report).into_diagnostic()),
+        };
+
+        let (succeeded, result) = match &outcome {
+            Ok(details) => (true, details.to_string()),
+            Err(e) => (false, json!({ "error": e.to_string() }).to_string()),
+        };
+
+        let recorded = state
+            .db_writer
+            .write(|| async {
+                sqlx::query!(
+                    "UPDATE NodeCommandResults
+                    SET succeeded = ?, result = ?, completed_at = CURRENT_TIMESTAMP
+                    WHERE command_id = ? AND node = ?",
+                    succeeded,
+                    result,
+                    self.id,
+                    node
+                )
+                .execute(&state.db_pool)
+                .await
+                .into_diagnostic()
+            })
+            .await;
+        if let Err(e) = recorded {
+            error!(command_id = self.id, error = ?e, "Could not record command result");
+        }
+
+        match outcome {
+            Ok(details) => {
+                info!(
+                    command_id = self.id,
+                    action = self.action.as_str(),
+                    "Ran command"
+                );
+
+                let audited = audit::record(
+                    state,
+                    &actor,
+                    self.action.as_str(),
+                    with_command_id(details, self.id),
+                )
+                .await;
+                if let Err(e) = audited {
+                    error!(command_id = self.id, error = ?e, "Could not audit command");
+                }
+            }
+            Err(e) => error!(command_id = self.id, error = ?e, "Command failed"),
+        }
+
+        Ok(())
+    }
+}
+
+/// So the audit log entry on the node that ran it points back at the command
+fn with_command_id(details: Value, command_id: i64) -> Value {
+    match details {
+        Value::Object(mut details) => {
+            details.insert("command_id".to_string(), command_id.into());
+            Value::Object(details)
+        }
+        other => json!({ "command_id": command_id, "result": other }),
+    }
+}
+
+/// Queue `action` for `target_node`, or every node when it's `None`
+///
+/// Every targeted node runs it the next time its worker checks the queue, including this one
+pub(crate) async fn enqueue(
+    state: &AppState,
+    actor: &Actor,
+    action: CommandAction,
+    target_node: Option<&str>,
+) -> Result<Command> {
+    let target_node = target_node.map(str::trim).filter(|node| !node.is_empty());
+    let now = Utc::now().naive_utc();
+
+    let command = state
+        .db_writer
+        .write(|| async {
+            query_as!(
+                Command,
+                r#"INSERT INTO NodeCommands (action, target_node, requested_by_id, requested_by, created_at)
+                VALUES (?, ?, ?, ?, ?)
+                RETURNING id, action as "action: CommandAction", target_node, requested_by_id,
+                    requested_by, created_at"#,
+                action,
+                target_node,
+                actor.admin_user_id,
+                actor.name,
+                now
+            )
+            .fetch_one(&state.db_pool)
+            .await
+            .into_diagnostic()
+        })
+        .await?;
+
+    audit::record(
+        state,
+        actor,
+        "command_queued",
+        json!({
+            "id": command.id,
+            "action": action,
+            "target_node": target_node,
+        }),
+    )
+    .await?;
+
+    Ok(command)
+}
+
+/// Run every recent command for this node that it hasn't run yet, oldest first
+async fn run_pending(state: &AppState) -> Result<()> {
+    let node = node_name();
+    let since =
+        Utc::now().naive_utc() - chrono::Duration::from_std(COMMAND_WINDOW).into_diagnostic()?;
+
+    let pending = query_as!(
+        Command,
+        r#"SELECT id, action as "action: CommandAction", target_node, requested_by_id,
+            requested_by, created_at
+        FROM NodeCommands
+        WHERE created_at > ?
+            AND (target_node IS NULL OR target_node = ?)
+            AND id NOT IN (SELECT command_id FROM NodeCommandResults WHERE node = ?)
+        ORDER BY id"#,
+        since,
+        node,
+        node
+    )
+    .fetch_all(&state.db_pool)
+    .await
+    .into_diagnostic()?;
+
+    // One command we can't claim shouldn't hold up the rest
+    for command in pending {
+        if let Err(e) = command.run(state).await {
+            error!(command_id = command.id, error = ?e, "Could not claim command");
+        }
+    }
+
+    Ok(())
+}
+
+/// Run the commands queued for this node from any node's dashboard
+pub(crate) fn spawn_worker(state: AppState) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(COMMAND_POLL_INTERVAL);
+
+        loop {
+            interval.tick().await;
+
+            if let Err(e) = run_pending(&state).await {
+                error!(error = ?e, "Failed to run queued commands");
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(target_node: Option<&str>) -> Command {
+        Command {
+            id: 1,
+            action: CommandAction::Populate,
+            target_node: target_node.map(str::to_string),
+            requested_by_id: None,
+            requested_by: "alice".to_string(),
+            created_at: Utc::now().naive_utc(),
+        }
+    }
+
+    #[test]
+    fn commands_target_one_node_or_all() {
+        assert!(command(None).targets("abc"));
+        assert!(command(Some("abc")).targets("abc"));
+        assert!(!command(Some("abc")).targets("def"));
+    }
+
+    #[test]
+    fn audit_details_point_at_the_command() {
+        assert_eq!(
+            with_command_id(json!({ "populated": 2 }), 7),
+            json!({ "populated": 2, "command_id": 7 })
+        );
+        assert_eq!(
+            with_command_id(Value::Null, 7),
+            json!({ "command_id": 7, "result": null })
+        );
+    }
+}
@@ -0,0 +1,153 @@
+use std::{fs::File, future::Future, sync::Arc, time::Duration};
+
+use litefs_rs::Position;
+use miette::{IntoDiagnostic, Result};
+use tokio::sync::Mutex;
+use tracing::{info, warn};
+
+/// How long a replica waits on the primary for the LiteFS HALT lock before giving up
+const LITEFS_HALT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long we wait for our own write to be visible on this replica after unhalting
+const LITEFS_POSITION_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How writes get into the shared database
+///
+/// Replicas can't write to a LiteFS database directly, so they HALT the primary around each
+/// write. Anything that needs to be seen by every node should write through this
+#[derive(Debug, Clone)]
+pub(crate) enum DbWriter {
+    /// A plain SQLite database, for running a single node without LiteFS
+    Direct,
+    /// A SQLite database replicated by LiteFS
+    LiteFs {
+        database_path: String,
+        /// Held for the whole of each HALT, since concurrent writers in one process would each
+        /// take their own lock and wait on each other
+        halt_lock: Arc<Mutex<()>>,
+    },
+}
+
+impl DbWriter {
+    pub(crate) fn litefs(database_path: String) -> Self {
+        DbWriter::LiteFs {
+            database_path,
+            halt_lock: Default::default(),
+        }
+    }
+
+    pub(crate) async fn write<T, Fut>(&self, write: impl FnOnce() -> Fut) -> Result<T>
+    where
+        Fut: Future<Output = Result<T>>,
+    {
+        match self {
+            DbWriter::Direct => write().await,
+            DbWriter::LiteFs {
+                database_path,
+                halt_lock,
+            } => halted(database_path, halt_lock, write).await,
+        }
+    }
+
+    /// Whether writes go straight to the database without a HALT
+    pub(crate) fn is_primary(&self) -> Result<bool> {
+        match self {
+            DbWriter::Direct => Ok(true),
+            DbWriter::LiteFs { database_path, .. } => {
+                litefs_rs::is_primary(database_path).into_diagnostic()
+            }
+        }
+    }
+}
+
+#[tracing::instrument(name = "litefs_halt", skip_all)]
+async fn halted<T, Fut>(
+    database_path: &str,
+    halt_lock: &Mutex<()>,
+    write: impl FnOnce() -> Fut,
+) -> Result<T>
+where
+    Fut: Future<Output = Result<T>>,
+{
+    if litefs_rs::is_primary(database_path).into_diagnostic()? {
+        info!("We are the primary, no need to halt");
+
+        return write().await;
+    }
+
+    let halt_guard = halt_lock.lock().await;
+
+    let (lockfile, before) = blocking(database_path, halt).await?;
+    info!(?before, "Halted database");
+
+    let result = write().await;
+
+    // A write that didn't change anything never moves the position, so there's nothing to
+    // wait for
+    let (lockfile, after) = blocking(database_path, move |database_path| {
+        let after = litefs_rs::position(database_path).into_diagnostic();
+        Ok((lockfile, after))
+    })
+    .await?;
+    blocking(database_path, move |_| unhalt(lockfile)).await?;
+    drop(halt_guard);
+
+    match after {
+        Ok(after) if result.is_ok() && after.txid > before.txid => {
+            // Our write goes through the primary, so make sure it has made it back to this
+            // replica before we move on
+            let caught_up = blocking(database_path, move |database_path| {
+                litefs_rs::wait_for_position(database_path, after.txid, LITEFS_POSITION_TIMEOUT)
+                    .into_diagnostic()
+            })
+            .await;
+            match caught_up {
+                Ok(position) => info!(?position, "Caught up to our write"),
+                Err(e) => warn!(error = ?e, "Our write hasn't shown up on this replica"),
+            }
+        }
+        Ok(_) => info!("Nothing written, not waiting on the position"),
+        Err(e) => warn!(error = ?e, "Could not read our position after writing"),
+    }
+
+    result
+}
+
+/// Run `f` where it can sleep without holding up the other tasks on this worker
+async fn blocking<T: Send + 'static>(
+    database_path: &str,
+    f: impl FnOnce(&str) -> Result<T> + Send + 'static,
+) -> Result<T> {
+    let database_path = database_path.to_string();
+
+    tokio::task::spawn_blocking(move || f(&database_path))
+        .await
+        .into_diagnostic()?
+}
+
+/// Take the HALT lock, returning it with the position this replica was at before our write
+fn halt(database_path: &str) -> Result<(File, Position)> {
+    let lockfile = litefs_rs::lockfile(database_path).into_diagnostic()?;
+    let lag = litefs_rs::lag(database_path).into_diagnostic()?;
+    info!(?lag, "Got lag from Primary");
+
+    litefs_rs::halt_with_timeout(&lockfile, LITEFS_HALT_TIMEOUT).into_diagnostic()?;
+
+    // Nobody else can write while we hold HALT, so anything past this is ours
+    let before = match litefs_rs::position(database_path) {
+        Ok(before) => before,
+        Err(e) => {
+            litefs_rs::unhalt(&lockfile).into_diagnostic()?;
+            return Err(e).into_diagnostic();
+        }
+    };
+
+    Ok((lockfile, before))
+}
+
+fn unhalt(lockfile: File) -> Result<()> {
+    litefs_rs::unhalt(&lockfile).into_diagnostic()?;
+    info!("Unhalted database");
+
+    Ok(())
+}
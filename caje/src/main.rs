@@ -1,4 +1,11 @@
-use std::{fmt::Display, fs::OpenOptions, net::SocketAddr, time::SystemTime};
+use std::{
+    fmt::Display,
+    fs::OpenOptions,
+    io::Write,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
 
 use axum::{
     body::{Body, Bytes},
@@ -7,29 +14,73 @@ use axum::{
     RequestExt, Router,
 };
 
+use access_log::{AccessLog, AccessLogConfig, AccessLogEntry};
+use admin::{login_limiter::LoginLimiter, warm::WarmJobs};
 use base64::Engine;
-use cacache::Metadata;
+use cache_status::{CacheStatus, CACHE_STATUS};
+use client_ip::ClientIp;
+use db_writer::DbWriter;
 use debug_ignore::DebugIgnore;
-use http::{uri::PathAndQuery, HeaderMap, Method, Request, Response, StatusCode, Uri, Version};
-use http_cache_semantics::{BeforeRequest, CachePolicy};
+use http::{
+    header::VARY, uri::PathAndQuery, HeaderMap, HeaderValue, Method, Request, Response, StatusCode,
+    Uri, Version,
+};
+use http_cache_semantics::{AfterResponse, BeforeRequest, CachePolicy};
+use litefs_rs::LagWatcher;
+use manifest::{LiteFsManifest, Manifest, SqliteManifest};
 use maud::html;
 use miette::{miette, Context, IntoDiagnostic, Result};
 use serde::{Deserialize, Serialize};
+use server_timing::{ServerTiming, Timings, SERVER_TIMING};
 use sqlx::SqlitePool;
-use tower_cookies::{CookieManagerLayer, Key};
-use tracing::{error, info};
+use stats::Stats;
+use tower_cookies::{CookieManagerLayer, Cookies, Key};
+use tracing::{info, info_span, Instrument, Span};
+use webauthn::RelyingParty;
 
+mod access_log;
 pub mod admin;
+mod audit;
+mod cache_status;
+mod client_ip;
+mod cluster;
+mod commands;
+mod db_writer;
+mod manifest;
+mod node;
+mod purge;
+mod replay;
+mod server_timing;
+mod stale;
+mod stats;
+mod tags;
+mod telemetry;
+mod webauthn;
 
 const PROXY_FROM_DOMAIN: &str = "slow.coreyja.com";
 const PROXY_ORIGIN_DOMAIN: &str = "slow-server.fly.dev";
 
+/// How often we re-read the LiteFS lag in the background
+const LITEFS_LAG_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Replicas further behind the primary than this refuse to populate the cache
+const MAX_POPULATE_LAG: Duration = Duration::from_secs(10);
+
 #[derive(Debug, Clone)]
 struct AppState {
     db_pool: SqlitePool,
-    database_path: Option<String>,
+    db_writer: DbWriter,
+    manifest: Arc<dyn Manifest>,
+    lag_watcher: Option<Arc<LagWatcher>>,
     cookie_key: DebugIgnore<Key>,
     admin_password: String,
+    relying_party: Option<RelyingParty>,
+    login_limiter: Arc<LoginLimiter>,
+    stats: Arc<Stats>,
+    access_log: Option<Arc<AccessLog>>,
+    server_timing: ServerTiming,
+    warm_jobs: Arc<WarmJobs>,
+    trust_fly_client_ip: bool,
 }
 
 impl FromRef<AppState> for SqlitePool {
@@ -38,9 +89,32 @@ impl FromRef<AppState> for SqlitePool {
     }
 }
 
+impl FromRef<AppState> for Arc<dyn Manifest> {
+    fn from_ref(state: &AppState) -> Self {
+        state.manifest.clone()
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
-    tracing_subscriber::fmt::init();
+    telemetry::init()?;
+
+    let result = run().await;
+    telemetry::shutdown().await;
+
+    result
+}
+
+async fn run() -> Result<()> {
+    let mut args = std::env::args().skip(1);
+    if let Some(subcommand) = args.next() {
+        return match subcommand.as_str() {
+            "replay" => replay::run(args).await,
+            _ => Err(miette!(
+                "Unknown subcommand {subcommand}, run without one to serve or use `replay`"
+            )),
+        };
+    }
 
     let database_path = std::env::var("DATABASE_PATH");
     let database_url: String = {
@@ -48,6 +122,7 @@ async fn main() -> Result<()> {
             OpenOptions::new()
                 .write(true)
                 .create(true)
+                .truncate(false)
                 .open(p)
                 .into_diagnostic()?;
 
@@ -65,6 +140,26 @@ async fn main() -> Result<()> {
 
     let database_path = database_path.ok();
 
+    let (db_writer, manifest, lag_watcher): (_, Arc<dyn Manifest>, _) =
+        match (std::env::var("LITEFS"), database_path) {
+            (Ok(_), Some(database_path)) => {
+                let lag_watcher = LagWatcher::spawn(&database_path, LITEFS_LAG_POLL_INTERVAL);
+
+                let db_writer = DbWriter::litefs(database_path);
+
+                (
+                    db_writer.clone(),
+                    Arc::new(LiteFsManifest::new(db_pool.clone(), db_writer)),
+                    Some(Arc::new(lag_watcher)),
+                )
+            }
+            _ => (
+                DbWriter::Direct,
+                Arc::new(SqliteManifest::new(db_pool.clone())),
+                None,
+            ),
+        };
+
     let cookie_key = std::env::var("COOKIE_KEY").into_diagnostic()?;
     let cookie_key = base64::engine::general_purpose::STANDARD
         .decode(cookie_key.as_bytes())
@@ -74,16 +169,109 @@ async fn main() -> Result<()> {
 
     let admin_password = std::env::var("ADMIN_AUTH_KEY").into_diagnostic()?;
 
+    let relying_party = std::env::var("WEBAUTHN_RP_ID").ok().map(|id| RelyingParty {
+        origin: std::env::var("WEBAUTHN_ORIGIN").unwrap_or_else(|_| format!("https://{id}")),
+        id,
+    });
+
+    let access_log = match AccessLogConfig::from_env()? {
+        Some(config) => Some(Arc::new(AccessLog::spawn(config)?)),
+        None => None,
+    };
+
     let app_state = AppState {
         db_pool: db_pool.clone(),
-        database_path,
+        db_writer,
+        manifest,
+        lag_watcher,
         cookie_key,
         admin_password,
+        relying_party,
+        login_limiter: Default::default(),
+        stats: Default::default(),
+        access_log,
+        server_timing: ServerTiming::from_env()?,
+        warm_jobs: Default::default(),
+        trust_fly_client_ip: client_ip::trust_fly_client_ip_from_env()?,
     };
 
+    purge::spawn_worker(app_state.clone());
+    stats::spawn_worker(app_state.clone());
+    cluster::spawn_worker(app_state.clone());
+    commands::spawn_worker(app_state.clone());
+
     let app = Router::new()
         .route("/_caje/auth", axum::routing::get(admin::auth::get))
         .route("/_caje/auth", axum::routing::post(admin::auth::post))
+        .route("/_caje/logout", axum::routing::post(admin::auth::logout))
+        .route(
+            "/_caje/auth/passkey/start",
+            axum::routing::post(admin::passkeys::login_start),
+        )
+        .route(
+            "/_caje/auth/passkey/finish",
+            axum::routing::post(admin::passkeys::login_finish),
+        )
+        .route(
+            "/_caje/passkeys",
+            axum::routing::get(admin::passkeys::index),
+        )
+        .route(
+            "/_caje/passkeys/register/start",
+            axum::routing::post(admin::passkeys::register_start),
+        )
+        .route(
+            "/_caje/passkeys/register/finish",
+            axum::routing::post(admin::passkeys::register_finish),
+        )
+        .route(
+            "/_caje/passkeys/delete",
+            axum::routing::post(admin::passkeys::delete),
+        )
+        .route(
+            "/_caje/users",
+            axum::routing::get(admin::users::index).post(admin::users::create),
+        )
+        .route("/_caje/audit", axum::routing::get(admin::audit_log::index))
+        .route("/_caje/entry", axum::routing::get(admin::entry::index))
+        .route("/_caje/stats", axum::routing::get(admin::stats::index))
+        .route("/_caje/cluster", axum::routing::get(admin::cluster::index))
+        .route(
+            "/_caje/entry/refetch",
+            axum::routing::post(admin::entry::refetch),
+        )
+        .route(
+            "/_caje/users/update",
+            axum::routing::post(admin::users::update),
+        )
+        .route(
+            "/_caje/users/delete",
+            axum::routing::post(admin::users::delete),
+        )
+        .route(
+            "/_caje/sessions",
+            axum::routing::get(admin::sessions::index),
+        )
+        .route(
+            "/_caje/sessions/revoke",
+            axum::routing::post(admin::sessions::revoke),
+        )
+        .route(
+            "/_caje/tokens",
+            axum::routing::get(admin::api_tokens::index).post(admin::api_tokens::create),
+        )
+        .route(
+            "/_caje/tokens/revoke",
+            axum::routing::post(admin::api_tokens::revoke),
+        )
+        .route(
+            "/_caje/purges",
+            axum::routing::get(admin::purges::index).post(admin::purges::create),
+        )
+        .route(
+            "/_caje/commands",
+            axum::routing::get(admin::commands::index).post(admin::commands::create),
+        )
         .route("/_caje/list", axum::routing::get(admin::list::route))
         .route(
             "/_caje/clear_fs",
@@ -97,6 +285,32 @@ async fn main() -> Result<()> {
             "/_caje/populate",
             axum::routing::post(admin::populate::route),
         )
+        .route(
+            "/_caje/warm",
+            axum::routing::get(admin::warm::index).post(admin::warm::create),
+        )
+        .route("/_caje/warm/job", axum::routing::get(admin::warm::job))
+        .route("/_caje/api/v1/list", axum::routing::get(admin::api::list))
+        .route(
+            "/_caje/api/v1/purge",
+            axum::routing::post(admin::api::purge_route),
+        )
+        .route(
+            "/_caje/api/v1/clear_fs",
+            axum::routing::post(admin::api::clear_fs_route),
+        )
+        .route(
+            "/_caje/api/v1/clear_db",
+            axum::routing::post(admin::api::clear_db_route),
+        )
+        .route(
+            "/_caje/api/v1/populate",
+            axum::routing::post(admin::api::populate_route),
+        )
+        .route(
+            "/_caje/api/v1/warm",
+            axum::routing::get(admin::api::warm_job_route).post(admin::api::warm_route),
+        )
         .fallback(proxy_request)
         .layer(CookieManagerLayer::new())
         .with_state(app_state);
@@ -104,18 +318,45 @@ async fn main() -> Result<()> {
     let addr = SocketAddr::from(([0, 0, 0, 0], 3001));
     tracing::debug!("listening on {}", addr);
     axum::Server::bind(&addr)
-        .serve(app.into_make_service())
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(shutdown_signal())
         .await
         .into_diagnostic()?;
 
     Ok(())
 }
 
+/// Resolves when we're asked to stop, on Ctrl+C or the SIGINT or SIGTERM Fly sends on deploys
+async fn shutdown_signal() {
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(e) => {
+                tracing::warn!(error = ?e, "Could not listen for SIGTERM");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {}
+        _ = terminate => {}
+    }
+
+    info!("Shutting down");
+}
+
 // #[axum_macros::debug_handler]
 async fn proxy_request(
     State(app_state): State<AppState>,
+    ClientIp(client_ip): ClientIp,
+    cookies: Cookies,
     mut request: Request<Body>,
 ) -> Result<impl IntoResponse, String> {
+    let started = Instant::now();
+    let timestamp = chrono::Utc::now();
     let host: Host = request
         .extract_parts()
         .await
@@ -130,20 +371,60 @@ async fn proxy_request(
         ));
     }
 
-    let response = get_potentially_cached_response(request, app_state)
+    let method = request.method().to_string();
+    let path = request.uri().to_string();
+    let proxied = get_potentially_cached_response(request, app_state.clone())
         .await
-        .map_err(|e| e.to_string())?;
+        .map_err(|e| e.to_string());
+
+    if let Some(access_log) = &app_state.access_log {
+        let (status, bytes, cache_status, origin_time) = match &proxied {
+            Ok(proxied) => (
+                proxied.response.status(),
+                proxied.response.body().len(),
+                Some(proxied.cache_status),
+                proxied.timings.origin,
+            ),
+            Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, e.len(), None, None),
+        };
 
-    Ok((
-        response.status(),
-        response.headers().clone(),
-        response.into_body(),
-    ))
+        access_log.record(&AccessLogEntry {
+            timestamp,
+            client_ip,
+            host: host.0.clone(),
+            method,
+            path,
+            status: status.as_u16(),
+            bytes,
+            cache_status,
+            origin_time,
+            total_time: started.elapsed(),
+        });
+    }
+
+    let Proxied {
+        cache_status,
+        timings,
+        response,
+    } = proxied?;
+
+    let mut headers = response.headers().clone();
+    tags::strip_tag_headers(&mut headers);
+    headers.insert(CACHE_STATUS, cache_status.header_value());
+    if app_state.server_timing.applies(&app_state, &cookies).await {
+        headers.insert(SERVER_TIMING, timings.header_value());
+        // Keep a shared cache downstream from handing an admin's timings to everyone else
+        if app_state.server_timing.varies_by_cookie() {
+            headers.append(VARY, HeaderValue::from_static("cookie"));
+        }
+    }
+
+    Ok((response.status(), headers, response.into_body()))
 }
 
 const CACHE_DIR: &str = "./tmp/cache";
 
-#[derive(Deserialize, Serialize)]
+#[derive(Deserialize, Serialize, Clone)]
 struct InnerCachedRequest {
     #[serde(with = "http_serde::method")]
     pub method: Method,
@@ -182,23 +463,57 @@ struct CachedResponse {
     cached_at: SystemTime,
 }
 
-async fn get_policy_from_cache(key: &str) -> Result<(CachePolicy, http::Response<Bytes>)> {
+impl CachedResponse {
+    fn policy(&self) -> Result<(CachePolicy, http::Response<Bytes>)> {
+        let response = http_response_from_parts(self.response.clone())
+            .map_err(|_| miette!("Could not build response"))?;
+
+        let request = http_request_from_parts(self.request.clone())
+            .map_err(|_| miette!("Could not build request"))?;
+
+        let policy =
+            CachePolicy::new_options(&request, &response, self.cached_at, Default::default());
+
+        Ok((policy, response))
+    }
+}
+
+async fn read_from_cache(key: &str) -> Result<CachedResponse> {
     let cached = cacache::read(CACHE_DIR, key)
         .await
         .context("Could not read from cache")?;
-    let cached = postcard::from_bytes::<CachedResponse>(&cached)
-        .map_err(|_| miette!("Could not deserialize cached response"))?;
-
-    let response = http_response_from_parts(cached.response)
-        .map_err(|_| miette!("Could not build response"))?;
 
-    let request =
-        http_request_from_parts(cached.request).map_err(|_| miette!("Could not build request"))?;
+    postcard::from_bytes::<CachedResponse>(&cached)
+        .map_err(|_| miette!("Could not deserialize cached response"))
+}
 
-    let policy =
-        CachePolicy::new_options(&request, &response, cached.cached_at, Default::default());
+async fn get_policy_from_cache(key: &str) -> Result<(CachePolicy, http::Response<Bytes>)> {
+    read_from_cache(key).await?.policy()
+}
 
-    Ok((policy, response))
+/// Write a response to the cache, with its tags in the index so purges can find it by tag
+#[tracing::instrument(name = "cache_write", skip_all, fields(cache_key = %cache_key))]
+async fn write_to_cache(
+    cache_key: String,
+    response: &CachedResponse,
+    tags: &[String],
+) -> Result<()> {
+    let bytes = postcard::to_allocvec(response).into_diagnostic()?;
+    let metadata = tags::entry_metadata(tags);
+
+    tokio::task::spawn_blocking(move || -> Result<()> {
+        let mut writer = cacache::WriteOpts::new()
+            .metadata(metadata)
+            .open_sync(CACHE_DIR, &cache_key)
+            .into_diagnostic()?;
+        writer.write_all(&bytes).into_diagnostic()?;
+        writer.commit().into_diagnostic()?;
+
+        Ok(())
+    })
+    .await
+    .into_diagnostic()?
+    .context("Could not write to cache")
 }
 
 pub fn cache_key(method: impl Display, url: impl Display) -> String {
@@ -228,20 +543,37 @@ where
     }
 }
 
+/// A response to a proxied request, and how we got it
+struct Proxied {
+    cache_status: CacheStatus,
+    /// Where the time went, for the `Server-Timing` header
+    timings: Timings,
+    response: http::Response<Bytes>,
+}
+
 #[tracing::instrument(skip_all)]
 async fn get_potentially_cached_response(
     request: Request<Body>,
     app_state: AppState,
-) -> Result<http::Response<Bytes>> {
-    let db_pool = app_state.db_pool;
-
+) -> Result<Proxied> {
+    let started = Instant::now();
+    let mut timings = Timings::default();
     let method = request.method().clone();
     let url = request.uri().clone();
     info!("Requesting: {}", url);
+    telemetry::continue_trace(&Span::current(), request.headers());
+
+    // A stale response we can revalidate, or fall back to if the origin is down
+    let mut stale = None;
+    let mut origin_request_headers = request.headers().clone();
 
     {
         let cache_key = cache_key(&method, &url);
-        let policy = get_policy_from_cache(&cache_key).await;
+        let cache_read_started = Instant::now();
+        let policy = get_policy_from_cache(&cache_key)
+            .instrument(info_span!("cache_lookup", cache_key = %cache_key))
+            .await;
+        timings.cache_read = Some(cache_read_started.elapsed());
 
         if let Ok((policy, response)) = policy {
             let can_cache = policy.before_request(&request, SystemTime::now());
@@ -250,7 +582,15 @@ async fn get_potentially_cached_response(
                 // TODO: Use the Parts from Fresh to build the response
                 BeforeRequest::Fresh(parts) => {
                     info!(parts =? parts, "Cache hit for: {}", url);
-                    return Ok(response);
+                    app_state
+                        .stats
+                        .record_hit(&cache_key, response.body().len(), None);
+                    timings.total = started.elapsed();
+                    return Ok(Proxied {
+                        cache_status: CacheStatus::Hit,
+                        timings,
+                        response,
+                    });
                 }
                 BeforeRequest::Stale {
                     matches,
@@ -263,6 +603,11 @@ async fn get_potentially_cached_response(
                         ttl =? policy.time_to_live(SystemTime::now()),
                         "Cache hit for: {} but not-usable", url
                     );
+
+                    if matches {
+                        origin_request_headers = revalidation_request.headers;
+                        stale = Some((policy, response));
+                    }
                 }
             };
         }
@@ -273,113 +618,220 @@ async fn get_potentially_cached_response(
         .cloned()
         .unwrap_or_else(|| PathAndQuery::from_static("/"));
 
-    let proxy_url = http::Uri::builder()
-        .scheme("https")
-        .authority(PROXY_ORIGIN_DOMAIN)
-        .path_and_query(path.clone())
-        .build()
-        .map_err(|_| miette!("Could not build url"))?;
+    let proxy_url = origin_url(path)?;
 
     let headers = request.headers().clone();
     let bytes = hyper::body::to_bytes(request.into_body())
         .await
         .map_err(|_| miette!("Could not get bytes from body"))?;
     let client = reqwest::Client::new();
+    let origin_span = info_span!("origin_fetch", url = %proxy_url);
+    telemetry::propagate_trace(&origin_span, &mut origin_request_headers);
+    let origin_started = Instant::now();
     let origin_response = client
         .request(method.clone(), proxy_url.to_string())
-        .headers(headers.clone())
+        .headers(origin_request_headers)
         .body(bytes.clone())
         .send()
-        .await
-        .map_err(|_| miette!("Request failed"))?;
+        .instrument(origin_span.clone())
+        .await;
+
+    let origin_failed = !origin_response
+        .as_ref()
+        .is_ok_and(|r| !r.status().is_server_error());
+    let serve_stale = origin_failed
+        && stale.as_ref().is_some_and(|(policy, response)| {
+            stale::can_serve_on_error(policy, response.headers(), SystemTime::now())
+        });
+    if serve_stale {
+        if let Some((_, response)) = stale {
+            info!("Origin failed, serving stale response for: {}", url);
+            timings.origin = Some(origin_started.elapsed());
+            app_state.stats.record_hit(
+                &cache_key(&method, &url),
+                response.body().len(),
+                timings.origin,
+            );
+            timings.total = started.elapsed();
+            return Ok(Proxied {
+                cache_status: CacheStatus::Stale,
+                timings,
+                response,
+            });
+        }
+    }
+
+    let origin_response = origin_response.map_err(|_| miette!("Request failed"))?;
 
     let origin_status = origin_response.status();
     let origin_headers = origin_response.headers().clone();
     let origin_version = origin_response.version();
     let origin_bytes = origin_response
         .bytes()
+        .instrument(origin_span.clone())
         .await
         .map_err(|_| miette!("Could not get bytes from body"))?;
+    let mut origin_latency = origin_started.elapsed();
+    timings.origin = Some(origin_latency);
 
-    let parts = InnerCachedResponse {
+    let mut parts = InnerCachedResponse {
         status_code: origin_status,
-        headers: origin_headers.clone(),
+        headers: origin_headers,
         body: origin_bytes.into(),
         version: origin_version,
     };
-    let response_to_cache =
+    let mut response_to_cache =
         http_response_from_parts(parts.clone()).map_err(|_| miette!("Could not build response"))?;
     let mut request_to_cache = Request::builder().method(method.clone()).uri(url.clone());
-    for (key, value) in headers {
+    for (key, value) in headers.clone() {
         if let Some(key) = key {
             request_to_cache = request_to_cache.header(key, value);
         }
     }
 
     let request_to_cache = request_to_cache
-        .body(bytes)
+        .body(bytes.clone())
         .map_err(|_| miette!("Could not build request"))?;
 
+    if let (StatusCode::NOT_MODIFIED, Some((policy, stale_response))) = (origin_status, stale) {
+        let now = SystemTime::now();
+        if let AfterResponse::NotModified(_, parts) =
+            policy.after_response(&request_to_cache, &response_to_cache, now)
+        {
+            info!("Revalidated stale response for: {}", url);
+            let response = Response::from_parts(parts, stale_response.into_body())
+                .into_inner_cached_response()?;
+            let refreshed = CachedResponse {
+                request: request_to_cache.into_inner_cached_request()?,
+                response: response.clone(),
+                cached_at: now,
+            };
+            let tags = tags::tags_from_headers(&response.headers);
+            let store_started = Instant::now();
+            write_to_cache(cache_key(&method, &url), &refreshed, &tags).await?;
+            timings.store = Some(store_started.elapsed());
+            app_state.stats.record_hit(
+                &cache_key(&method, &url),
+                response.body.len(),
+                Some(origin_latency),
+            );
+
+            timings.total = started.elapsed();
+            return Ok(Proxied {
+                cache_status: CacheStatus::Revalidated,
+                timings,
+                response: http_response_from_parts(response)?,
+            });
+        }
+
+        // The 304 answered our validators, not anything the client sent, so it can't go back to
+        // them. Ask again without the validators
+        info!("Revalidation didn't match, refetching: {}", url);
+        let mut headers = headers;
+        telemetry::propagate_trace(&origin_span, &mut headers);
+        parts = request_origin(&client, &method, &proxy_url, headers, bytes)
+            .instrument(origin_span)
+            .await?;
+        response_to_cache = http_response_from_parts(parts.clone())
+            .map_err(|_| miette!("Could not build response"))?;
+        origin_latency = origin_started.elapsed();
+        timings.origin = Some(origin_latency);
+    }
+
+    app_state.stats.record_miss(
+        &cache_key(&method, &url),
+        response_to_cache.body().len(),
+        origin_latency,
+    );
+
     let policy = CachePolicy::new(&request_to_cache, &response_to_cache);
-    if policy.is_storable() && !policy.time_to_live(SystemTime::now()).is_zero() {
+    let cache_status = if policy.is_storable() && !policy.time_to_live(SystemTime::now()).is_zero()
+    {
         let response_to_cache = CachedResponse {
             request: request_to_cache.into_inner_cached_request()?,
             response: response_to_cache.into_inner_cached_response()?,
             cached_at: SystemTime::now(),
         };
 
-        let cache_key = format!("{}@{}", method, url);
-        cacache::write(
-            CACHE_DIR,
-            cache_key,
-            postcard::to_allocvec(&response_to_cache).into_diagnostic()?,
-        )
-        .await
-        .context("Could not write to cache")?;
+        let tags = tags::tags_from_headers(&parts.headers);
+        let store_started = Instant::now();
+        write_to_cache(cache_key(&method, &url), &response_to_cache, &tags).await?;
+        timings.store = Some(store_started.elapsed());
 
-        let method = method.to_string();
-        let url = url.to_string();
+        let manifest_started = Instant::now();
+        app_state
+            .manifest
+            .record_page(method.as_str(), &url.to_string(), &tags)
+            .await?;
+        timings.manifest = Some(manifest_started.elapsed());
 
-        let lockfile = match (std::env::var("LITEFS"), &app_state.database_path) {
-            (Ok(_), Some(database_path)) => {
-                let lockfile = litefs_rs::lockfile(database_path).into_diagnostic()?;
-                let lag = litefs_rs::lag(database_path).into_diagnostic()?;
-                info!(?lag, "Got lag from Primary");
+        CacheStatus::Stored
+    } else {
+        CacheStatus::Miss
+    };
 
-                litefs_rs::halt(&lockfile).into_diagnostic()?;
-                info!("Halted database");
+    let response =
+        http_response_from_parts(parts).map_err(|_| miette::miette!("Could not build response"))?;
 
-                Some(lockfile)
-            }
-            _ => None,
-        };
+    timings.total = started.elapsed();
+    Ok(Proxied {
+        cache_status,
+        timings,
+        response,
+    })
+}
 
-        let existing_db_entry = sqlx::query!(
-            "SELECT * FROM Pages WHERE method = ? AND url = ?",
-            method,
-            url
-        )
-        .fetch_optional(&db_pool)
+/// Fetch a whole response from the origin, failing if we can't reach it
+async fn request_origin(
+    client: &reqwest::Client,
+    method: &Method,
+    proxy_url: &Uri,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Result<InnerCachedResponse> {
+    let response = client
+        .request(method.clone(), proxy_url.to_string())
+        .headers(headers)
+        .body(body)
+        .send()
         .await
-        .into_diagnostic()?;
+        .map_err(|_| miette!("Request failed"))?;
 
-        if existing_db_entry.is_none() {
-            sqlx::query!("INSERT INTO Pages (method, url) VALUES (?, ?)", method, url)
-                .execute(&db_pool)
-                .await
-                .into_diagnostic()?;
-        }
+    let status_code = response.status();
+    let headers = response.headers().clone();
+    let version = response.version();
+    let body = response
+        .bytes()
+        .await
+        .map_err(|_| miette!("Could not get bytes from body"))?;
 
-        if let Some(lockfile) = lockfile {
-            litefs_rs::unhalt(&lockfile).into_diagnostic()?;
-            info!("Unhalted database");
-        }
-    }
+    Ok(InnerCachedResponse {
+        status_code,
+        headers,
+        body: body.into(),
+        version,
+    })
+}
 
-    let response =
-        http_response_from_parts(parts).map_err(|_| miette::miette!("Could not build response"))?;
+/// Where to fetch `path` from on the origin
+///
+/// `PROXY_ORIGIN` points this somewhere else, like `http://localhost:3000` for a local `slow_server`
+fn origin_url(path: PathAndQuery) -> Result<Uri> {
+    let origin = std::env::var("PROXY_ORIGIN")
+        .unwrap_or_else(|_| format!("https://{PROXY_ORIGIN_DOMAIN}"))
+        .parse::<Uri>()
+        .into_diagnostic()?
+        .into_parts();
+
+    let mut url = Uri::builder().path_and_query(path);
+    if let Some(scheme) = origin.scheme {
+        url = url.scheme(scheme);
+    }
+    if let Some(authority) = origin.authority {
+        url = url.authority(authority);
+    }
 
-    Ok(response)
+    url.build().map_err(|_| miette!("Could not build url"))
 }
 
 fn http_response_from_parts(parts: InnerCachedResponse) -> Result<http::Response<Bytes>> {
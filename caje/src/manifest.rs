@@ -0,0 +1,431 @@
+use std::fmt::Debug;
+
+use async_trait::async_trait;
+use miette::{IntoDiagnostic, Result};
+use serde::Serialize;
+use sqlx::SqlitePool;
+
+use crate::{db_writer::DbWriter, node::node_name};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub(crate) struct Page {
+    pub method: String,
+    pub url: String,
+    /// The tags the origin gave this page the last time we cached it
+    pub tags: Vec<String>,
+}
+
+/// The list of pages every node should know how to cache
+///
+/// This is what gets shared between nodes, the cached responses themselves stay local
+#[async_trait]
+pub(crate) trait Manifest: Debug + Send + Sync {
+    /// Record that a page is cacheable and cached on this node, and the tags it was cached with
+    ///
+    /// Does nothing if we already know this node has the page with the same tags
+    async fn record_page(&self, method: &str, url: &str, tags: &[String]) -> Result<()>;
+
+    async fn list_pages(&self) -> Result<Vec<Page>>;
+
+    /// The nodes that have cached a page
+    async fn locations(&self, method: &str, url: &str) -> Result<Vec<String>>;
+
+    /// Forget these pages, on every node
+    async fn forget_pages(&self, pages: &[Page]) -> Result<()>;
+
+    /// Forget that this node has cached any pages, other nodes keep theirs
+    async fn forget_node(&self) -> Result<()>;
+
+    /// Forget every page in the manifest
+    async fn clear(&self) -> Result<()>;
+}
+
+/// A manifest in a plain SQLite database, for running a single node without LiteFS
+#[derive(Debug, Clone)]
+pub(crate) struct SqliteManifest {
+    db_pool: SqlitePool,
+    /// Where the pages we record are cached
+    node: String,
+}
+
+impl SqliteManifest {
+    pub(crate) fn new(db_pool: SqlitePool) -> Self {
+        Self {
+            db_pool,
+            node: node_name(),
+        }
+    }
+}
+
+impl SqliteManifest {
+    /// Whether the page is already recorded with exactly these tags, as cached on this node
+    async fn is_recorded(&self, method: &str, url: &str, tags: &[String]) -> Result<bool> {
+        let tags = tags.join(" ");
+        let existing_db_entry = sqlx::query!(
+            "SELECT Pages.id FROM Pages
+            JOIN PageLocations ON PageLocations.page_id = Pages.id AND PageLocations.node = ?
+            WHERE Pages.method = ? AND Pages.url = ? AND Pages.tags = ?",
+            self.node,
+            method,
+            url,
+            tags
+        )
+        .fetch_optional(&self.db_pool)
+        .await
+        .into_diagnostic()?;
+
+        Ok(existing_db_entry.is_some())
+    }
+}
+
+#[async_trait]
+impl Manifest for SqliteManifest {
+    async fn record_page(&self, method: &str, url: &str, tags: &[String]) -> Result<()> {
+        let tags = tags.join(" ");
+        let page_id = sqlx::query_scalar!(
+            "INSERT INTO Pages (method, url, tags) VALUES (?, ?, ?)
+            ON CONFLICT (method, url) DO UPDATE SET tags = excluded.tags
+            RETURNING id",
+            method,
+            url,
+            tags
+        )
+        .fetch_one(&self.db_pool)
+        .await
+        .into_diagnostic()?;
+
+        sqlx::query!(
+            "INSERT INTO PageLocations (page_id, node) VALUES (?, ?)
+            ON CONFLICT (page_id, node) DO NOTHING",
+            page_id,
+            self.node
+        )
+        .execute(&self.db_pool)
+        .await
+        .into_diagnostic()?;
+
+        Ok(())
+    }
+
+    async fn list_pages(&self) -> Result<Vec<Page>> {
+        let pages = sqlx::query!("SELECT method, url, tags FROM Pages")
+            .fetch_all(&self.db_pool)
+            .await
+            .into_diagnostic()?;
+
+        Ok(pages
+            .into_iter()
+            .map(|page| Page {
+                method: page.method,
+                url: page.url,
+                tags: page.tags.split_whitespace().map(str::to_string).collect(),
+            })
+            .collect())
+    }
+
+    async fn locations(&self, method: &str, url: &str) -> Result<Vec<String>> {
+        sqlx::query_scalar!(
+            "SELECT PageLocations.node FROM PageLocations
+            JOIN Pages ON Pages.id = PageLocations.page_id
+            WHERE Pages.method = ? AND Pages.url = ?
+            ORDER BY PageLocations.node",
+            method,
+            url
+        )
+        .fetch_all(&self.db_pool)
+        .await
+        .into_diagnostic()
+    }
+
+    async fn forget_pages(&self, pages: &[Page]) -> Result<()> {
+        let mut tx = self.db_pool.begin().await.into_diagnostic()?;
+        for page in pages {
+            sqlx::query!(
+                "DELETE FROM PageLocations
+                WHERE page_id IN (SELECT id FROM Pages WHERE method = ? AND url = ?)",
+                page.method,
+                page.url
+            )
+            .execute(&mut *tx)
+            .await
+            .into_diagnostic()?;
+            sqlx::query!(
+                "DELETE FROM Pages WHERE method = ? AND url = ?",
+                page.method,
+                page.url
+            )
+            .execute(&mut *tx)
+            .await
+            .into_diagnostic()?;
+        }
+
+        tx.commit().await.into_diagnostic()
+    }
+
+    async fn forget_node(&self) -> Result<()> {
+        sqlx::query!("DELETE FROM PageLocations WHERE node = ?", self.node)
+            .execute(&self.db_pool)
+            .await
+            .into_diagnostic()?;
+
+        Ok(())
+    }
+
+    async fn clear(&self) -> Result<()> {
+        sqlx::query!("DELETE FROM PageLocations")
+            .execute(&self.db_pool)
+            .await
+            .into_diagnostic()?;
+        sqlx::query!("DELETE FROM Pages")
+            .execute(&self.db_pool)
+            .await
+            .into_diagnostic()?;
+
+        Ok(())
+    }
+}
+
+/// A manifest in a SQLite database replicated by LiteFS
+///
+/// Replicas can't write to the database directly, so every write HALTs the primary first
+#[derive(Debug, Clone)]
+pub(crate) struct LiteFsManifest {
+    inner: SqliteManifest,
+    writer: DbWriter,
+}
+
+impl LiteFsManifest {
+    pub(crate) fn new(db_pool: SqlitePool, writer: DbWriter) -> Self {
+        Self {
+            inner: SqliteManifest::new(db_pool),
+            writer,
+        }
+    }
+}
+
+#[async_trait]
+impl Manifest for LiteFsManifest {
+    async fn record_page(&self, method: &str, url: &str, tags: &[String]) -> Result<()> {
+        // Most responses we cache are pages we already know about, so don't HALT for those
+        if self.inner.is_recorded(method, url, tags).await? {
+            return Ok(());
+        }
+
+        self.writer
+            .write(|| self.inner.record_page(method, url, tags))
+            .await
+    }
+
+    async fn list_pages(&self) -> Result<Vec<Page>> {
+        self.inner.list_pages().await
+    }
+
+    async fn locations(&self, method: &str, url: &str) -> Result<Vec<String>> {
+        self.inner.locations(method, url).await
+    }
+
+    async fn forget_pages(&self, pages: &[Page]) -> Result<()> {
+        self.writer.write(|| self.inner.forget_pages(pages)).await
+    }
+
+    async fn forget_node(&self) -> Result<()> {
+        self.writer.write(|| self.inner.forget_node()).await
+    }
+
+    async fn clear(&self) -> Result<()> {
+        self.writer.write(|| self.inner.clear()).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use litefs_rs::{test_support::FakeLiteFs, Position};
+
+    use super::*;
+
+    async fn manifest() -> SqliteManifest {
+        let db_pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        sqlx::migrate!().run(&db_pool).await.unwrap();
+
+        SqliteManifest::new(db_pool)
+    }
+
+    #[tokio::test]
+    async fn record_page_ignores_duplicates() {
+        let manifest = manifest().await;
+
+        manifest.record_page("GET", "/slow", &[]).await.unwrap();
+        manifest.record_page("GET", "/slow", &[]).await.unwrap();
+        manifest.record_page("GET", "/fast", &[]).await.unwrap();
+
+        let pages = manifest.list_pages().await.unwrap();
+        assert_eq!(
+            pages,
+            vec![
+                Page {
+                    method: "GET".to_string(),
+                    url: "/slow".to_string(),
+                    tags: vec![],
+                },
+                Page {
+                    method: "GET".to_string(),
+                    url: "/fast".to_string(),
+                    tags: vec![],
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn record_page_updates_tags() {
+        let manifest = manifest().await;
+        let tags = vec!["product-1".to_string(), "sale".to_string()];
+
+        manifest
+            .record_page("GET", "/products/1", &[])
+            .await
+            .unwrap();
+        manifest
+            .record_page("GET", "/products/1", &tags)
+            .await
+            .unwrap();
+
+        let pages = manifest.list_pages().await.unwrap();
+        assert_eq!(pages.len(), 1);
+        assert_eq!(pages[0].tags, tags);
+        assert!(manifest
+            .is_recorded("GET", "/products/1", &tags)
+            .await
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn records_which_nodes_have_a_page() {
+        let manifest = manifest().await;
+        let other_node = SqliteManifest {
+            db_pool: manifest.db_pool.clone(),
+            node: "caje-ewr".to_string(),
+        };
+        assert!(manifest.locations("GET", "/slow").await.unwrap().is_empty());
+
+        manifest.record_page("GET", "/slow", &[]).await.unwrap();
+        assert!(!other_node.is_recorded("GET", "/slow", &[]).await.unwrap());
+        other_node.record_page("GET", "/slow", &[]).await.unwrap();
+        other_node.record_page("GET", "/slow", &[]).await.unwrap();
+
+        let mut expected = vec![node_name(), "caje-ewr".to_string()];
+        expected.sort();
+        assert_eq!(manifest.locations("GET", "/slow").await.unwrap(), expected);
+        assert!(manifest.locations("GET", "/fast").await.unwrap().is_empty());
+
+        manifest.clear().await.unwrap();
+        assert!(manifest.locations("GET", "/slow").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn forget_node_leaves_other_nodes() {
+        let manifest = manifest().await;
+        let other_node = SqliteManifest {
+            db_pool: manifest.db_pool.clone(),
+            node: "caje-ewr".to_string(),
+        };
+        manifest.record_page("GET", "/slow", &[]).await.unwrap();
+        other_node.record_page("GET", "/slow", &[]).await.unwrap();
+
+        manifest.forget_node().await.unwrap();
+
+        assert_eq!(
+            manifest.locations("GET", "/slow").await.unwrap(),
+            vec!["caje-ewr".to_string()]
+        );
+        assert_eq!(manifest.list_pages().await.unwrap().len(), 1);
+        assert!(!manifest.is_recorded("GET", "/slow", &[]).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn clear_forgets_everything() {
+        let manifest = manifest().await;
+        manifest.record_page("GET", "/slow", &[]).await.unwrap();
+
+        manifest.clear().await.unwrap();
+
+        assert!(manifest.list_pages().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn forget_pages_leaves_the_rest() {
+        let manifest = manifest().await;
+        manifest.record_page("GET", "/slow", &[]).await.unwrap();
+        manifest.record_page("GET", "/fast", &[]).await.unwrap();
+
+        let slow = manifest
+            .list_pages()
+            .await
+            .unwrap()
+            .into_iter()
+            .filter(|page| page.url == "/slow")
+            .collect::<Vec<_>>();
+        manifest.forget_pages(&slow).await.unwrap();
+
+        let pages = manifest.list_pages().await.unwrap();
+        assert_eq!(pages.len(), 1);
+        assert_eq!(pages[0].url, "/fast");
+        assert!(manifest.locations("GET", "/slow").await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn litefs_replica_halts_around_writes() {
+        let fake = FakeLiteFs::new().unwrap();
+        fake.set_primary(Some("caje-ewr")).unwrap();
+
+        let db_pool = SqlitePool::connect(&format!("sqlite:{}", fake.database_path()))
+            .await
+            .unwrap();
+        sqlx::migrate!().run(&db_pool).await.unwrap();
+        let writer = DbWriter::litefs(fake.database_path().to_string());
+        let manifest = LiteFsManifest::new(db_pool, writer.clone());
+
+        // Nothing moves the fake's position, like a write that didn't change anything, so we
+        // shouldn't sit waiting for it
+        let started = Instant::now();
+        manifest.record_page("GET", "/slow", &[]).await.unwrap();
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(manifest.list_pages().await.unwrap().len(), 1);
+
+        // A write that commits moves the position, and we're caught up once it's there
+        writer
+            .write(|| async {
+                fake.set_position(Position {
+                    txid: 2,
+                    post_apply_checksum: 0,
+                })
+                .into_diagnostic()?;
+                manifest.inner.record_page("GET", "/fast", &[]).await
+            })
+            .await
+            .unwrap();
+        assert_eq!(manifest.list_pages().await.unwrap().len(), 2);
+
+        // We let go of HALT once we're done writing
+        drop(fake.hold_halt().unwrap());
+    }
+
+    #[tokio::test]
+    async fn litefs_replica_gives_up_if_primary_never_releases_halt() {
+        let fake = FakeLiteFs::new().unwrap();
+        fake.set_primary(Some("caje-ewr")).unwrap();
+
+        let db_pool = SqlitePool::connect(&format!("sqlite:{}", fake.database_path()))
+            .await
+            .unwrap();
+        sqlx::migrate!().run(&db_pool).await.unwrap();
+        let manifest =
+            LiteFsManifest::new(db_pool, DbWriter::litefs(fake.database_path().to_string()));
+
+        let _held = fake.hold_halt().unwrap();
+        assert!(manifest.record_page("GET", "/slow", &[]).await.is_err());
+        assert!(manifest.list_pages().await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,13 @@
+/// A name for the node we are running on, that other nodes can tell apart from their own
+///
+/// On Fly this is the machine id, which survives restarts and deploys
+pub(crate) fn node_name() -> String {
+    std::env::var("FLY_MACHINE_ID")
+        .or_else(|_| std::env::var("HOSTNAME"))
+        .unwrap_or_else(|_| "local".to_string())
+}
+
+/// The region the node is running in, if we know it
+pub(crate) fn node_region() -> Option<String> {
+    std::env::var("FLY_REGION").ok()
+}
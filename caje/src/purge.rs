@@ -0,0 +1,476 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use cacache::Metadata;
+use chrono::{NaiveDateTime, TimeZone, Utc};
+use miette::{miette, IntoDiagnostic, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sqlx::query_as;
+use tokio::task::JoinHandle;
+use tracing::{error, info};
+
+use crate::{
+    audit::{self, Actor},
+    node::node_name,
+    read_from_cache,
+    tags::entry_tags,
+    write_to_cache, AppState, CACHE_DIR,
+};
+
+/// How often each node checks the purge log for purges it hasn't applied yet
+const PURGE_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Purges older than this are never applied by nodes that missed them
+///
+/// Anything cached before them has long since expired
+const PURGE_LOG_WINDOW: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, sqlx::Type)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(rename_all = "lowercase")]
+pub(crate) enum PurgeKind {
+    /// Exactly one URL
+    Url,
+    /// Every URL starting with the pattern
+    Prefix,
+    /// Every URL matching a glob, like `/products/*/reviews`
+    Glob,
+    /// Every URL the origin tagged with `Surrogate-Key` or `Cache-Tag`
+    Tag,
+}
+
+impl PurgeKind {
+    pub(crate) const ALL: [PurgeKind; 4] = [
+        PurgeKind::Url,
+        PurgeKind::Prefix,
+        PurgeKind::Glob,
+        PurgeKind::Tag,
+    ];
+
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            PurgeKind::Url => "url",
+            PurgeKind::Prefix => "prefix",
+            PurgeKind::Glob => "glob",
+            PurgeKind::Tag => "tag",
+        }
+    }
+}
+
+/// Which cached URLs a purge applies to
+#[derive(Debug, Clone)]
+enum Target {
+    Url(String),
+    Prefix(String),
+    Glob(glob::Pattern),
+    Tag(String),
+}
+
+#[derive(Debug, Clone)]
+struct Matcher {
+    /// Only purge this method, if the pattern was a whole cache key like `GET@/about`
+    method: Option<String>,
+    target: Target,
+}
+
+/// We cache by `METHOD@path`, so `https://slow.coreyja.com/about` and `GET@/about` both purge
+/// `/about`, the second only for `GET`
+///
+/// This is done by hand instead of parsing a `Uri`, because globs like `/products/[0-9]*` aren't
+/// valid URIs
+fn normalize(pattern: &str) -> (Option<String>, String) {
+    let (method, rest) = match pattern.split_once('@') {
+        Some((method, rest))
+            if !method.is_empty() && method.bytes().all(|b| b.is_ascii_uppercase()) =>
+        {
+            (Some(method.to_string()), rest)
+        }
+        _ => (None, pattern),
+    };
+
+    let path = match rest
+        .strip_prefix("https://")
+        .or_else(|| rest.strip_prefix("http://"))
+    {
+        Some(without_scheme) => match without_scheme.find(['/', '?']) {
+            Some(path_start) if without_scheme[path_start..].starts_with('?') => {
+                format!("/{}", &without_scheme[path_start..])
+            }
+            Some(path_start) => without_scheme[path_start..].to_string(),
+            None => "/".to_string(),
+        },
+        None => rest.to_string(),
+    };
+
+    (method, path)
+}
+
+impl Matcher {
+    fn new(kind: PurgeKind, pattern: &str) -> Result<Self> {
+        let pattern = pattern.trim();
+        if pattern.is_empty() {
+            Err(miette!("Purges need a pattern"))?
+        }
+
+        let (method, path) = normalize(pattern);
+        let target = match kind {
+            // Tags aren't paths, so they're used as they are
+            PurgeKind::Tag => {
+                return Ok(Matcher {
+                    method: None,
+                    target: Target::Tag(pattern.to_string()),
+                })
+            }
+            PurgeKind::Url => Target::Url(path),
+            PurgeKind::Prefix => Target::Prefix(path),
+            PurgeKind::Glob => Target::Glob(
+                glob::Pattern::new(&path).map_err(|e| miette!("Invalid glob {pattern}: {e}"))?,
+            ),
+        };
+
+        Ok(Matcher { method, target })
+    }
+
+    /// Cache keys look like `GET@/about`, purges apply to every method unless they name one
+    ///
+    /// Tags live in the metadata we stored next to the entry, not in the key
+    fn matches(&self, key: &str, metadata: &Value) -> bool {
+        let Some((method, url)) = key.split_once('@') else {
+            return false;
+        };
+
+        self.matches_page(method, url, entry_tags(metadata))
+    }
+
+    fn matches_page<'a>(
+        &self,
+        method: &str,
+        url: &str,
+        mut tags: impl Iterator<Item = &'a str>,
+    ) -> bool {
+        if self.method.as_deref().is_some_and(|m| m != method) {
+            return false;
+        }
+
+        match &self.target {
+            Target::Url(target) => url == target,
+            Target::Prefix(prefix) => url.starts_with(prefix.as_str()),
+            Target::Glob(pattern) => pattern.matches(url),
+            Target::Tag(tag) => tags.any(|t| t == tag),
+        }
+    }
+}
+
+/// Make a cached entry stale as of now, by moving when we cached it back by its remaining TTL
+///
+/// It stays in the cache, so the next request can revalidate it with the origin instead of
+/// refetching it, and it can still be served if the origin is down and allows `stale-if-error`
+async fn mark_stale(entry: &Metadata) -> Result<()> {
+    let mut cached = read_from_cache(&entry.key).await?;
+    let (policy, _) = cached.policy()?;
+
+    cached.cached_at -= policy.time_to_live(SystemTime::now());
+
+    let tags = entry_tags(&entry.metadata)
+        .map(str::to_string)
+        .collect::<Vec<_>>();
+    write_to_cache(entry.key.clone(), &cached, &tags).await
+}
+
+/// Check that `pattern` is a valid purge of `kind`, without applying it
+pub(crate) fn validate(kind: PurgeKind, pattern: &str) -> Result<()> {
+    Matcher::new(kind, pattern).map(|_| ())
+}
+
+/// Remove the entries `matcher` applies to from this node's cache index, or mark them stale for
+/// soft purges
+///
+/// Only entries cached before `before` are purged, so a purge that arrives late doesn't throw
+/// away responses that were fetched after it was requested
+async fn purge_locally(matcher: &Matcher, before: SystemTime, soft: bool) -> Result<usize> {
+    let entries: Result<Vec<Metadata>, _> =
+        tokio::task::spawn_blocking(|| cacache::list_sync(CACHE_DIR).collect())
+            .await
+            .into_diagnostic()?;
+    let entries = entries.unwrap_or_default();
+
+    let before = before
+        .duration_since(UNIX_EPOCH)
+        .into_diagnostic()?
+        .as_millis();
+
+    let mut removed = 0;
+    for entry in entries {
+        if entry.time < before && matcher.matches(&entry.key, &entry.metadata) {
+            let marked_stale = soft && mark_stale(&entry).await.is_ok();
+
+            // Entries we can't read can't be marked stale either, so they go
+            if !marked_stale {
+                cacache::remove(CACHE_DIR, &entry.key)
+                    .await
+                    .into_diagnostic()?;
+            }
+            removed += 1;
+        }
+    }
+
+    Ok(removed)
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Purge {
+    pub id: i64,
+    pub kind: PurgeKind,
+    pub pattern: String,
+    pub requested_by: String,
+    pub created_at: NaiveDateTime,
+    /// Soft purges leave entries in the cache, but stale
+    pub soft: bool,
+}
+
+impl Purge {
+    /// Apply the purge to our cache, and let everyone know we have
+    async fn apply(&self, state: &AppState) -> Result<usize> {
+        let matcher = Matcher::new(self.kind, &self.pattern)?;
+        let before = SystemTime::from(Utc.from_utc_datetime(&self.created_at));
+
+        let removed = purge_locally(&matcher, before, self.soft).await?;
+
+        let node = node_name();
+        let removed_count = i64::try_from(removed).into_diagnostic()?;
+        state
+            .db_writer
+            .write(|| async {
+                sqlx::query!(
+                    "INSERT INTO PurgeCompletions (purge_id, node, removed) VALUES (?, ?, ?)
+                    ON CONFLICT (purge_id, node) DO NOTHING",
+                    self.id,
+                    node,
+                    removed_count
+                )
+                .execute(&state.db_pool)
+                .await
+                .into_diagnostic()
+            })
+            .await?;
+
+        info!(purge_id = self.id, removed, "Applied purge");
+
+        Ok(removed)
+    }
+}
+
+/// Remove the pages a purge applies to from the shared manifest, returning how many there were
+async fn forget_pages(state: &AppState, kind: PurgeKind, pattern: &str) -> Result<usize> {
+    let matcher = Matcher::new(kind, pattern)?;
+    let pages = state
+        .manifest
+        .list_pages()
+        .await?
+        .into_iter()
+        .filter(|page| {
+            matcher.matches_page(
+                &page.method,
+                &page.url,
+                page.tags.iter().map(String::as_str),
+            )
+        })
+        .collect::<Vec<_>>();
+
+    if !pages.is_empty() {
+        state.manifest.forget_pages(&pages).await?;
+    }
+
+    Ok(pages.len())
+}
+
+/// Add a purge to the log for every node, and apply it to this node straight away
+///
+/// Returns the purge and how many entries it purged here
+pub(crate) async fn request(
+    state: &AppState,
+    actor: &Actor,
+    kind: PurgeKind,
+    pattern: &str,
+    soft: bool,
+) -> Result<(Purge, usize)> {
+    // Make sure it's valid before anyone else tries to apply it
+    validate(kind, pattern)?;
+    let pattern = pattern.trim();
+    let now = Utc::now().naive_utc();
+
+    let purge = state
+        .db_writer
+        .write(|| async {
+            query_as!(
+                Purge,
+                r#"INSERT INTO Purges (kind, pattern, requested_by, created_at, soft)
+                VALUES (?, ?, ?, ?, ?)
+                RETURNING id, kind as "kind: PurgeKind", pattern, requested_by, created_at, soft"#,
+                kind,
+                pattern,
+                actor.name,
+                now,
+                soft
+            )
+            .fetch_one(&state.db_pool)
+            .await
+            .into_diagnostic()
+        })
+        .await?;
+
+    let removed = purge.apply(state).await?;
+
+    // Otherwise the next populate on any node would fetch the pages straight back. Soft purges
+    // keep them, since the point is to revalidate rather than drop them
+    let forgotten = if soft {
+        0
+    } else {
+        forget_pages(state, kind, pattern).await?
+    };
+
+    audit::record(
+        state,
+        actor,
+        "purge",
+        json!({
+            "id": purge.id,
+            "kind": kind,
+            "pattern": pattern,
+            "soft": soft,
+            "removed": removed,
+            "forgotten": forgotten,
+        }),
+    )
+    .await?;
+
+    Ok((purge, removed))
+}
+
+/// Apply every recent purge this node hasn't done yet, oldest first
+async fn apply_pending(state: &AppState) -> Result<()> {
+    let node = node_name();
+    let since =
+        Utc::now().naive_utc() - chrono::Duration::from_std(PURGE_LOG_WINDOW).into_diagnostic()?;
+
+    let pending = query_as!(
+        Purge,
+        r#"SELECT id, kind as "kind: PurgeKind", pattern, requested_by, created_at, soft
+        FROM Purges
+        WHERE created_at > ?
+            AND id NOT IN (SELECT purge_id FROM PurgeCompletions WHERE node = ?)
+        ORDER BY id"#,
+        since,
+        node
+    )
+    .fetch_all(&state.db_pool)
+    .await
+    .into_diagnostic()?;
+
+    // One purge we can't apply shouldn't hold up the rest
+    for purge in pending {
+        if let Err(e) = purge.apply(state).await {
+            error!(purge_id = purge.id, error = ?e, "Could not apply purge");
+        }
+    }
+
+    Ok(())
+}
+
+/// Keep this node's cache in line with the purges requested on every other node
+pub(crate) fn spawn_worker(state: AppState) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(PURGE_POLL_INTERVAL);
+
+        loop {
+            interval.tick().await;
+
+            if let Err(e) = apply_pending(&state).await {
+                error!(error = ?e, "Failed to apply pending purges");
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn url_purges_match_exactly_for_every_method() {
+        let matcher = Matcher::new(PurgeKind::Url, "https://slow.coreyja.com/about").unwrap();
+
+        assert!(matcher.matches("GET@/about", &Value::Null));
+        assert!(matcher.matches("HEAD@/about", &Value::Null));
+        assert!(!matcher.matches("GET@/about/team", &Value::Null));
+    }
+
+    #[test]
+    fn prefix_purges_match_everything_below() {
+        let matcher = Matcher::new(PurgeKind::Prefix, "/products/").unwrap();
+
+        assert!(matcher.matches("GET@/products/1", &Value::Null));
+        assert!(matcher.matches("GET@/products/1/reviews?page=2", &Value::Null));
+        assert!(!matcher.matches("GET@/product", &Value::Null));
+    }
+
+    #[test]
+    fn glob_purges() {
+        let matcher = Matcher::new(PurgeKind::Glob, "/products/*/reviews").unwrap();
+
+        assert!(matcher.matches("GET@/products/1/reviews", &Value::Null));
+        assert!(!matcher.matches("GET@/products/1/specs", &Value::Null));
+    }
+
+    #[test]
+    fn tag_purges_match_the_stored_tags() {
+        let matcher = Matcher::new(PurgeKind::Tag, "product-1").unwrap();
+        let tagged = crate::tags::entry_metadata(&["product-1".to_string()]);
+        let other = crate::tags::entry_metadata(&["product-2".to_string()]);
+
+        assert!(matcher.matches("GET@/products/1", &tagged));
+        assert!(!matcher.matches("GET@/products/2", &other));
+        assert!(!matcher.matches("GET@/products/1", &Value::Null));
+    }
+
+    #[test]
+    fn patterns_are_normalized_to_cache_keys() {
+        assert_eq!(normalize("/about"), (None, "/about".to_string()));
+        assert_eq!(
+            normalize("https://slow.coreyja.com/products/*?page=[0-9]"),
+            (None, "/products/*?page=[0-9]".to_string())
+        );
+        assert_eq!(
+            normalize("http://slow.coreyja.com"),
+            (None, "/".to_string())
+        );
+        assert_eq!(
+            normalize("https://slow.coreyja.com?x=1"),
+            (None, "/?x=1".to_string())
+        );
+        assert_eq!(
+            normalize("HEAD@https://slow.coreyja.com/about"),
+            (Some("HEAD".to_string()), "/about".to_string())
+        );
+        assert_eq!(normalize("/me@home"), (None, "/me@home".to_string()));
+    }
+
+    #[test]
+    fn patterns_with_a_method_only_purge_that_method() {
+        let matcher =
+            Matcher::new(PurgeKind::Glob, "GET@https://slow.coreyja.com/products/*").unwrap();
+
+        assert!(matcher.matches("GET@/products/1", &Value::Null));
+        assert!(!matcher.matches("HEAD@/products/1", &Value::Null));
+
+        let matcher = Matcher::new(PurgeKind::Tag, "GET@product-1").unwrap();
+        let tagged = crate::tags::entry_metadata(&["GET@product-1".to_string()]);
+        assert!(matcher.matches("HEAD@/products/1", &tagged));
+    }
+
+    #[test]
+    fn purges_need_a_valid_pattern() {
+        assert!(Matcher::new(PurgeKind::Url, "  ").is_err());
+        assert!(Matcher::new(PurgeKind::Glob, "/products/[").is_err());
+    }
+}
@@ -0,0 +1,306 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+    time::Duration,
+};
+
+use http::{
+    header::{HeaderName, HOST},
+    HeaderValue, Method, Uri,
+};
+use miette::{miette, IntoDiagnostic, Result};
+use serde::Deserialize;
+use tokio::{
+    sync::Semaphore,
+    task::JoinSet,
+    time::{sleep_until, Instant},
+};
+
+use crate::{
+    cache_status::{CacheStatus, CACHE_STATUS},
+    PROXY_FROM_DOMAIN,
+};
+
+const USAGE: &str = "Usage: caje replay <requests.jsonl> [--target http://127.0.0.1:3001] [--paced] [--concurrency 8]";
+
+/// One line of a request log
+#[derive(Debug, Clone, Deserialize)]
+struct LoggedRequest {
+    #[serde(default = "default_method")]
+    method: String,
+    /// A path, or a full URL we only use the path and query of
+    url: String,
+    #[serde(default)]
+    headers: HashMap<String, String>,
+    /// Milliseconds after the first request that this one was made, for replaying at the
+    /// recorded pace
+    #[serde(default)]
+    at_ms: u64,
+}
+
+fn default_method() -> String {
+    "GET".to_string()
+}
+
+#[derive(Debug)]
+struct Options {
+    log: String,
+    target: String,
+    paced: bool,
+    concurrency: usize,
+}
+
+impl Options {
+    fn parse(args: impl IntoIterator<Item = String>) -> Result<Self> {
+        let mut log = None;
+        let mut target = "http://127.0.0.1:3001".to_string();
+        let mut paced = false;
+        let mut concurrency = 8;
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--target" => target = args.next().ok_or_else(|| miette!("{USAGE}"))?,
+                "--paced" => paced = true,
+                "--concurrency" => {
+                    concurrency = args
+                        .next()
+                        .and_then(|n| n.parse().ok())
+                        .filter(|n| *n > 0)
+                        .ok_or_else(|| miette!("{USAGE}"))?
+                }
+                _ if log.is_none() && !arg.starts_with("--") => log = Some(arg),
+                _ => Err(miette!("Unexpected argument {arg}\n{USAGE}"))?,
+            }
+        }
+
+        Ok(Self {
+            log: log.ok_or_else(|| miette!("{USAGE}"))?,
+            target: target.trim_end_matches('/').to_string(),
+            paced,
+            concurrency,
+        })
+    }
+}
+
+/// Every request in a JSONL log, skipping blank lines
+fn parse_log(contents: &str) -> Result<Vec<LoggedRequest>> {
+    contents
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| {
+            serde_json::from_str(line).map_err(|e| miette!("Line {} isn't a request: {e}", i + 1))
+        })
+        .collect()
+}
+
+/// What came back for one replayed request
+#[derive(Debug)]
+enum Outcome {
+    /// `None` when the response didn't say how the cache answered it
+    Answered(Option<CacheStatus>, Duration),
+    Failed(String),
+}
+
+async fn send(
+    client: reqwest::Client,
+    target: Arc<String>,
+    request: LoggedRequest,
+) -> Result<Outcome> {
+    let url = request.url.parse::<Uri>().into_diagnostic()?;
+    let path = url.path_and_query().map_or("/", |path| path.as_str());
+    let method = request.method.parse::<Method>().into_diagnostic()?;
+
+    let mut builder = client
+        .request(method, format!("{target}{path}"))
+        .header(HOST, PROXY_FROM_DOMAIN);
+    for (name, value) in &request.headers {
+        let name = HeaderName::try_from(name.as_str()).into_diagnostic()?;
+        let value = HeaderValue::try_from(value.as_str()).into_diagnostic()?;
+        builder = builder.header(name, value);
+    }
+
+    let started = Instant::now();
+    let response = match builder.send().await {
+        Ok(response) => response,
+        Err(e) => return Ok(Outcome::Failed(e.to_string())),
+    };
+    let cache_status = response
+        .headers()
+        .get(CACHE_STATUS)
+        .and_then(|value| value.to_str().ok())
+        .and_then(CacheStatus::parse);
+    // The request isn't done until we have the whole body
+    if let Err(e) = response.bytes().await {
+        return Ok(Outcome::Failed(e.to_string()));
+    }
+
+    Ok(Outcome::Answered(cache_status, started.elapsed()))
+}
+
+/// The latency `percentile` of requests took at most, from latencies sorted fastest first
+fn percentile(sorted: &[Duration], percentile: f64) -> Option<Duration> {
+    if sorted.is_empty() {
+        return None;
+    }
+
+    let index = ((sorted.len() as f64 * percentile).ceil() as usize).clamp(1, sorted.len()) - 1;
+
+    Some(sorted[index])
+}
+
+#[derive(Debug, Default)]
+struct Report {
+    statuses: BTreeMap<&'static str, usize>,
+    hits: usize,
+    answered: usize,
+    failed: usize,
+    latencies: Vec<Duration>,
+}
+
+impl Report {
+    fn add(&mut self, outcome: Outcome) {
+        match outcome {
+            Outcome::Answered(status, latency) => {
+                self.answered += 1;
+                self.latencies.push(latency);
+
+                let label = status.map_or("unknown", |status| status.label());
+                *self.statuses.entry(label).or_default() += 1;
+                if status.is_some_and(|status| status.is_hit()) {
+                    self.hits += 1;
+                }
+            }
+            Outcome::Failed(e) => {
+                tracing::warn!(error = e, "Replayed request failed");
+                self.failed += 1;
+            }
+        }
+    }
+
+    fn print(mut self, elapsed: Duration) {
+        self.latencies.sort();
+
+        println!(
+            "Replayed {} requests in {elapsed:.2?}, {} failed",
+            self.answered + self.failed,
+            self.failed
+        );
+        if self.answered > 0 {
+            println!(
+                "Hit ratio: {:.1}% ({} of {})",
+                self.hits as f64 / self.answered as f64 * 100.0,
+                self.hits,
+                self.answered
+            );
+        }
+        for (status, count) in &self.statuses {
+            println!("  {status}: {count}");
+        }
+        for (label, p) in [("p50", 0.5), ("p95", 0.95), ("p99", 0.99)] {
+            if let Some(latency) = percentile(&self.latencies, p) {
+                println!("Latency {label}: {latency:.2?}");
+            }
+        }
+    }
+}
+
+/// Replay a request log against a running caje, and report how the cache did
+///
+/// Without `--paced` requests go as fast as `--concurrency` allows, with it each one is sent
+/// `at_ms` after we start
+pub(crate) async fn run(args: impl IntoIterator<Item = String>) -> Result<()> {
+    let options = Options::parse(args)?;
+    let contents = tokio::fs::read_to_string(&options.log)
+        .await
+        .into_diagnostic()?;
+    let requests = parse_log(&contents)?;
+
+    let client = reqwest::Client::new();
+    let target = Arc::new(options.target);
+    let permits = Arc::new(Semaphore::new(options.concurrency));
+    let started = Instant::now();
+    let mut tasks = JoinSet::new();
+
+    for request in requests {
+        let client = client.clone();
+        let target = target.clone();
+        let permits = permits.clone();
+        let send_at = options
+            .paced
+            .then(|| started + Duration::from_millis(request.at_ms));
+
+        tasks.spawn(async move {
+            if let Some(send_at) = send_at {
+                sleep_until(send_at).await;
+            }
+            let _permit = permits.acquire_owned().await;
+
+            send(client, target, request).await
+        });
+    }
+
+    let mut report = Report::default();
+    while let Some(task) = tasks.join_next().await {
+        let outcome = match task.into_diagnostic()? {
+            Ok(outcome) => outcome,
+            Err(e) => Outcome::Failed(e.to_string()),
+        };
+        report.add(outcome);
+    }
+
+    report.print(started.elapsed());
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_request_logs() {
+        let log = r#"{"url": "/fast"}
+
+{"method": "HEAD", "url": "https://slow.coreyja.com/slow?x=1", "headers": {"accept": "text/html"}, "at_ms": 1500}"#;
+        let requests = parse_log(log).unwrap();
+
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].method, "GET");
+        assert_eq!(requests[0].at_ms, 0);
+        assert_eq!(requests[1].method, "HEAD");
+        assert_eq!(requests[1].headers["accept"], "text/html");
+        assert_eq!(requests[1].at_ms, 1500);
+
+        assert!(parse_log("{\"method\": \"GET\"}").is_err());
+    }
+
+    #[test]
+    fn percentiles_of_sorted_latencies() {
+        let latencies = (1..=100).map(Duration::from_millis).collect::<Vec<_>>();
+
+        assert_eq!(percentile(&latencies, 0.5), Some(Duration::from_millis(50)));
+        assert_eq!(
+            percentile(&latencies, 0.95),
+            Some(Duration::from_millis(95))
+        );
+        assert_eq!(percentile(&latencies, 0.0), Some(Duration::from_millis(1)));
+        assert_eq!(percentile(&[], 0.5), None);
+    }
+
+    #[test]
+    fn parses_options() {
+        let options = Options::parse(
+            ["log.jsonl", "--paced", "--target", "http://localhost:3001/"].map(String::from),
+        )
+        .unwrap();
+
+        assert_eq!(options.log, "log.jsonl");
+        assert_eq!(options.target, "http://localhost:3001");
+        assert!(options.paced);
+        assert_eq!(options.concurrency, 8);
+
+        assert!(Options::parse(["--paced".to_string()]).is_err());
+    }
+}
@@ -0,0 +1,146 @@
+use std::{fmt::Write, str::FromStr, time::Duration};
+
+use http::HeaderValue;
+use miette::miette;
+use tower_cookies::Cookies;
+use tracing::warn;
+
+use crate::{
+    admin::{auth::DBSession, roles::Viewer},
+    AppState,
+};
+
+/// The header we break down where the time on a proxied request went in
+pub(crate) const SERVER_TIMING: &str = "server-timing";
+
+/// Where the time went while answering a proxied request
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub(crate) struct Timings {
+    /// Looking the request up in our cache
+    pub cache_read: Option<Duration>,
+    /// Waiting on the origin
+    pub origin: Option<Duration>,
+    /// Writing the response to our cache
+    pub store: Option<Duration>,
+    /// Recording the page in the shared manifest, including any LiteFS HALT
+    pub manifest: Option<Duration>,
+    pub total: Duration,
+}
+
+impl Timings {
+    /// The `Server-Timing` header, with only the steps we actually took
+    pub(crate) fn header_value(&self) -> HeaderValue {
+        let steps = [
+            ("cache-read", self.cache_read),
+            ("origin", self.origin),
+            ("store", self.store),
+            ("manifest", self.manifest),
+            ("total", Some(self.total)),
+        ];
+
+        let mut value = String::new();
+        for (name, duration) in steps {
+            let Some(duration) = duration else {
+                continue;
+            };
+            if !value.is_empty() {
+                value.push_str(", ");
+            }
+            let _ = write!(value, "{name};dur={:.1}", duration.as_secs_f64() * 1000.0);
+        }
+
+        HeaderValue::from_str(&value).expect("Server-Timing is always a valid header")
+    }
+}
+
+/// Who gets a `Server-Timing` header on proxied responses, from `SERVER_TIMING`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ServerTiming {
+    Off,
+    /// Only requests from someone logged in to the dashboard
+    #[default]
+    Admins,
+    /// Every request, which tells the public how we're doing
+    Everyone,
+}
+
+impl FromStr for ServerTiming {
+    type Err = miette::Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(ServerTiming::Off),
+            "admins" => Ok(ServerTiming::Admins),
+            "all" => Ok(ServerTiming::Everyone),
+            _ => Err(miette!(
+                "SERVER_TIMING has to be off, admins or all, not {s}"
+            )),
+        }
+    }
+}
+
+impl ServerTiming {
+    pub(crate) fn from_env() -> miette::Result<Self> {
+        match std::env::var("SERVER_TIMING") {
+            Ok(value) => value.trim().parse(),
+            Err(_) => Ok(ServerTiming::default()),
+        }
+    }
+
+    /// Whether the request these cookies came with should see where the time went
+    pub(crate) async fn applies(&self, state: &AppState, cookies: &Cookies) -> bool {
+        match self {
+            ServerTiming::Off => false,
+            ServerTiming::Everyone => true,
+            ServerTiming::Admins => match DBSession::<Viewer>::peek(state, cookies).await {
+                Ok(session) => session.is_some(),
+                Err(e) => {
+                    warn!(error = ?e, "Could not check for an admin session");
+                    false
+                }
+            },
+        }
+    }
+
+    /// Whether responses with the header differ by cookie, so caches in front of us need to know
+    pub(crate) fn varies_by_cookie(&self) -> bool {
+        *self == ServerTiming::Admins
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_steps_we_took_are_in_the_header() {
+        let hit = Timings {
+            cache_read: Some(Duration::from_micros(1240)),
+            total: Duration::from_micros(1500),
+            ..Default::default()
+        };
+        assert_eq!(hit.header_value(), "cache-read;dur=1.2, total;dur=1.5");
+
+        let stored = Timings {
+            cache_read: Some(Duration::from_millis(1)),
+            origin: Some(Duration::from_millis(1003)),
+            store: Some(Duration::from_millis(4)),
+            manifest: Some(Duration::from_millis(2)),
+            total: Duration::from_millis(1010),
+        };
+        assert_eq!(
+            stored.header_value(),
+            "cache-read;dur=1.0, origin;dur=1003.0, store;dur=4.0, manifest;dur=2.0, total;dur=1010.0"
+        );
+    }
+
+    #[test]
+    fn parses_who_gets_server_timing() {
+        assert_eq!("off".parse::<ServerTiming>().unwrap(), ServerTiming::Off);
+        assert_eq!(
+            "all".parse::<ServerTiming>().unwrap(),
+            ServerTiming::Everyone
+        );
+        assert!("everyone".parse::<ServerTiming>().is_err());
+    }
+}
@@ -0,0 +1,88 @@
+use std::time::{Duration, SystemTime};
+
+use http::HeaderMap;
+use http_cache_semantics::CachePolicy;
+
+/// The value of a `Cache-Control` directive, empty for directives like `no-store` that don't
+/// have one
+pub(crate) fn cache_control(headers: &HeaderMap, directive: &str) -> Option<String> {
+    headers
+        .get_all(http::header::CACHE_CONTROL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|part| match part.trim().split_once('=') {
+            Some((name, value)) => (name.trim(), value.trim().trim_matches('"')),
+            None => (part.trim(), ""),
+        })
+        .find(|(name, _)| name.eq_ignore_ascii_case(directive))
+        .map(|(_, value)| value.to_string())
+}
+
+/// How long past going stale a response may still be used, from a directive like
+/// `stale-while-revalidate` or `stale-if-error` (RFC 5861)
+pub(crate) fn stale_window(headers: &HeaderMap, directive: &str) -> Option<Duration> {
+    cache_control(headers, directive)
+        .and_then(|seconds| seconds.parse().ok())
+        .map(Duration::from_secs)
+}
+
+/// Whether a stale cached response can stand in for an origin that failed or errored
+pub(crate) fn can_serve_on_error(
+    policy: &CachePolicy,
+    cached_headers: &HeaderMap,
+    now: SystemTime,
+) -> bool {
+    let Some(window) = stale_window(cached_headers, "stale-if-error") else {
+        return false;
+    };
+
+    // The response is within the window if it was still fresh `window` ago
+    match now.checked_sub(window) {
+        Some(window_start) => !policy.time_to_live(window_start).is_zero(),
+        None => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http::HeaderValue;
+
+    use super::*;
+
+    #[test]
+    fn reads_cache_control_directives() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            http::header::CACHE_CONTROL,
+            HeaderValue::from_static("max-age=60, Stale-If-Error=300"),
+        );
+
+        assert_eq!(
+            stale_window(&headers, "stale-if-error"),
+            Some(Duration::from_secs(300))
+        );
+        assert_eq!(stale_window(&HeaderMap::new(), "stale-if-error"), None);
+        assert_eq!(cache_control(&headers, "max-age").as_deref(), Some("60"));
+    }
+
+    #[test]
+    fn serves_stale_only_within_the_window() {
+        let request = http::Request::get("/about").body(()).unwrap();
+        let response = http::Response::builder()
+            .header(
+                http::header::CACHE_CONTROL,
+                "max-age=60, stale-if-error=300",
+            )
+            .body(())
+            .unwrap();
+        let cached_at = SystemTime::now();
+        let policy = CachePolicy::new_options(&request, &response, cached_at, Default::default());
+
+        let just_stale = cached_at + Duration::from_secs(120);
+        let long_stale = cached_at + Duration::from_secs(600);
+
+        assert!(can_serve_on_error(&policy, response.headers(), just_stale));
+        assert!(!can_serve_on_error(&policy, response.headers(), long_stale));
+    }
+}
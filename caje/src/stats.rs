@@ -0,0 +1,420 @@
+use std::{collections::HashMap, sync::Mutex, time::Duration};
+
+use chrono::{NaiveDateTime, Utc};
+use miette::{IntoDiagnostic, Result};
+use tokio::task::JoinHandle;
+use tracing::error;
+
+use crate::{node::node_name, AppState};
+
+/// How often each node writes its counters to the database
+///
+/// On a replica every write HALTs the primary, so this shouldn't be too often
+const ROLLUP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Rollups older than this are deleted
+const ROLLUP_RETENTION: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// We only keep the busiest URLs of each rollup
+const URLS_PER_ROLLUP: usize = 50;
+
+/// Most cache keys we count hits or requests for, so junk query strings can't use up our memory
+///
+/// Past this the least hit keys are forgotten for the cache browser, and new URLs in a rollup
+/// window only count towards the totals
+const MAX_TRACKED_KEYS: usize = 10_000;
+
+/// Upper bounds of the origin latency histogram buckets, with one more bucket for anything slower
+pub(crate) const LATENCY_BUCKETS_MS: [u64; 12] =
+    [5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10000, 30000];
+
+type Histogram = [u64; LATENCY_BUCKETS_MS.len() + 1];
+
+#[derive(Debug, Default, Clone, Copy)]
+struct UrlCounts {
+    hits: u64,
+    misses: u64,
+}
+
+/// Everything that happened since the last rollup
+#[derive(Debug, Default)]
+struct Window {
+    hits: u64,
+    misses: u64,
+    bytes_from_cache: u64,
+    bytes_from_origin: u64,
+    origin_latency: Histogram,
+    urls: HashMap<String, UrlCounts>,
+}
+
+impl Window {
+    fn url_counts(&mut self, cache_key: &str) -> Option<&mut UrlCounts> {
+        if self.urls.len() >= MAX_TRACKED_KEYS && !self.urls.contains_key(cache_key) {
+            return None;
+        }
+
+        Some(self.urls.entry(cache_key.to_string()).or_default())
+    }
+
+    /// Add the counts from an earlier window we couldn't roll up back into this one
+    fn merge(&mut self, earlier: Window) {
+        self.hits += earlier.hits;
+        self.misses += earlier.misses;
+        self.bytes_from_cache += earlier.bytes_from_cache;
+        self.bytes_from_origin += earlier.bytes_from_origin;
+        for (total, count) in self.origin_latency.iter_mut().zip(earlier.origin_latency) {
+            *total += count;
+        }
+        for (cache_key, counts) in earlier.urls {
+            if let Some(url) = self.url_counts(&cache_key) {
+                url.hits += counts.hits;
+                url.misses += counts.misses;
+            }
+        }
+    }
+
+    fn record_latency(&mut self, latency: Duration) {
+        let ms = u64::try_from(latency.as_millis()).unwrap_or(u64::MAX);
+        let bucket = LATENCY_BUCKETS_MS
+            .iter()
+            .position(|bound| ms <= *bound)
+            .unwrap_or(LATENCY_BUCKETS_MS.len());
+
+        self.origin_latency[bucket] += 1;
+    }
+
+    fn busiest_urls(&self) -> Vec<(&String, UrlCounts)> {
+        let mut urls = self
+            .urls
+            .iter()
+            .map(|(key, counts)| (key, *counts))
+            .collect::<Vec<_>>();
+        urls.sort_by_key(|(key, counts)| (std::cmp::Reverse(counts.hits + counts.misses), *key));
+        urls.truncate(URLS_PER_ROLLUP);
+
+        urls
+    }
+}
+
+/// In-process request counters for this node
+#[derive(Debug)]
+pub(crate) struct Stats {
+    /// Hits per cache key since this node started, for the cache browser
+    hits: Mutex<HashMap<String, u64>>,
+    /// When the current window started, and what happened in it
+    window: Mutex<(NaiveDateTime, Window)>,
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self {
+            hits: Default::default(),
+            window: Mutex::new((Utc::now().naive_utc(), Window::default())),
+        }
+    }
+}
+
+impl Stats {
+    /// A response served from our cache, `origin_latency` is set if we revalidated it first
+    pub(crate) fn record_hit(
+        &self,
+        cache_key: &str,
+        bytes: usize,
+        origin_latency: Option<Duration>,
+    ) {
+        {
+            let mut hits = self.hits.lock().unwrap();
+            if hits.len() >= MAX_TRACKED_KEYS && !hits.contains_key(cache_key) {
+                forget_least_hit(&mut hits);
+            }
+            *hits.entry(cache_key.to_string()).or_default() += 1;
+        }
+
+        let mut window = self.window.lock().unwrap();
+        let window = &mut window.1;
+        window.hits += 1;
+        window.bytes_from_cache += bytes as u64;
+        if let Some(url) = window.url_counts(cache_key) {
+            url.hits += 1;
+        }
+        if let Some(latency) = origin_latency {
+            window.record_latency(latency);
+        }
+    }
+
+    /// A response we had to get from the origin
+    pub(crate) fn record_miss(&self, cache_key: &str, bytes: usize, origin_latency: Duration) {
+        let mut window = self.window.lock().unwrap();
+        let window = &mut window.1;
+        window.misses += 1;
+        window.bytes_from_origin += bytes as u64;
+        if let Some(url) = window.url_counts(cache_key) {
+            url.misses += 1;
+        }
+        window.record_latency(origin_latency);
+    }
+
+    /// How many times `cache_key` was served from this node's cache since it started
+    pub(crate) fn hits(&self, cache_key: &str) -> u64 {
+        self.hits
+            .lock()
+            .unwrap()
+            .get(cache_key)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    fn take_window(&self, now: NaiveDateTime) -> (NaiveDateTime, Window) {
+        std::mem::replace(&mut *self.window.lock().unwrap(), (now, Window::default()))
+    }
+
+    /// Put back a window we took but couldn't roll up, so it goes out with the next one
+    fn restore_window(&self, period_start: NaiveDateTime, earlier: Window) {
+        let mut window = self.window.lock().unwrap();
+        window.0 = window.0.min(period_start);
+        window.1.merge(earlier);
+    }
+}
+
+/// Forget the least hit half of the keys, to make room for new ones
+fn forget_least_hit(hits: &mut HashMap<String, u64>) {
+    let mut counts = hits.values().copied().collect::<Vec<_>>();
+    let middle = counts.len() / 2;
+    let (_, threshold, _) = counts.select_nth_unstable(middle);
+    let threshold = *threshold;
+
+    let mut to_forget = middle.max(1);
+    hits.retain(|_, count| {
+        if *count <= threshold && to_forget > 0 {
+            to_forget -= 1;
+            false
+        } else {
+            true
+        }
+    });
+}
+
+/// Write everything since the last rollup to the database
+async fn rollup(state: &AppState) -> Result<()> {
+    let now = Utc::now().naive_utc();
+    let (period_start, window) = state.stats.take_window(now);
+
+    // Don't HALT the primary for nothing
+    if window.hits == 0 && window.misses == 0 {
+        return Ok(());
+    }
+
+    let node = node_name();
+    let hits = window.hits as i64;
+    let misses = window.misses as i64;
+    let bytes_from_cache = window.bytes_from_cache as i64;
+    let bytes_from_origin = window.bytes_from_origin as i64;
+    let histogram = serde_json::to_string(&window.origin_latency).into_diagnostic()?;
+    let retain_since = now - chrono::Duration::from_std(ROLLUP_RETENTION).into_diagnostic()?;
+    let urls = window.busiest_urls();
+
+    let written = state
+        .db_writer
+        .write(|| async {
+            let mut tx = state.db_pool.begin().await.into_diagnostic()?;
+
+            let rollup_id = sqlx::query_scalar!(
+                "INSERT INTO StatsRollups (node, period_start, period_end, hits, misses,
+                    bytes_from_cache, bytes_from_origin, origin_latency_histogram)
+                VALUES (?, ?, ?, ?, ?, ?, ?, ?)
+                RETURNING id",
+                node,
+                period_start,
+                now,
+                hits,
+                misses,
+                bytes_from_cache,
+                bytes_from_origin,
+                histogram
+            )
+            .fetch_one(&mut *tx)
+            .await
+            .into_diagnostic()?;
+
+            for (cache_key, counts) in &urls {
+                let url_hits = counts.hits as i64;
+                let url_misses = counts.misses as i64;
+                sqlx::query!(
+                    "INSERT INTO StatsUrlRollups (rollup_id, cache_key, hits, misses)
+                    VALUES (?, ?, ?, ?)",
+                    rollup_id,
+                    cache_key,
+                    url_hits,
+                    url_misses
+                )
+                .execute(&mut *tx)
+                .await
+                .into_diagnostic()?;
+            }
+
+            sqlx::query!(
+                "DELETE FROM StatsUrlRollups
+                WHERE rollup_id IN (SELECT id FROM StatsRollups WHERE period_end < ?)",
+                retain_since
+            )
+            .execute(&mut *tx)
+            .await
+            .into_diagnostic()?;
+            sqlx::query!(
+                "DELETE FROM StatsRollups WHERE period_end < ?",
+                retain_since
+            )
+            .execute(&mut *tx)
+            .await
+            .into_diagnostic()?;
+
+            tx.commit().await.into_diagnostic()
+        })
+        .await;
+
+    if written.is_err() {
+        state.stats.restore_window(period_start, window);
+    }
+
+    written
+}
+
+/// Periodically roll this node's counters up into the shared database
+pub(crate) fn spawn_worker(state: AppState) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(ROLLUP_INTERVAL);
+        // The first tick is immediate, and there's nothing to roll up yet
+        interval.tick().await;
+
+        loop {
+            interval.tick().await;
+
+            if let Err(e) = rollup(&state).await {
+                error!(error = ?e, "Failed to roll up stats");
+            }
+        }
+    })
+}
+
+/// Add up histograms from many rollups, ignoring any that don't have the buckets we expect
+pub(crate) fn merge_histograms<'a>(histograms: impl IntoIterator<Item = &'a str>) -> Histogram {
+    let mut merged = Histogram::default();
+
+    for histogram in histograms {
+        let Ok(counts) = serde_json::from_str::<Vec<u64>>(histogram) else {
+            continue;
+        };
+        if counts.len() != merged.len() {
+            continue;
+        }
+
+        for (total, count) in merged.iter_mut().zip(counts) {
+            *total += count;
+        }
+    }
+
+    merged
+}
+
+/// The latency `percentile` of requests were at or below, to the resolution of the buckets
+pub(crate) fn latency_percentile(histogram: &Histogram, percentile: f64) -> String {
+    let total = histogram.iter().sum::<u64>();
+    if total == 0 {
+        return "No requests".to_string();
+    }
+
+    let target = (total as f64 * percentile).ceil() as u64;
+    let mut seen = 0;
+    for (bucket, count) in histogram.iter().enumerate() {
+        seen += count;
+        if seen >= target {
+            return match LATENCY_BUCKETS_MS.get(bucket) {
+                Some(bound) => format!("≤ {bound}ms"),
+                None => format!("> {}ms", LATENCY_BUCKETS_MS[LATENCY_BUCKETS_MS.len() - 1]),
+            };
+        }
+    }
+
+    unreachable!("The target is never more than the total")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_hits_and_misses() {
+        let stats = Stats::default();
+
+        stats.record_hit("GET@/about", 100, None);
+        stats.record_hit("GET@/about", 100, Some(Duration::from_millis(20)));
+        stats.record_miss("GET@/", 50, Duration::from_millis(300));
+
+        assert_eq!(stats.hits("GET@/about"), 2);
+        assert_eq!(stats.hits("GET@/"), 0);
+
+        let (_, window) = stats.take_window(Utc::now().naive_utc());
+        assert_eq!((window.hits, window.misses), (2, 1));
+        assert_eq!(window.bytes_from_cache, 200);
+        assert_eq!(window.bytes_from_origin, 50);
+        assert_eq!(window.origin_latency.iter().sum::<u64>(), 2);
+        assert_eq!(window.busiest_urls()[0].0, "GET@/about");
+
+        let (_, window) = stats.take_window(Utc::now().naive_utc());
+        assert_eq!(window.hits, 0);
+    }
+
+    #[test]
+    fn failed_rollups_are_put_back() {
+        let stats = Stats::default();
+        stats.record_hit("GET@/about", 100, None);
+
+        let (period_start, window) = stats.take_window(Utc::now().naive_utc());
+        stats.record_miss("GET@/", 50, Duration::from_millis(300));
+        stats.restore_window(period_start, window);
+
+        let (restored_start, window) = stats.take_window(Utc::now().naive_utc());
+        assert_eq!(restored_start, period_start);
+        assert_eq!((window.hits, window.misses), (1, 1));
+        assert_eq!(window.urls.len(), 2);
+    }
+
+    #[test]
+    fn tracked_keys_are_capped() {
+        let stats = Stats::default();
+        for _ in 0..3 {
+            stats.record_hit("GET@/popular", 10, None);
+        }
+        for i in 0..MAX_TRACKED_KEYS + 10 {
+            stats.record_hit(&format!("GET@/?junk={i}"), 10, None);
+        }
+
+        assert!(stats.hits.lock().unwrap().len() <= MAX_TRACKED_KEYS);
+        assert_eq!(stats.hits("GET@/popular"), 3);
+
+        let (_, window) = stats.take_window(Utc::now().naive_utc());
+        assert_eq!(window.urls.len(), MAX_TRACKED_KEYS);
+        assert_eq!(window.hits, MAX_TRACKED_KEYS as u64 + 13);
+    }
+
+    #[test]
+    fn percentiles_come_from_merged_histograms() {
+        let mut fast = Histogram::default();
+        fast[2] = 9;
+        let mut slow = Histogram::default();
+        slow[LATENCY_BUCKETS_MS.len()] = 1;
+
+        let merged = merge_histograms([
+            serde_json::to_string(&fast).unwrap().as_str(),
+            serde_json::to_string(&slow).unwrap().as_str(),
+            "not json",
+        ]);
+
+        assert_eq!(latency_percentile(&merged, 0.5), "≤ 25ms");
+        assert_eq!(latency_percentile(&merged, 0.95), "> 30000ms");
+        assert_eq!(
+            latency_percentile(&Histogram::default(), 0.5),
+            "No requests"
+        );
+    }
+}
@@ -0,0 +1,87 @@
+use http::HeaderMap;
+use serde_json::Value;
+
+/// Space separated tags, used by Fastly and friends
+const SURROGATE_KEY: &str = "surrogate-key";
+
+/// Comma separated tags, used by Cloudflare
+const CACHE_TAG: &str = "cache-tag";
+
+/// The tags the origin gave a response, for purging everything with a tag at once
+pub(crate) fn tags_from_headers(headers: &HeaderMap) -> Vec<String> {
+    let surrogate_keys = headers
+        .get_all(SURROGATE_KEY)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(str::split_whitespace);
+    let cache_tags = headers
+        .get_all(CACHE_TAG)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim);
+
+    let mut tags = surrogate_keys
+        .chain(cache_tags)
+        .filter(|tag| !tag.is_empty())
+        .map(str::to_string)
+        .collect::<Vec<_>>();
+    tags.sort();
+    tags.dedup();
+
+    tags
+}
+
+/// Tags are for us, not for the people we're proxying to
+pub(crate) fn strip_tag_headers(headers: &mut HeaderMap) {
+    headers.remove(SURROGATE_KEY);
+    headers.remove(CACHE_TAG);
+}
+
+/// The metadata we store next to each entry in the cache index
+pub(crate) fn entry_metadata(tags: &[String]) -> Value {
+    serde_json::json!({ "tags": tags })
+}
+
+/// The tags stored in an entry's metadata, entries cached before we kept tags have none
+pub(crate) fn entry_tags(metadata: &Value) -> impl Iterator<Item = &str> {
+    metadata
+        .get("tags")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter_map(Value::as_str)
+}
+
+#[cfg(test)]
+mod tests {
+    use http::HeaderValue;
+
+    use super::*;
+
+    #[test]
+    fn reads_both_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            SURROGATE_KEY,
+            HeaderValue::from_static("product-1  homepage"),
+        );
+        headers.insert(CACHE_TAG, HeaderValue::from_static("product-1, sale"));
+
+        assert_eq!(
+            tags_from_headers(&headers),
+            vec!["homepage", "product-1", "sale"]
+        );
+
+        strip_tag_headers(&mut headers);
+        assert!(headers.is_empty());
+    }
+
+    #[test]
+    fn tags_round_trip_through_metadata() {
+        let metadata = entry_metadata(&["a".to_string(), "b".to_string()]);
+
+        assert_eq!(entry_tags(&metadata).collect::<Vec<_>>(), vec!["a", "b"]);
+        assert_eq!(entry_tags(&Value::Null).count(), 0);
+    }
+}
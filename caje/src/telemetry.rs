@@ -0,0 +1,146 @@
+use http::{HeaderMap, HeaderName, HeaderValue};
+use miette::{IntoDiagnostic, Result};
+use opentelemetry::{
+    global,
+    propagation::{Extractor, Injector},
+    Context, KeyValue,
+};
+use opentelemetry_sdk::{propagation::TraceContextPropagator, runtime, trace, Resource};
+use tracing::{level_filters::LevelFilter, Span};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, Layer};
+
+use crate::node::{node_name, node_region};
+
+/// Setting either of these turns on exporting spans over OTLP/HTTP, like
+/// `OTEL_EXPORTER_OTLP_ENDPOINT=http://localhost:4318`
+const OTLP_ENDPOINT_VARS: [&str; 2] = [
+    "OTEL_EXPORTER_OTLP_ENDPOINT",
+    "OTEL_EXPORTER_OTLP_TRACES_ENDPOINT",
+];
+
+/// Log to stdout like we always have, and also export spans if an OTLP endpoint is configured
+pub(crate) fn init() -> Result<()> {
+    global::set_text_map_propagator(TraceContextPropagator::new());
+
+    let otlp_enabled = OTLP_ENDPOINT_VARS
+        .iter()
+        .any(|var| std::env::var(var).is_ok());
+    let otel = if otlp_enabled {
+        let mut resource = vec![
+            KeyValue::new("service.name", "caje"),
+            KeyValue::new("service.version", env!("CARGO_PKG_VERSION")),
+            KeyValue::new("service.instance.id", node_name()),
+        ];
+        if let Some(region) = node_region() {
+            resource.push(KeyValue::new("cloud.region", region));
+        }
+
+        // The endpoint comes from the standard OTEL_EXPORTER_OTLP_* variables
+        let tracer = opentelemetry_otlp::new_pipeline()
+            .tracing()
+            .with_exporter(opentelemetry_otlp::new_exporter().http())
+            .with_trace_config(trace::config().with_resource(Resource::new(resource)))
+            .install_batch(runtime::Tokio)
+            .into_diagnostic()?;
+
+        Some(tracing_opentelemetry::layer().with_tracer(tracer))
+    } else {
+        None
+    };
+
+    tracing_subscriber::registry()
+        .with(tracing_subscriber::fmt::layer().with_filter(LevelFilter::INFO))
+        .with(otel.map(|otel| otel.with_filter(LevelFilter::INFO)))
+        .try_init()
+        .into_diagnostic()
+}
+
+/// Export any spans still waiting in the batch, call this before exiting or they're lost
+///
+/// Shutting down blocks until the exporter is done, so it runs off the async runtime
+pub(crate) async fn shutdown() {
+    if let Err(e) = tokio::task::spawn_blocking(global::shutdown_tracer_provider).await {
+        tracing::warn!(error = ?e, "Failed to flush spans");
+    }
+}
+
+struct HeaderInjector<'a>(&'a mut HeaderMap);
+
+impl Injector for HeaderInjector<'_> {
+    fn set(&mut self, key: &str, value: String) {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(key.as_bytes()),
+            HeaderValue::from_str(&value),
+        ) {
+            self.0.insert(name, value);
+        }
+    }
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(HeaderName::as_str).collect()
+    }
+}
+
+fn inject_context(context: &Context, headers: &mut HeaderMap) {
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(context, &mut HeaderInjector(headers))
+    });
+}
+
+fn extract_context(headers: &HeaderMap) -> Context {
+    global::get_text_map_propagator(|propagator| propagator.extract(&HeaderExtractor(headers)))
+}
+
+/// Continue the trace the client started, if it sent a `traceparent`
+pub(crate) fn continue_trace(span: &Span, headers: &HeaderMap) {
+    span.set_parent(extract_context(headers));
+}
+
+/// Add `traceparent` for `span` to a request we are about to send, so the origin can join the trace
+///
+/// Without an OTLP exporter spans have no trace context, and the headers are left alone
+pub(crate) fn propagate_trace(span: &Span, headers: &mut HeaderMap) {
+    inject_context(&span.context(), headers);
+}
+
+#[cfg(test)]
+mod tests {
+    use opentelemetry::trace::{
+        SpanContext, SpanId, TraceContextExt, TraceFlags, TraceId, TraceState,
+    };
+
+    use super::*;
+
+    #[test]
+    fn trace_context_round_trips_through_headers() {
+        global::set_text_map_propagator(TraceContextPropagator::new());
+
+        let span_context = SpanContext::new(
+            TraceId::from_hex("4bf92f3577b34da6a3ce929d0e0e4736").unwrap(),
+            SpanId::from_hex("00f067aa0ba902b7").unwrap(),
+            TraceFlags::SAMPLED,
+            true,
+            TraceState::default(),
+        );
+        let context = Context::new().with_remote_span_context(span_context.clone());
+
+        let mut headers = HeaderMap::new();
+        inject_context(&context, &mut headers);
+        assert_eq!(
+            headers["traceparent"],
+            "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"
+        );
+
+        let extracted = extract_context(&headers);
+        assert_eq!(extracted.span().span_context(), &span_context);
+    }
+}
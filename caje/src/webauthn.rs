@@ -0,0 +1,490 @@
+//! Just enough WebAuthn to log admins in with passkeys
+//!
+//! The usual crate for this (webauthn-rs) pulls in OpenSSL, which we avoid, so this
+//! implements the relying party side of the ceremonies with `ring`. We ask for `none`
+//! attestation, so attestation statements are not verified, only the credential itself.
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use ciborium::Value;
+use miette::{miette, IntoDiagnostic, Result};
+use ring::{
+    digest::{digest, SHA256},
+    rand::{SecureRandom, SystemRandom},
+    signature::{UnparsedPublicKey, VerificationAlgorithm, ECDSA_P256_SHA256_ASN1, ED25519},
+};
+use serde::Deserialize;
+use serde_json::json;
+
+/// COSE algorithm identifiers we know how to verify
+const COSE_ES256: i64 = -7;
+const COSE_EDDSA: i64 = -8;
+
+const FLAG_USER_PRESENT: u8 = 0x01;
+const FLAG_ATTESTED_CREDENTIAL_DATA: u8 = 0x40;
+
+/// How long the browser gives the user to complete a ceremony, in milliseconds
+const CEREMONY_TIMEOUT_MS: u64 = 60_000;
+
+#[derive(Debug, Clone)]
+pub(crate) struct RelyingParty {
+    /// The domain passkeys are scoped to, eg `caje.fly.dev`
+    pub id: String,
+    /// The exact origin the admin dashboard is served from, eg `https://caje.fly.dev`
+    pub origin: String,
+}
+
+/// The browser's `PublicKeyCredential` from `navigator.credentials.create()`, base64url encoded
+#[derive(Debug, Deserialize)]
+pub(crate) struct RegistrationResponse {
+    pub id: String,
+    pub client_data_json: String,
+    pub attestation_object: String,
+}
+
+/// The browser's `PublicKeyCredential` from `navigator.credentials.get()`, base64url encoded
+#[derive(Debug, Deserialize)]
+pub(crate) struct AuthenticationResponse {
+    pub id: String,
+    pub client_data_json: String,
+    pub authenticator_data: String,
+    pub signature: String,
+}
+
+/// A newly registered credential, ready to be stored
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct VerifiedCredential {
+    pub credential_id: String,
+    pub public_key: Vec<u8>,
+    pub algorithm: i64,
+    pub sign_count: u32,
+}
+
+/// The parts of a stored credential needed to check an assertion
+#[derive(Debug, Clone)]
+pub(crate) struct StoredCredential<'a> {
+    pub public_key: &'a [u8],
+    pub algorithm: i64,
+    pub sign_count: u32,
+}
+
+#[derive(Debug, Deserialize)]
+struct ClientData {
+    #[serde(rename = "type")]
+    ceremony: String,
+    challenge: String,
+    origin: String,
+}
+
+struct AuthenticatorData<'a> {
+    raw: &'a [u8],
+    rp_id_hash: &'a [u8],
+    flags: u8,
+    sign_count: u32,
+}
+
+pub(crate) fn new_challenge() -> Result<String> {
+    let mut challenge = [0u8; 32];
+    SystemRandom::new()
+        .fill(&mut challenge)
+        .map_err(|_| miette!("Could not generate a WebAuthn challenge"))?;
+
+    Ok(URL_SAFE_NO_PAD.encode(challenge))
+}
+
+impl RelyingParty {
+    /// Options for `navigator.credentials.create()`, with binary fields base64url encoded
+    pub(crate) fn creation_options(
+        &self,
+        challenge: &str,
+        user_id: i64,
+        username: &str,
+        existing_credential_ids: &[String],
+    ) -> serde_json::Value {
+        json!({
+            "challenge": challenge,
+            "rp": { "id": self.id, "name": "caje" },
+            "user": {
+                "id": URL_SAFE_NO_PAD.encode(user_id.to_string()),
+                "name": username,
+                "displayName": username,
+            },
+            "pubKeyCredParams": [
+                { "type": "public-key", "alg": COSE_ES256 },
+                { "type": "public-key", "alg": COSE_EDDSA },
+            ],
+            "excludeCredentials": existing_credential_ids
+                .iter()
+                .map(|id| json!({ "type": "public-key", "id": id }))
+                .collect::<Vec<_>>(),
+            "authenticatorSelection": {
+                "residentKey": "required",
+                "userVerification": "preferred",
+            },
+            "attestation": "none",
+            "timeout": CEREMONY_TIMEOUT_MS,
+        })
+    }
+
+    /// Options for `navigator.credentials.get()`, with binary fields base64url encoded
+    ///
+    /// We rely on discoverable credentials, so there is no allow list
+    pub(crate) fn request_options(&self, challenge: &str) -> serde_json::Value {
+        json!({
+            "challenge": challenge,
+            "rpId": self.id,
+            "userVerification": "preferred",
+            "timeout": CEREMONY_TIMEOUT_MS,
+        })
+    }
+
+    pub(crate) fn verify_registration(
+        &self,
+        challenge: &str,
+        response: &RegistrationResponse,
+    ) -> Result<VerifiedCredential> {
+        let client_data_json = decode(&response.client_data_json)?;
+        self.verify_client_data(&client_data_json, "webauthn.create", challenge)?;
+
+        let attestation_object: Value =
+            ciborium::from_reader(decode(&response.attestation_object)?.as_slice())
+                .into_diagnostic()?;
+        let auth_data = map_get(&attestation_object, &Value::Text("authData".to_string()))
+            .and_then(Value::as_bytes)
+            .ok_or_else(|| miette!("Attestation object is missing authData"))?;
+
+        let auth_data = self.verify_authenticator_data(auth_data)?;
+        if auth_data.flags & FLAG_ATTESTED_CREDENTIAL_DATA == 0 {
+            return Err(miette!("Authenticator did not include a credential"));
+        }
+
+        // aaguid (16 bytes), then a u16 length prefixed credential id, then the COSE key
+        let attested = auth_data
+            .raw
+            .get(37 + 16..)
+            .ok_or_else(|| miette!("Attested credential data is truncated"))?;
+        if attested.len() < 2 {
+            return Err(miette!("Attested credential data is truncated"));
+        }
+        let id_len = u16::from_be_bytes([attested[0], attested[1]]) as usize;
+        let rest = &attested[2..];
+        if rest.len() < id_len {
+            return Err(miette!("Attested credential data is truncated"));
+        }
+        let (credential_id, cose_key) = rest.split_at(id_len);
+
+        let credential_id = URL_SAFE_NO_PAD.encode(credential_id);
+        if credential_id != response.id {
+            return Err(miette!(
+                "Credential id does not match the authenticator data"
+            ));
+        }
+
+        let cose_key: Value = ciborium::from_reader(cose_key).into_diagnostic()?;
+        let (algorithm, public_key) = public_key_from_cose(&cose_key)?;
+
+        Ok(VerifiedCredential {
+            credential_id,
+            public_key,
+            algorithm,
+            sign_count: auth_data.sign_count,
+        })
+    }
+
+    /// Check an assertion against a stored credential, returning the new signature counter
+    pub(crate) fn verify_authentication(
+        &self,
+        challenge: &str,
+        response: &AuthenticationResponse,
+        credential: &StoredCredential,
+    ) -> Result<u32> {
+        let client_data_json = decode(&response.client_data_json)?;
+        self.verify_client_data(&client_data_json, "webauthn.get", challenge)?;
+
+        let raw_auth_data = decode(&response.authenticator_data)?;
+        let auth_data = self.verify_authenticator_data(&raw_auth_data)?;
+
+        let mut signed = auth_data.raw.to_vec();
+        signed.extend_from_slice(digest(&SHA256, &client_data_json).as_ref());
+
+        let algorithm: &'static dyn VerificationAlgorithm = match credential.algorithm {
+            COSE_ES256 => &ECDSA_P256_SHA256_ASN1,
+            COSE_EDDSA => &ED25519,
+            other => return Err(miette!("Unsupported credential algorithm {other}")),
+        };
+        UnparsedPublicKey::new(algorithm, credential.public_key)
+            .verify(&signed, &decode(&response.signature)?)
+            .map_err(|_| miette!("Passkey signature is invalid"))?;
+
+        // Authenticators that don't count always report 0, otherwise it must go up
+        let counting = auth_data.sign_count != 0 || credential.sign_count != 0;
+        if counting && auth_data.sign_count <= credential.sign_count {
+            return Err(miette!(
+                "Passkey signature counter went backwards, it may have been cloned"
+            ));
+        }
+
+        Ok(auth_data.sign_count)
+    }
+
+    fn verify_client_data(&self, raw: &[u8], ceremony: &str, challenge: &str) -> Result<()> {
+        let client_data: ClientData = serde_json::from_slice(raw).into_diagnostic()?;
+
+        if client_data.ceremony != ceremony {
+            return Err(miette!("Expected a {ceremony} ceremony"));
+        }
+        if client_data.challenge != challenge {
+            return Err(miette!("WebAuthn challenge does not match"));
+        }
+        if client_data.origin != self.origin {
+            return Err(miette!(
+                "WebAuthn origin {} is not {}",
+                client_data.origin,
+                self.origin
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn verify_authenticator_data<'a>(&self, raw: &'a [u8]) -> Result<AuthenticatorData<'a>> {
+        if raw.len() < 37 {
+            return Err(miette!("Authenticator data is truncated"));
+        }
+
+        let auth_data = AuthenticatorData {
+            raw,
+            rp_id_hash: &raw[..32],
+            flags: raw[32],
+            sign_count: u32::from_be_bytes([raw[33], raw[34], raw[35], raw[36]]),
+        };
+
+        if auth_data.rp_id_hash != digest(&SHA256, self.id.as_bytes()).as_ref() {
+            return Err(miette!("Passkey is for a different relying party"));
+        }
+        if auth_data.flags & FLAG_USER_PRESENT == 0 {
+            return Err(miette!("User was not present"));
+        }
+
+        Ok(auth_data)
+    }
+}
+
+fn decode(encoded: &str) -> Result<Vec<u8>> {
+    URL_SAFE_NO_PAD.decode(encoded).into_diagnostic()
+}
+
+fn map_get<'a>(map: &'a Value, key: &Value) -> Option<&'a Value> {
+    map.as_map()?.iter().find(|(k, _)| k == key).map(|(_, v)| v)
+}
+
+/// Turn a COSE_Key into the raw public key format `ring` expects
+fn public_key_from_cose(cose_key: &Value) -> Result<(i64, Vec<u8>)> {
+    let label = |l: i64| Value::Integer(l.into());
+    let bytes = |l: i64| {
+        map_get(cose_key, &label(l))
+            .and_then(Value::as_bytes)
+            .ok_or_else(|| miette!("COSE key is missing parameter {l}"))
+    };
+
+    let algorithm = map_get(cose_key, &label(3))
+        .and_then(Value::as_integer)
+        .and_then(|alg| i64::try_from(alg).ok())
+        .ok_or_else(|| miette!("COSE key is missing its algorithm"))?;
+
+    match algorithm {
+        COSE_ES256 => {
+            let mut public_key = vec![0x04];
+            public_key.extend_from_slice(bytes(-2)?);
+            public_key.extend_from_slice(bytes(-3)?);
+
+            Ok((algorithm, public_key))
+        }
+        COSE_EDDSA => Ok((algorithm, bytes(-2)?.clone())),
+        other => Err(miette!("Unsupported credential algorithm {other}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_ASN1_SIGNING};
+
+    use super::*;
+
+    const CREDENTIAL_ID: &[u8] = b"test-credential";
+
+    fn relying_party() -> RelyingParty {
+        RelyingParty {
+            id: "caje.example".to_string(),
+            origin: "https://caje.example".to_string(),
+        }
+    }
+
+    fn key_pair() -> EcdsaKeyPair {
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &rng).unwrap();
+
+        EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8.as_ref(), &rng).unwrap()
+    }
+
+    fn client_data(ceremony: &str, challenge: &str) -> Vec<u8> {
+        json!({
+            "type": ceremony,
+            "challenge": challenge,
+            "origin": "https://caje.example",
+        })
+        .to_string()
+        .into_bytes()
+    }
+
+    fn auth_data(flags: u8, sign_count: u32) -> Vec<u8> {
+        let mut auth_data = digest(&SHA256, b"caje.example").as_ref().to_vec();
+        auth_data.push(flags);
+        auth_data.extend_from_slice(&sign_count.to_be_bytes());
+        auth_data
+    }
+
+    fn register(key_pair: &EcdsaKeyPair, challenge: &str) -> RegistrationResponse {
+        // Uncompressed point is 0x04 || x || y
+        let point = key_pair.public_key().as_ref();
+        let cose_key = Value::Map(vec![
+            (Value::Integer(1.into()), Value::Integer(2.into())),
+            (Value::Integer(3.into()), Value::Integer(COSE_ES256.into())),
+            (Value::Integer((-1).into()), Value::Integer(1.into())),
+            (
+                Value::Integer((-2).into()),
+                Value::Bytes(point[1..33].to_vec()),
+            ),
+            (
+                Value::Integer((-3).into()),
+                Value::Bytes(point[33..].to_vec()),
+            ),
+        ]);
+
+        let mut auth_data = auth_data(FLAG_USER_PRESENT | FLAG_ATTESTED_CREDENTIAL_DATA, 0);
+        auth_data.extend_from_slice(&[0; 16]);
+        auth_data.extend_from_slice(&(CREDENTIAL_ID.len() as u16).to_be_bytes());
+        auth_data.extend_from_slice(CREDENTIAL_ID);
+        ciborium::into_writer(&cose_key, &mut auth_data).unwrap();
+
+        let attestation_object = Value::Map(vec![
+            (Value::Text("fmt".into()), Value::Text("none".into())),
+            (Value::Text("attStmt".into()), Value::Map(vec![])),
+            (Value::Text("authData".into()), Value::Bytes(auth_data)),
+        ]);
+        let mut attestation_bytes = vec![];
+        ciborium::into_writer(&attestation_object, &mut attestation_bytes).unwrap();
+
+        RegistrationResponse {
+            id: URL_SAFE_NO_PAD.encode(CREDENTIAL_ID),
+            client_data_json: URL_SAFE_NO_PAD.encode(client_data("webauthn.create", challenge)),
+            attestation_object: URL_SAFE_NO_PAD.encode(attestation_bytes),
+        }
+    }
+
+    fn authenticate(
+        key_pair: &EcdsaKeyPair,
+        challenge: &str,
+        sign_count: u32,
+    ) -> AuthenticationResponse {
+        let auth_data = auth_data(FLAG_USER_PRESENT, sign_count);
+        let client_data = client_data("webauthn.get", challenge);
+
+        let mut signed = auth_data.clone();
+        signed.extend_from_slice(digest(&SHA256, &client_data).as_ref());
+        let signature = key_pair.sign(&SystemRandom::new(), &signed).unwrap();
+
+        AuthenticationResponse {
+            id: URL_SAFE_NO_PAD.encode(CREDENTIAL_ID),
+            client_data_json: URL_SAFE_NO_PAD.encode(client_data),
+            authenticator_data: URL_SAFE_NO_PAD.encode(auth_data),
+            signature: URL_SAFE_NO_PAD.encode(signature.as_ref()),
+        }
+    }
+
+    #[test]
+    fn register_then_authenticate() {
+        let rp = relying_party();
+        let key_pair = key_pair();
+
+        let challenge = new_challenge().unwrap();
+        let credential = rp
+            .verify_registration(&challenge, &register(&key_pair, &challenge))
+            .unwrap();
+        assert_eq!(credential.algorithm, COSE_ES256);
+        assert_eq!(credential.public_key, key_pair.public_key().as_ref());
+
+        let stored = StoredCredential {
+            public_key: &credential.public_key,
+            algorithm: credential.algorithm,
+            sign_count: credential.sign_count,
+        };
+        let challenge = new_challenge().unwrap();
+        let sign_count = rp
+            .verify_authentication(&challenge, &authenticate(&key_pair, &challenge, 1), &stored)
+            .unwrap();
+        assert_eq!(sign_count, 1);
+    }
+
+    #[test]
+    fn registration_for_another_challenge_is_rejected() {
+        let rp = relying_party();
+        let response = register(&key_pair(), &new_challenge().unwrap());
+
+        assert!(rp
+            .verify_registration(&new_challenge().unwrap(), &response)
+            .is_err());
+    }
+
+    #[test]
+    fn registration_from_another_origin_is_rejected() {
+        let rp = RelyingParty {
+            origin: "https://evil.example".to_string(),
+            ..relying_party()
+        };
+        let challenge = new_challenge().unwrap();
+
+        assert!(rp
+            .verify_registration(&challenge, &register(&key_pair(), &challenge))
+            .is_err());
+    }
+
+    #[test]
+    fn authentication_with_another_key_is_rejected() {
+        let rp = relying_party();
+        let registered = key_pair();
+
+        let challenge = new_challenge().unwrap();
+        let credential = rp
+            .verify_registration(&challenge, &register(&registered, &challenge))
+            .unwrap();
+        let stored = StoredCredential {
+            public_key: &credential.public_key,
+            algorithm: credential.algorithm,
+            sign_count: 0,
+        };
+
+        let challenge = new_challenge().unwrap();
+        let response = authenticate(&key_pair(), &challenge, 1);
+        assert!(rp
+            .verify_authentication(&challenge, &response, &stored)
+            .is_err());
+    }
+
+    #[test]
+    fn authentication_with_a_stale_counter_is_rejected() {
+        let rp = relying_party();
+        let key_pair = key_pair();
+        let public_key = key_pair.public_key().as_ref().to_vec();
+        let stored = StoredCredential {
+            public_key: &public_key,
+            algorithm: COSE_ES256,
+            sign_count: 5,
+        };
+
+        let challenge = new_challenge().unwrap();
+        let response = authenticate(&key_pair, &challenge, 5);
+        assert!(rp
+            .verify_authentication(&challenge, &response, &stored)
+            .is_err());
+    }
+}
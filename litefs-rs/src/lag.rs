@@ -0,0 +1,100 @@
+use std::{num::ParseIntError, time::Duration};
+
+use thiserror::Error;
+use tokio::{sync::watch, task::JoinHandle};
+use tracing::warn;
+
+use crate::mount_dir;
+
+#[derive(Error, Debug)]
+pub enum LagError {
+    #[error("Could not read the LiteFS lag file")]
+    Io(#[from] std::io::Error),
+
+    #[error("Could not parse LiteFS lag from {contents:?}")]
+    Parse {
+        contents: String,
+        #[source]
+        source: ParseIntError,
+    },
+}
+
+/// How far behind the primary this node is, as reported by the `.lag` file
+pub fn lag(database_path: &str) -> Result<Duration, LagError> {
+    let lagfile = mount_dir(database_path)?.join(".lag");
+    let lag = std::fs::read_to_string(lagfile)?;
+
+    let millis = lag
+        .trim()
+        .parse::<u64>()
+        .map_err(|source| LagError::Parse {
+            contents: lag.clone(),
+            source,
+        })?;
+
+    Ok(Duration::from_millis(millis))
+}
+
+/// Polls the `.lag` file in the background and publishes the latest value
+///
+/// The value is `None` until the first successful read, and goes back to `None`
+/// whenever the lag can't be read, so callers should treat it as 'unknown'
+#[derive(Debug)]
+pub struct LagWatcher {
+    receiver: watch::Receiver<Option<Duration>>,
+    task: JoinHandle<()>,
+}
+
+impl LagWatcher {
+    /// Start polling the lag for `database_path` every `interval`
+    ///
+    /// Must be called from within a Tokio runtime
+    pub fn spawn(database_path: impl Into<String>, interval: Duration) -> Self {
+        let database_path = database_path.into();
+        let (sender, receiver) = watch::channel(None);
+
+        let task = tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+
+            loop {
+                interval.tick().await;
+
+                let current = match lag(&database_path) {
+                    Ok(lag) => Some(lag),
+                    Err(e) => {
+                        warn!(error = %e, "Could not read LiteFS lag");
+                        None
+                    }
+                };
+
+                sender.send_if_modified(|previous| {
+                    let changed = *previous != current;
+                    *previous = current;
+                    changed
+                });
+
+                if sender.is_closed() {
+                    break;
+                }
+            }
+        });
+
+        Self { receiver, task }
+    }
+
+    /// The most recently observed lag
+    pub fn current(&self) -> Option<Duration> {
+        *self.receiver.borrow()
+    }
+
+    /// A channel that is notified every time the observed lag changes
+    pub fn subscribe(&self) -> watch::Receiver<Option<Duration>> {
+        self.receiver.clone()
+    }
+}
+
+impl Drop for LagWatcher {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
@@ -1,113 +1,206 @@
 use std::{
     fmt::Display,
     fs::{File, OpenOptions},
+    io,
     os::{fd::AsRawFd, unix::prelude::OpenOptionsExt},
     path::PathBuf,
-    time::Duration,
+    str::FromStr,
+    time::{Duration, Instant},
 };
 
-use libc::flock;
-use thiserror::Error;
-use tracing::info;
+use libc::{c_int, flock};
+
+mod lag;
+pub use lag::{lag, LagError, LagWatcher};
+
+#[cfg(feature = "test-support")]
+pub mod test_support;
 
 const HALT_BYTE: i64 = 72;
 
-pub fn halt(lockfile: &File) -> Result<(), FlockError> {
-    // let (flock, lock_type) = get_flock_and_type();
+/// How long we sleep between attempts when waiting on LiteFS (HALT lock, position, etc)
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+// LiteFS only looks at Open File Description locks, which are Linux specific.
+// On other platforms we fall back to the process-associated variants so the crate
+// still compiles, even though LiteFS itself won't be there to see them.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const F_OFD_SETLK: c_int = libc::F_OFD_SETLK;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const F_OFD_SETLKW: c_int = libc::F_OFD_SETLKW;
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+const F_OFD_SETLK: c_int = libc::F_SETLK;
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+const F_OFD_SETLKW: c_int = libc::F_SETLKW;
+
+/// Acquire the HALT lock, blocking until the primary hands it to us
+///
+/// If the primary is unreachable this will wait forever, prefer `halt_with_timeout`
+pub fn halt(lockfile: &File) -> io::Result<()> {
+    set_lock(lockfile, F_OFD_SETLKW, libc::F_WRLCK)
+}
 
-    let fd = lockfile.as_raw_fd();
-    let mut flock = get_flock();
+/// Try to acquire the HALT lock without blocking
+///
+/// Returns an error of kind `WouldBlock` if someone else currently holds the lock
+pub fn try_halt(lockfile: &File) -> io::Result<()> {
+    set_lock(lockfile, F_OFD_SETLK, libc::F_WRLCK).map_err(|e| match e.raw_os_error() {
+        // POSIX allows either of these when the lock is held elsewhere
+        Some(libc::EACCES) | Some(libc::EAGAIN) => io::Error::from(io::ErrorKind::WouldBlock),
+        _ => e,
+    })
+}
 
-    // F_OFD_SETLKW = 38
-    let rv = unsafe { libc::fcntl(fd, 38, &mut flock) };
-    if rv == 0 {
-        Ok(())
-    } else {
-        #[cfg(not(target_os = "macos"))]
-        let errno_ptr = unsafe { libc::__errno_location() };
-        #[cfg(target_os = "macos")]
-        let errno_ptr = unsafe { libc::__error() };
-
-        let errno = if errno_ptr.is_null() {
-            None
-        } else {
-            // *should* be safe here as we checked against NULL pointer..
-            Some(unsafe { *errno_ptr })
-        };
-        Err(FlockError { errno, rv })
+/// Acquire the HALT lock, giving up with a `TimedOut` error after `timeout`
+pub fn halt_with_timeout(lockfile: &File, timeout: Duration) -> io::Result<()> {
+    let deadline = Instant::now() + timeout;
+
+    loop {
+        match try_halt(lockfile) {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            result => return result,
+        }
+
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("Could not acquire HALT lock within {timeout:?}"),
+            ));
+        }
+
+        std::thread::sleep(POLL_INTERVAL.min(deadline - now));
     }
 }
 
-#[derive(Error, Debug)]
-pub struct FlockError {
-    pub errno: Option<i32>,
-    pub rv: i32,
+pub fn unhalt(lockfile: &File) -> io::Result<()> {
+    set_lock(lockfile, F_OFD_SETLK, libc::F_UNLCK)
 }
 
-impl Display for FlockError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "Flock Error | rv={} errno={}",
-            self.rv,
-            self.errno.map(|x| x.to_string()).unwrap_or_default()
-        )
+/// The hostname of the current primary, or `None` if this node is the primary
+///
+/// LiteFS only writes the `.primary` file on replicas
+pub fn primary_hostname(database_path: &str) -> io::Result<Option<String>> {
+    let primary_file = mount_dir(database_path)?.join(".primary");
+
+    match std::fs::read_to_string(primary_file) {
+        Ok(hostname) => Ok(Some(hostname.trim().to_string())),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
     }
 }
 
-pub fn unhalt(lockfile: &File) -> Result<(), FlockError> {
-    let mut flock = get_flock();
-    flock.l_type = libc::F_UNLCK.try_into().unwrap();
+pub fn is_primary(database_path: &str) -> io::Result<bool> {
+    Ok(primary_hostname(database_path)?.is_none())
+}
 
-    let fd = lockfile.as_raw_fd();
+/// The replication position of a database, as reported by its `-pos` file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Position {
+    pub txid: u64,
+    pub post_apply_checksum: u64,
+}
 
-    let rv = unsafe { libc::fcntl(fd, 38, &mut flock) };
-    if rv == 0 {
-        Ok(())
-    } else {
-        #[cfg(not(target_os = "macos"))]
-        let errno_ptr = unsafe { libc::__errno_location() };
-        #[cfg(target_os = "macos")]
-        let errno_ptr = unsafe { libc::__error() };
-
-        let errno = if errno_ptr.is_null() {
-            None
-        } else {
-            // *should* be safe here as we checked against NULL pointer..
-            Some(unsafe { *errno_ptr })
+impl FromStr for Position {
+    type Err = io::Error;
+
+    /// Parses the `{txid}/{checksum}` format LiteFS uses, both as 16 digit hex
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid LiteFS position: {s:?}"),
+            )
         };
-        Err(FlockError { errno, rv })
+
+        let (txid, checksum) = s.trim().split_once('/').ok_or_else(invalid)?;
+
+        Ok(Position {
+            txid: u64::from_str_radix(txid, 16).map_err(|_| invalid())?,
+            post_apply_checksum: u64::from_str_radix(checksum, 16).map_err(|_| invalid())?,
+        })
     }
 }
 
-pub fn lag(database_path: &str) -> std::io::Result<Duration> {
-    let database_path = PathBuf::from(database_path);
-    let parent = database_path.parent().ok_or_else(|| {
-        std::io::Error::new(
-            std::io::ErrorKind::NotFound,
-            "Couldnt get parent directory of DB",
-        )
-    })?;
+impl Display for Position {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:016x}/{:016x}", self.txid, self.post_apply_checksum)
+    }
+}
 
-    let lagfile = parent.join(".lag");
-    let lag = std::fs::read_to_string(lagfile)?;
-    info!(lag, "Stringy Lag");
+pub fn position(database_path: &str) -> io::Result<Position> {
+    let pos = std::fs::read_to_string(format!("{database_path}-pos"))?;
 
-    let lag = lag.trim().parse::<u64>().unwrap();
-    let lag = Duration::from_millis(lag);
+    pos.parse()
+}
 
-    Ok(lag)
+/// Wait until this node has applied at least `txid`, giving up with a `TimedOut` error after `timeout`
+pub fn wait_for_position(
+    database_path: &str,
+    txid: u64,
+    timeout: Duration,
+) -> io::Result<Position> {
+    let deadline = Instant::now() + timeout;
+
+    loop {
+        let now = Instant::now();
+
+        match position(database_path) {
+            Ok(pos) if pos.txid >= txid => return Ok(pos),
+            Ok(pos) if now >= deadline => {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!(
+                        "Still at TXID {} after waiting {timeout:?} for {txid}",
+                        pos.txid
+                    ),
+                ))
+            }
+            Ok(_) => {}
+            // We can catch the file half written, that's just not caught up yet
+            Err(e) if e.kind() == io::ErrorKind::InvalidData && now < deadline => {}
+            Err(e) => return Err(e),
+        }
+
+        std::thread::sleep(POLL_INTERVAL.min(deadline - now));
+    }
 }
 
 pub fn lockfile(database_path: &str) -> Result<std::fs::File, std::io::Error> {
     let lockfile_path = format!("{database_path}-lock");
-    let fd = OpenOptions::new()
+    OpenOptions::new()
         .read(true)
         .write(true)
         .create(true)
+        .truncate(false)
         .mode(0o666)
-        .open(lockfile_path);
-    fd
+        .open(lockfile_path)
+}
+
+/// The LiteFS mount directory, where the `.lag` and `.primary` files live
+pub(crate) fn mount_dir(database_path: &str) -> io::Result<PathBuf> {
+    let database_path = PathBuf::from(database_path);
+    let parent = database_path.parent().ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            "Couldnt get parent directory of DB",
+        )
+    })?;
+
+    Ok(parent.to_path_buf())
+}
+
+fn set_lock(lockfile: &File, cmd: c_int, lock_type: c_int) -> io::Result<()> {
+    let fd = lockfile.as_raw_fd();
+    let mut flock = get_flock();
+    flock.l_type = lock_type.try_into().unwrap();
+
+    let rv = unsafe { libc::fcntl(fd, cmd, &mut flock) };
+    if rv == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
 }
 
 fn get_flock() -> flock {
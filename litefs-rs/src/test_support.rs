@@ -0,0 +1,113 @@
+//! A stand-in for a LiteFS mount, so halt, lag and primary handling can be
+//! tested without FUSE or a cluster.
+//!
+//! LiteFS exposes everything through plain files next to the database, so we
+//! lay out the same files in a temp dir and let tests poke at them.
+
+use std::{fs::File, io, path::Path, time::Duration};
+
+use tempfile::TempDir;
+
+use crate::{lockfile, Position};
+
+const DATABASE_NAME: &str = "db";
+
+#[derive(Debug)]
+pub struct FakeLiteFs {
+    dir: TempDir,
+    database_path: String,
+}
+
+impl FakeLiteFs {
+    /// Create a mount that looks like a freshly started primary with no lag
+    pub fn new() -> io::Result<Self> {
+        let dir = tempfile::tempdir()?;
+        let database_path = dir
+            .path()
+            .join(DATABASE_NAME)
+            .to_str()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Non UTF-8 temp dir"))?
+            .to_string();
+
+        File::create(&database_path)?;
+        lockfile(&database_path)?;
+
+        let fake = Self { dir, database_path };
+        fake.set_lag(Duration::ZERO)?;
+        fake.set_position(Position {
+            txid: 1,
+            post_apply_checksum: 0,
+        })?;
+
+        Ok(fake)
+    }
+
+    /// Path to pass to the `litefs_rs` functions, like `DATABASE_PATH` in production
+    pub fn database_path(&self) -> &str {
+        &self.database_path
+    }
+
+    pub fn mount_dir(&self) -> &Path {
+        self.dir.path()
+    }
+
+    pub fn set_lag(&self, lag: Duration) -> io::Result<()> {
+        self.write_lag(&format!("{}\n", lag.as_millis()))
+    }
+
+    /// Write arbitrary contents to `.lag`, useful for exercising parse errors
+    pub fn write_lag(&self, contents: &str) -> io::Result<()> {
+        write_atomically(&self.mount_dir().join(".lag"), contents)
+    }
+
+    /// Make this node a replica of `hostname`, or the primary if `None`
+    pub fn set_primary(&self, hostname: Option<&str>) -> io::Result<()> {
+        let primary_file = self.mount_dir().join(".primary");
+
+        match hostname {
+            Some(hostname) => std::fs::write(primary_file, format!("{hostname}\n")),
+            None => match std::fs::remove_file(primary_file) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+                _ => Ok(()),
+            },
+        }
+    }
+
+    pub fn set_position(&self, position: Position) -> io::Result<()> {
+        write_atomically(
+            Path::new(&format!("{}-pos", self.database_path)),
+            &format!("{position}\n"),
+        )
+    }
+
+    /// Simulate the primary holding the HALT byte, as it does while another node writes
+    ///
+    /// The lock is released when the returned guard is dropped
+    pub fn hold_halt(&self) -> io::Result<HeldHalt> {
+        let lockfile = lockfile(&self.database_path)?;
+        crate::try_halt(&lockfile)?;
+
+        Ok(HeldHalt { lockfile })
+    }
+}
+
+/// Replace `path` all at once, so readers polling it never see it truncated or half written
+fn write_atomically(path: &Path, contents: &str) -> io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+
+    std::fs::write(&tmp, contents)?;
+    std::fs::rename(tmp, path)
+}
+
+/// The HALT lock held by the simulated primary, see `FakeLiteFs::hold_halt`
+#[derive(Debug)]
+pub struct HeldHalt {
+    lockfile: File,
+}
+
+impl Drop for HeldHalt {
+    fn drop(&mut self) {
+        let _ = crate::unhalt(&self.lockfile);
+    }
+}
@@ -0,0 +1,189 @@
+use std::{io::ErrorKind, time::Duration};
+
+use litefs_rs::{test_support::FakeLiteFs, LagError, LagWatcher, Position};
+
+#[test]
+fn halt_and_unhalt_when_nobody_holds_the_lock() {
+    let fake = FakeLiteFs::new().unwrap();
+    let lockfile = litefs_rs::lockfile(fake.database_path()).unwrap();
+
+    litefs_rs::halt(&lockfile).unwrap();
+    litefs_rs::unhalt(&lockfile).unwrap();
+}
+
+#[test]
+fn try_halt_would_block_while_primary_holds_halt() {
+    let fake = FakeLiteFs::new().unwrap();
+    let lockfile = litefs_rs::lockfile(fake.database_path()).unwrap();
+
+    let held = fake.hold_halt().unwrap();
+    let err = litefs_rs::try_halt(&lockfile).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::WouldBlock);
+
+    drop(held);
+    litefs_rs::try_halt(&lockfile).unwrap();
+}
+
+#[test]
+fn halt_with_timeout_gives_up() {
+    let fake = FakeLiteFs::new().unwrap();
+    let lockfile = litefs_rs::lockfile(fake.database_path()).unwrap();
+
+    let _held = fake.hold_halt().unwrap();
+    let err = litefs_rs::halt_with_timeout(&lockfile, Duration::from_millis(50)).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::TimedOut);
+}
+
+#[test]
+fn halt_with_timeout_waits_for_release() {
+    let fake = FakeLiteFs::new().unwrap();
+    let lockfile = litefs_rs::lockfile(fake.database_path()).unwrap();
+
+    let held = fake.hold_halt().unwrap();
+    let releaser = std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_millis(50));
+        drop(held);
+    });
+
+    litefs_rs::halt_with_timeout(&lockfile, Duration::from_secs(5)).unwrap();
+    releaser.join().unwrap();
+
+    // We hold it now, so the simulated primary can't
+    assert_eq!(fake.hold_halt().unwrap_err().kind(), ErrorKind::WouldBlock);
+}
+
+#[test]
+fn lag_is_read_in_millis() {
+    let fake = FakeLiteFs::new().unwrap();
+    assert_eq!(
+        litefs_rs::lag(fake.database_path()).unwrap(),
+        Duration::ZERO
+    );
+
+    fake.set_lag(Duration::from_millis(1500)).unwrap();
+    assert_eq!(
+        litefs_rs::lag(fake.database_path()).unwrap(),
+        Duration::from_millis(1500)
+    );
+}
+
+#[test]
+fn lag_with_garbage_is_a_parse_error() {
+    let fake = FakeLiteFs::new().unwrap();
+    fake.write_lag("not a number").unwrap();
+
+    let err = litefs_rs::lag(fake.database_path()).unwrap_err();
+    assert!(matches!(err, LagError::Parse { contents, .. } if contents == "not a number"));
+}
+
+#[test]
+fn primary_detection() {
+    let fake = FakeLiteFs::new().unwrap();
+    assert!(litefs_rs::is_primary(fake.database_path()).unwrap());
+    assert_eq!(
+        litefs_rs::primary_hostname(fake.database_path()).unwrap(),
+        None
+    );
+
+    fake.set_primary(Some("caje-ewr")).unwrap();
+    assert!(!litefs_rs::is_primary(fake.database_path()).unwrap());
+    assert_eq!(
+        litefs_rs::primary_hostname(fake.database_path()).unwrap(),
+        Some("caje-ewr".to_string())
+    );
+
+    fake.set_primary(None).unwrap();
+    assert!(litefs_rs::is_primary(fake.database_path()).unwrap());
+}
+
+#[test]
+fn position_round_trips_through_pos_file() {
+    let fake = FakeLiteFs::new().unwrap();
+    let position = Position {
+        txid: 0x2a,
+        post_apply_checksum: 0xd4f06a7ecf5d1a4b,
+    };
+    fake.set_position(position).unwrap();
+
+    assert_eq!(litefs_rs::position(fake.database_path()).unwrap(), position);
+    assert_eq!(position.to_string(), "000000000000002a/d4f06a7ecf5d1a4b");
+}
+
+#[test]
+fn wait_for_position_waits_for_txid() {
+    let fake = FakeLiteFs::new().unwrap();
+    let database_path = fake.database_path().to_string();
+
+    let err =
+        litefs_rs::wait_for_position(&database_path, 2, Duration::from_millis(30)).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::TimedOut);
+
+    std::thread::scope(|s| {
+        s.spawn(|| {
+            std::thread::sleep(Duration::from_millis(50));
+            fake.set_position(Position {
+                txid: 3,
+                post_apply_checksum: 0,
+            })
+            .unwrap();
+        });
+
+        let pos = litefs_rs::wait_for_position(&database_path, 2, Duration::from_secs(5)).unwrap();
+        assert_eq!(pos.txid, 3);
+    });
+}
+
+#[test]
+fn wait_for_position_retries_a_half_written_pos_file() {
+    let fake = FakeLiteFs::new().unwrap();
+    let pos_path = format!("{}-pos", fake.database_path());
+    std::fs::write(&pos_path, "0000000000").unwrap();
+
+    // Reading it directly is still an error, only waiting treats it as not caught up yet
+    assert_eq!(
+        litefs_rs::position(fake.database_path())
+            .unwrap_err()
+            .kind(),
+        ErrorKind::InvalidData
+    );
+
+    std::thread::scope(|s| {
+        s.spawn(|| {
+            std::thread::sleep(Duration::from_millis(50));
+            fake.set_position(Position {
+                txid: 2,
+                post_apply_checksum: 0,
+            })
+            .unwrap();
+        });
+
+        let pos =
+            litefs_rs::wait_for_position(fake.database_path(), 2, Duration::from_secs(5)).unwrap();
+        assert_eq!(pos.txid, 2);
+    });
+
+    std::fs::write(&pos_path, "").unwrap();
+    let err = litefs_rs::wait_for_position(fake.database_path(), 2, Duration::from_millis(30))
+        .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+}
+
+#[tokio::test]
+async fn lag_watcher_follows_the_lag_file() {
+    let fake = FakeLiteFs::new().unwrap();
+    let watcher = LagWatcher::spawn(fake.database_path(), Duration::from_millis(10));
+    let mut lag = watcher.subscribe();
+
+    lag.wait_for(|lag| *lag == Some(Duration::ZERO))
+        .await
+        .unwrap();
+
+    fake.set_lag(Duration::from_secs(30)).unwrap();
+    lag.wait_for(|lag| *lag == Some(Duration::from_secs(30)))
+        .await
+        .unwrap();
+    assert_eq!(watcher.current(), Some(Duration::from_secs(30)));
+
+    fake.write_lag("garbage").unwrap();
+    lag.wait_for(|lag| lag.is_none()).await.unwrap();
+}
@@ -76,15 +76,24 @@ fn now_template(title: &str) -> impl IntoResponse {
     (headers, template)
 }
 
+/// Log the trace a proxy like caje sent us, so a slow request can be followed end to end
+fn log_trace(headers: &HeaderMap) {
+    if let Some(traceparent) = headers.get("traceparent") {
+        tracing::info!(?traceparent, "Continuing trace");
+    }
+}
+
 // handler that responds after 5 seconds
-async fn slow() -> impl IntoResponse {
+async fn slow(headers: HeaderMap) -> impl IntoResponse {
+    log_trace(&headers);
     tokio::time::sleep(std::time::Duration::from_secs(5)).await;
 
     now_template("Slow")
 }
 
 // handler that responds after 1 second
-async fn fast() -> impl IntoResponse {
+async fn fast(headers: HeaderMap) -> impl IntoResponse {
+    log_trace(&headers);
     tokio::time::sleep(std::time::Duration::from_secs(1)).await;
 
     now_template("Fast")